@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use crate::metadata::TrackMetadata;
+
+/// Name of the directory that compilations and other albums with many artists are grouped
+/// into by default.
+pub const DEFAULT_VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Text that joins a song's main artist to the ones featured on it, e.g. "Artist feat. Guest".
+const FEATURE_SEPARATORS: [&str; 10] = [
+    " feat.", " feat ", " ft.", " ft ", " featuring ", " (feat", " with ", " & ", ", ", "; ",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AlbumGroup {
+    /// An album artist given on at least one of the album's song files, or else the artist of
+    /// most of them
+    Artist(String),
+    /// Flagged as a compilation, or with tracks by too many different artists to pick one
+    VariousArtists,
+    /// None of the album's song files has an artist, so each is left to its own tag
+    TrackArtist,
+}
+
+#[derive(Debug, Default)]
+struct AlbumTracks<'a> {
+    album_artist: Option<&'a str>,
+    compilation: bool,
+    /// Highest disc number or total number of discs tagged on any of the album's song files
+    discs: Option<u16>,
+    /// Artist of each track, keyed by main artist
+    artists: HashMap<String, Vec<&'a str>>,
+}
+
+impl AlbumTracks<'_> {
+    fn group(&self) -> AlbumGroup {
+        if let Some(album_artist) = self.album_artist {
+            return AlbumGroup::Artist(album_artist.to_string())
+        }
+        let tracks: usize = self.artists.values().map(Vec::len).sum();
+        let Some(names) = self.artists.values().max_by_key(|names| names.len()) else {
+            return AlbumGroup::TrackArtist
+        };
+        // No artist can be said to be the album's own unless they have most of its tracks
+        if self.compilation || names.len() * 2 <= tracks {
+            return AlbumGroup::VariousArtists
+        }
+        AlbumGroup::Artist(most_common(names).to_string())
+    }
+}
+
+/// The name that appears most often in `names`, the earliest of them on a tie.
+fn most_common<'a>(names: &[&'a str]) -> &'a str {
+    let mut best = names[0];
+    let mut best_count = 0;
+    for name in names.iter() {
+        let count = names.iter().filter(|other| *other == name).count();
+        if count > best_count {
+            best = name;
+            best_count = count;
+        }
+    }
+    best
+}
+
+/// What tells an album apart from others with the same title.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Discriminator {
+    AlbumArtist(String),
+    /// The year, for song files without an album artist, so that e.g. two artists' "Greatest
+    /// Hits" aren't taken for one album with many artists
+    Year(Option<i32>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AlbumKey {
+    album: String,
+    discriminator: Discriminator,
+}
+
+/// The artist each album among a run's song files is grouped under and how many discs it has,
+/// worked out from the metadata of all its song files together so that an album's songs stay
+/// in one place.
+#[derive(Debug, Default)]
+pub(crate) struct AlbumGroups {
+    /// The different album artists given for each album title
+    album_artists: HashMap<String, Vec<String>>,
+    albums: HashMap<AlbumKey, AlbumGroup>,
+    discs: HashMap<AlbumKey, u16>,
+}
+
+impl AlbumGroups {
+    pub(crate) fn new(songs: &[TrackMetadata]) -> AlbumGroups {
+        let mut album_groups = AlbumGroups::default();
+        for metadata in songs.iter() {
+            let album = non_empty(metadata.album.as_deref());
+            let album_artist = non_empty(metadata.album_artist.as_deref());
+            if let Some((album, album_artist)) = album.zip(album_artist) {
+                let names = album_groups.album_artists.entry(album.to_string()).or_default();
+                if !names.iter().any(|name| name == album_artist) {
+                    names.push(album_artist.to_string());
+                }
+            }
+        }
+        let mut tracks_per_album: HashMap<AlbumKey, AlbumTracks> = HashMap::new();
+        for metadata in songs.iter() {
+            let Some(key) = album_groups.key(metadata) else {
+                continue
+            };
+            let tracks = tracks_per_album.entry(key).or_default();
+            let album_artist = non_empty(metadata.album_artist.as_deref());
+            tracks.album_artist = tracks.album_artist.or(album_artist);
+            tracks.compilation |= metadata.compilation;
+            tracks.discs = tracks.discs.max(metadata.disc_number).max(metadata.total_discs);
+            if let Some(artist) = non_empty(metadata.artist.as_deref()) {
+                tracks.artists.entry(main_artist(artist)).or_default().push(artist);
+            }
+        }
+        album_groups.discs = tracks_per_album.iter()
+            .filter_map(|(key, tracks)| Some((key.clone(), tracks.discs?)))
+            .collect();
+        album_groups.albums = tracks_per_album.into_iter()
+            .map(|(key, tracks)| (key, tracks.group()))
+            .collect();
+        album_groups
+    }
+
+    /// Which album `metadata` is on, if it has one. A song file without an album artist is
+    /// taken to be on the album of the only album artist given for its title, if there's one.
+    fn key(&self, metadata: &TrackMetadata) -> Option<AlbumKey> {
+        let album = non_empty(metadata.album.as_deref())?;
+        let album_artist = non_empty(metadata.album_artist.as_deref())
+            .or_else(|| match self.album_artists.get(album).map(Vec::as_slice) {
+                Some([album_artist]) => Some(album_artist.as_str()),
+                _ => None,
+            });
+        let discriminator = match album_artist {
+            Some(album_artist) => Discriminator::AlbumArtist(album_artist.to_string()),
+            None => Discriminator::Year(metadata.year),
+        };
+        Some(AlbumKey { album: album.to_string(), discriminator })
+    }
+
+    /// Sets the artist that `metadata` is grouped under unless that's its own album artist, and
+    /// the number of discs its album has.
+    pub(crate) fn assign(&self, metadata: &mut TrackMetadata, various_artists: &str) {
+        let key = self.key(metadata);
+        metadata.album_discs = key.as_ref().and_then(|key| self.discs.get(key)).copied();
+        if non_empty(metadata.album_artist.as_deref()).is_some() {
+            return
+        }
+        let album_group = key.as_ref()
+            .and_then(|key| self.albums.get(key))
+            .unwrap_or(&AlbumGroup::TrackArtist);
+        metadata.album_group = match album_group {
+            _ if metadata.compilation => Some(various_artists.to_string()),
+            AlbumGroup::Artist(artist) => Some(artist.clone()),
+            AlbumGroup::VariousArtists => Some(various_artists.to_string()),
+            AlbumGroup::TrackArtist => None,
+        };
+    }
+}
+
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.filter(|val| !val.trim().is_empty())
+}
+
+/// The artist a song is by, minus any featured artists, in a form that can be compared with
+/// those of other songs.
+fn main_artist(artist: &str) -> String {
+    let artist = artist.to_lowercase();
+    let end = FEATURE_SEPARATORS.iter()
+        .filter_map(|separator| artist.find(separator))
+        .min()
+        .unwrap_or(artist.len());
+    artist[..end].trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(artist: &str, album: &str) -> TrackMetadata {
+        TrackMetadata {
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            ..TrackMetadata::default()
+        }
+    }
+
+    fn groups_of(mut songs: Vec<TrackMetadata>) -> Vec<Option<String>> {
+        let album_groups = AlbumGroups::new(&songs);
+        for metadata in songs.iter_mut() {
+            album_groups.assign(metadata, DEFAULT_VARIOUS_ARTISTS);
+        }
+        songs.into_iter().map(|metadata| metadata.album_group).collect()
+    }
+
+    fn discs_of(mut songs: Vec<TrackMetadata>) -> Vec<Option<u16>> {
+        let album_groups = AlbumGroups::new(&songs);
+        for metadata in songs.iter_mut() {
+            album_groups.assign(metadata, DEFAULT_VARIOUS_ARTISTS);
+        }
+        songs.into_iter().map(|metadata| metadata.album_discs).collect()
+    }
+
+    fn group(name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    #[test]
+    fn album_artist_shared_across_album() {
+        let songs = vec![
+            TrackMetadata { album_artist: group("DJ Mixer"), ..song("Artist A", "Mix") },
+            song("Artist B", "Mix"),
+            song("Artist C", "Other Album"),
+        ];
+        assert_eq!(groups_of(songs), vec![None, group("DJ Mixer"), group("Artist C")]);
+    }
+
+    #[test]
+    fn compilation_flag_groups_album_under_various_artists() {
+        let songs = vec![
+            TrackMetadata { compilation: true, ..song("Artist A", "Hits") },
+            song("Artist A", "Hits"),
+            song("Artist A", "Hits"),
+        ];
+        assert_eq!(groups_of(songs), vec![group(DEFAULT_VARIOUS_ARTISTS); 3]);
+    }
+
+    #[test]
+    fn album_with_many_artists_detected() {
+        let songs = vec![
+            song("Artist A", "Hits"),
+            song("Artist B", "Hits"),
+            song("Artist C", "Hits"),
+            song("Artist A", "Hits"),
+        ];
+        assert_eq!(groups_of(songs), vec![group(DEFAULT_VARIOUS_ARTISTS); 4]);
+    }
+
+    #[test]
+    fn featured_artists_dont_make_album_multi_artist() {
+        let songs = vec![
+            song("Artist A feat. Guest", "Album"),
+            song("Artist A", "Album"),
+            song("artist a & Other Guest", "Album"),
+            song("Artist A", "Album"),
+            song("Guest", "Album"),
+        ];
+        assert_eq!(groups_of(songs), vec![group("Artist A"); 5]);
+    }
+
+    #[test]
+    fn disc_count_shared_across_album() {
+        // Neither disc is tagged with a total, and the album artist doesn't stop it being shared
+        let songs = vec![
+            TrackMetadata { disc_number: Some(1), ..song("Artist A", "Double") },
+            TrackMetadata {
+                disc_number: Some(2),
+                album_artist: group("Artist A"),
+                ..song("Artist A", "Double")
+            },
+            TrackMetadata { disc_number: Some(1), ..song("Artist A", "Single") },
+            song("Artist A", "No Discs"),
+        ];
+        assert_eq!(discs_of(songs), vec![Some(2), Some(2), Some(1), None]);
+    }
+
+    #[test]
+    fn albums_sharing_title_kept_apart() {
+        let songs = vec![
+            TrackMetadata { album_artist: group("Artist A"), ..song("Artist A", "Greatest Hits") },
+            TrackMetadata {
+                disc_number: Some(2),
+                album_artist: group("Artist B"),
+                ..song("Artist B", "Greatest Hits")
+            },
+            TrackMetadata { disc_number: Some(1), ..song("Artist A", "Greatest Hits") },
+        ];
+        // The last song has no album artist and the title has two, so it's grouped on its own
+        assert_eq!(discs_of(songs.clone()), vec![None, Some(2), Some(1)]);
+        assert_eq!(groups_of(songs), vec![None, None, group("Artist A")]);
+    }
+
+    #[test]
+    fn year_tells_apart_albums_without_album_artist() {
+        let songs = vec![
+            TrackMetadata { year: Some(1998), ..song("Artist A", "Greatest Hits") },
+            TrackMetadata { year: Some(1998), ..song("Artist A", "Greatest Hits") },
+            TrackMetadata { year: Some(2004), ..song("Artist B", "Greatest Hits") },
+            TrackMetadata { year: Some(2004), ..song("Artist B", "Greatest Hits") },
+        ];
+        let expected = vec![group("Artist A"), group("Artist A"), group("Artist B"), group("Artist B")];
+        assert_eq!(groups_of(songs), expected);
+    }
+
+    #[test]
+    fn song_without_album_left_to_own_tag() {
+        let songs = vec![
+            TrackMetadata { album: None, ..song("Artist A", "") },
+            TrackMetadata { album: None, compilation: true, ..song("Artist B", "") },
+        ];
+        assert_eq!(groups_of(songs), vec![None, group(DEFAULT_VARIOUS_ARTISTS)]);
+    }
+
+    #[test]
+    fn main_artist_strips_featured_artists() {
+        assert_eq!(main_artist("Artist A ft. Guest"), "artist a");
+        assert_eq!(main_artist("Artist A, Guest & Other Guest"), "artist a");
+        assert_eq!(main_artist("Artist A"), "artist a");
+    }
+}
@@ -0,0 +1,537 @@
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+
+use crate::album::DEFAULT_VARIOUS_ARTISTS;
+use crate::collision::CollisionPolicy;
+use crate::dedup::DuplicatePolicy;
+use crate::discover::{SymlinkPolicy, WalkOptions};
+use crate::operation::Operation;
+use crate::playlist::PlaylistPaths;
+use crate::sanitize::{
+    DEFAULT_MAX_NAME_LENGTH, DEFAULT_REPLACEMENT, InvalidSanitizer, SanitizeProfile, Sanitizer,
+};
+use crate::template::DEFAULT_TEMPLATE;
+use crate::watch::DEFAULT_SETTLE_SECS;
+
+const SUBCOMMANDS: [&str; 7] = ["organize", "scan", "watch", "verify", "undo", "config", "help"];
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "mp3_mover",
+    version,
+    about = "Organise song files into an artist/album layout",
+    override_usage = "mp3_mover [organize] [OPTIONS] <INPUT> <OUTPUT>\n       mp3_mover <COMMAND>",
+    after_help = "Exit codes: 0 success, 1 undo incomplete, 2 invalid args, 3 missing tag \
+        fields, 4 unreadable tags, 5 input dir unreadable, 6 collision, 7 filesystem error",
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Move song files from the input directory into an artist/album layout in the output
+    /// directory (the default when no subcommand is given)
+    Organize(OrganizeArgs),
+    /// List the song files found in the input directory along with their tag info
+    Scan(ScanArgs),
+    /// Keep organising song files as they land in the input directory, starting with any
+    /// already there, until interrupted (Linux only)
+    Watch(WatchArgs),
+    /// Check that the song files in a library organised by an earlier run are still where
+    /// their tags say they belong, and list empty directories and files that aren't song files
+    Verify(VerifyArgs),
+    /// Revert the changes recorded in the journal that an organize run leaves in its output
+    /// directory
+    Undo(UndoArgs),
+    /// Work with the config file that settings are read from
+    Config(ConfigArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct OrganizeArgs {
+    /// Directory containing the song files to organise
+    pub input: String,
+    /// Directory to place the organised song files in
+    pub output: String,
+    /// Print what would be done without touching the filesystem
+    #[arg(long)]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub walk: WalkArgs,
+    #[command(flatten)]
+    pub layout: TemplateArgs,
+    #[command(flatten)]
+    pub naming: NamingArgs,
+    /// What to do when a song file's destination is already taken, either by an existing file
+    /// or by another song file in the same run
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Rename)]
+    pub on_collision: CollisionPolicy,
+    /// Look for song files with the same audio, even if their tags differ, and organise only
+    /// one copy of each, chosen by this policy. Each song file's audio has to be read in full
+    /// when another has as much audio, so this can take a while
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub dedup: Option<DuplicatePolicy>,
+    /// How song files are placed in the output directory
+    #[arg(long, value_enum, default_value_t = Operation::Move)]
+    pub mode: Operation,
+    /// Move song files whose tags are unreadable or lack a field the template needs into this
+    /// directory, under missing-<field>/ or unreadable-tag/ and keeping their path relative to
+    /// the input directory, instead of leaving them where they are
+    #[arg(long, value_name = "DIR")]
+    pub unsorted_dir: Option<String>,
+    /// Also write the end-of-run summary, including every song file's outcome, to this file as
+    /// JSON
+    #[arg(long, value_name = "PATH")]
+    pub json_report: Option<String>,
+    /// Write the playlists in the iPod's iTunesDB (see --itunes-db) to .m3u8 files in the
+    /// output directory, and rewrite the .m3u, .m3u8 and .pls playlists in the input directory
+    /// to the same place in the output directory, with entries pointing at where their song
+    /// files end up, given relative to the playlist or as absolute paths. Entries whose song
+    /// file can't be found are left out and reported
+    #[arg(long, value_enum, value_name = "PATHS")]
+    pub playlists: Option<PlaylistPaths>,
+    #[command(flatten)]
+    pub config_file: ConfigFileArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ScanArgs {
+    /// Directory containing the song files to scan
+    pub input: String,
+    #[command(flatten)]
+    pub walk: WalkArgs,
+    #[command(flatten)]
+    pub layout: TemplateArgs,
+    #[command(flatten)]
+    pub naming: NamingArgs,
+    #[command(flatten)]
+    pub config_file: ConfigFileArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    #[command(flatten)]
+    pub organize: OrganizeArgs,
+    /// Seconds a song file has to go unchanged before it's taken to have finished being written
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_SETTLE_SECS)]
+    pub settle_secs: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Output directory of an earlier organize run to check
+    pub library: String,
+    #[command(flatten)]
+    pub walk: WalkArgs,
+    #[command(flatten)]
+    pub layout: TemplateArgs,
+    #[command(flatten)]
+    pub naming: NamingArgs,
+    /// Move misplaced song files to where they belong, recording the moves in a journal in the
+    /// library that undo can revert, then remove empty directories. Files that aren't song
+    /// files are only listed
+    #[arg(long)]
+    pub fix: bool,
+    #[command(flatten)]
+    pub config_file: ConfigFileArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct UndoArgs {
+    /// Journal file written by the run to revert
+    pub journal: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Check the config file and the given args, and print the settings an organize run with
+    /// them would use
+    Check(OrganizeArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ConfigFileArgs {
+    /// Config file to read settings from instead of $XDG_CONFIG_HOME/mp3_mover/config.toml or
+    /// .mp3_mover.toml in the input directory, whichever is found first. Flags given on the
+    /// command line take precedence over it
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Profile in the config file, i.e. its [profile.NAME] table, whose settings take
+    /// precedence over the file's top-level ones
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Args that were given on the command line rather than left at their defaults
+    #[arg(skip)]
+    pub explicit_args: Vec<String>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct WalkArgs {
+    /// Maximum number of directory levels below the input directory to search for song files
+    /// (0 means only the input directory itself) [default: unlimited]
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+    /// How symlinks found in the input directory are treated
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Follow)]
+    pub symlinks: SymlinkPolicy,
+    /// Search hidden files and directories (names starting with '.') for song files
+    #[arg(long, overrides_with = "no_include_hidden")]
+    pub include_hidden: bool,
+    /// Skip hidden files and directories, even if a config file sets include-hidden
+    #[arg(long, overrides_with = "include_hidden")]
+    pub no_include_hidden: bool,
+    /// Number of threads to read song files' tags on, which mostly helps on
+    /// network or otherwise slow storage [default: number of CPUs]
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Args)]
+pub struct TemplateArgs {
+    /// Layout of destination paths relative to the output directory, or to the library when
+    /// verifying one. Placeholders are {artist}, {albumartist}, {group}, {album}, {title},
+    /// {year}, {date}, {genre}, {composer}, {track}, {totaltracks}, {disc}, {totaldiscs},
+    /// {multidisc}, {codec}, {filename} and {ext}; {a|b|"text"} falls back to b then "text"
+    /// when a is missing, {track:02} zero-pads to two digits, and text in [...] is left out
+    /// when a placeholder in it is missing. {group} is the album artist, the --various-artists
+    /// name for compilations, or else the artist, {multidisc} is the disc number of albums with
+    /// more than one disc, and {codec} is e.g. MP3 or FLAC. Use
+    /// "{group}/{album}/[{multidisc}-][{track:02} ]{title|filename}.{ext}" to prefix the disc
+    /// number rather than using a disc folder
+    #[arg(long, default_value = DEFAULT_TEMPLATE)]
+    pub template: String,
+    /// Pattern matched against the end of each song file's path, with the last component
+    /// matched without its extension, to fill in fields missing from its tag, e.g.
+    /// "{artist} - {album}/{track} - {title}". Values in the tag always take precedence
+    #[arg(long, value_name = "PATTERN")]
+    pub path_pattern: Option<String>,
+}
+
+impl Default for TemplateArgs {
+    fn default() -> TemplateArgs {
+        TemplateArgs { template: String::from(DEFAULT_TEMPLATE), path_pattern: None }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct NamingArgs {
+    /// Which filesystem's naming rules tag values are made to follow when used in paths
+    #[arg(long, value_enum, default_value_t = SanitizeProfile::Windows)]
+    pub sanitize: SanitizeProfile,
+    /// Text that replaces characters which aren't allowed in paths
+    #[arg(long, default_value = DEFAULT_REPLACEMENT)]
+    pub replacement: String,
+    /// Maximum length in bytes of each directory and file name in destination paths
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_NAME_LENGTH)]
+    pub max_name_length: usize,
+    /// Directory name that {group} gives compilations, and albums whose songs are by many
+    /// different artists and which have no album artist
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_VARIOUS_ARTISTS)]
+    pub various_artists: String,
+    /// iPod iTunesDB to fill in fields missing from song files' tags from, by the song files'
+    /// Fxx/ABCD.mp3 names, which also stands in for tags that can't be read at all
+    /// [default: iPod_Control/iTunes/iTunesDB on the iPod the input directory is on, if any]
+    #[arg(long, value_name = "PATH")]
+    pub itunes_db: Option<String>,
+}
+
+impl Default for NamingArgs {
+    fn default() -> NamingArgs {
+        NamingArgs {
+            sanitize: SanitizeProfile::default(),
+            replacement: String::from(DEFAULT_REPLACEMENT),
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+            various_artists: String::from(DEFAULT_VARIOUS_ARTISTS),
+            itunes_db: None,
+        }
+    }
+}
+
+impl OrganizeArgs {
+    /// Args for organising `input` into `output` with every option left at its default, as if
+    /// only the two paths had been given on the command line.
+    pub fn new(input: String, output: String) -> OrganizeArgs {
+        OrganizeArgs {
+            input,
+            output,
+            dry_run: false,
+            walk: WalkArgs::default(),
+            layout: TemplateArgs::default(),
+            naming: NamingArgs::default(),
+            on_collision: CollisionPolicy::default(),
+            dedup: None,
+            mode: Operation::default(),
+            unsorted_dir: None,
+            json_report: None,
+            playlists: None,
+            config_file: ConfigFileArgs::default(),
+        }
+    }
+}
+
+impl NamingArgs {
+    pub fn to_sanitizer(&self) -> Result<Sanitizer, InvalidSanitizer> {
+        Sanitizer::new(self.sanitize, &self.replacement, self.max_name_length)
+    }
+}
+
+impl WalkArgs {
+    pub fn to_walk_options(&self) -> WalkOptions {
+        WalkOptions {
+            max_depth: self.max_depth,
+            symlinks: self.symlinks,
+            include_hidden: self.include_hidden,
+            jobs: self.jobs,
+        }
+    }
+}
+
+impl Cli {
+    /// Parses the given args, treating an invocation without a subcommand as `organize` so that
+    /// `mp3_mover <input> <output>` keeps working.
+    pub fn parse_args<I, T>(args: I) -> Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        if let Some(first) = args.get(1) {
+            let first = first.to_string_lossy();
+            let is_subcommand = SUBCOMMANDS.contains(&first.as_ref());
+            let is_top_level_flag = ["-h", "--help", "-V", "--version"].contains(&first.as_ref());
+            if !is_subcommand && !is_top_level_flag {
+                args.insert(1, OsString::from("organize"));
+            }
+        }
+        let mut command = Cli::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        let mut command_matches = &matches;
+        while let Some((name, subcommand_matches)) = command_matches.subcommand() {
+            command = command.find_subcommand(name).cloned().unwrap_or_default();
+            command_matches = subcommand_matches;
+        }
+        let config_file = match &mut cli.command {
+            Command::Organize(args) | Command::Config(ConfigArgs {
+                command: ConfigCommand::Check(args),
+            }) => Some(&mut args.config_file),
+            Command::Watch(args) => Some(&mut args.organize.config_file),
+            Command::Scan(args) => Some(&mut args.config_file),
+            Command::Verify(args) => Some(&mut args.config_file),
+            Command::Undo(_) => None,
+        };
+        if let Some(config_file) = config_file {
+            // Matches also list groups named after the flattened structs, which aren't args
+            config_file.explicit_args = command.get_arguments()
+                .map(|arg| arg.get_id().as_str())
+                .filter(|id| command_matches.value_source(id) == Some(ValueSource::CommandLine))
+                .map(String::from)
+                .collect();
+        }
+        Ok(cli)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn positional_args_default_to_organize() {
+        let args = ["/path/to/program", "/input/dir/path", "/output/dir/path"];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Organize(organize_args) => {
+                assert_eq!(organize_args.input, "/input/dir/path");
+                assert_eq!(organize_args.output, "/output/dir/path");
+                assert!(!organize_args.dry_run);
+            },
+            _ => panic!("Expected organize subcommand"),
+        }
+    }
+
+    #[test]
+    fn flags_without_subcommand_default_to_organize() {
+        let args = ["/path/to/program", "--dry-run", "/input/dir/path", "/output/dir/path"];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Organize(organize_args) => assert!(organize_args.dry_run),
+            _ => panic!("Expected organize subcommand"),
+        }
+    }
+
+    #[test]
+    fn scan_subcommand() {
+        let args = ["/path/to/program", "scan", "/input/dir/path"];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Scan(scan_args) => assert_eq!(scan_args.input, "/input/dir/path"),
+            _ => panic!("Expected scan subcommand"),
+        }
+    }
+
+    #[test]
+    fn verify_subcommand() {
+        let args = ["/path/to/program", "verify", "--fix", "/library/path"];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Verify(verify_args) => {
+                assert_eq!(verify_args.library, "/library/path");
+                assert!(verify_args.fix);
+                assert_eq!(verify_args.config_file.explicit_args, ["library", "fix"]);
+            },
+            _ => panic!("Expected verify subcommand"),
+        }
+    }
+
+    #[test]
+    fn watch_subcommand() {
+        let args = [
+            "/path/to/program",
+            "watch",
+            "--settle-secs",
+            "5",
+            "/input/dir/path",
+            "/output/dir/path",
+        ];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Watch(watch_args) => {
+                assert_eq!(watch_args.organize.input, "/input/dir/path");
+                assert_eq!(watch_args.organize.output, "/output/dir/path");
+                assert_eq!(watch_args.settle_secs, 5);
+            },
+            _ => panic!("Expected watch subcommand"),
+        }
+    }
+
+    #[test]
+    fn config_check_subcommand() {
+        let args = [
+            "/path/to/program",
+            "config",
+            "check",
+            "--profile",
+            "car-usb",
+            "--mode",
+            "copy",
+            "/input/dir/path",
+            "/output/dir/path",
+        ];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Config(ConfigArgs { command: ConfigCommand::Check(organize_args) }) => {
+                assert_eq!(organize_args.config_file.profile.as_deref(), Some("car-usb"));
+                assert_eq!(organize_args.mode, Operation::Copy);
+            },
+            _ => panic!("Expected config check subcommand"),
+        }
+    }
+
+    #[test]
+    fn args_given_on_command_line_recorded() {
+        let args = [
+            "/path/to/program",
+            "--template",
+            "{artist}/{title}.{ext}",
+            "--include-hidden",
+            "/input/dir/path",
+            "/output/dir/path",
+        ];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Organize(organize_args) => {
+                let mut explicit_args = organize_args.config_file.explicit_args;
+                explicit_args.sort();
+                assert_eq!(explicit_args, ["include_hidden", "input", "output", "template"]);
+            },
+            _ => panic!("Expected organize subcommand"),
+        }
+    }
+
+    #[test]
+    fn undo_subcommand() {
+        let args = ["/path/to/program", "undo", "/output/dir/path/journal.jsonl"];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Undo(undo_args) => {
+                assert_eq!(undo_args.journal, "/output/dir/path/journal.jsonl")
+            },
+            _ => panic!("Expected undo subcommand"),
+        }
+    }
+
+    #[test]
+    fn walk_flags() {
+        let args = [
+            "/path/to/program",
+            "scan",
+            "--max-depth",
+            "2",
+            "--symlinks",
+            "files-only",
+            "--include-hidden",
+            "--jobs",
+            "4",
+            "/input/dir/path",
+        ];
+        let cli = Cli::parse_args(args).unwrap();
+        match cli.command {
+            Command::Scan(scan_args) => {
+                let expected = WalkOptions {
+                    max_depth: Some(2),
+                    symlinks: SymlinkPolicy::FilesOnly,
+                    include_hidden: true,
+                    jobs: NonZeroUsize::new(4),
+                };
+                assert_eq!(scan_args.walk.to_walk_options(), expected);
+            },
+            _ => panic!("Expected scan subcommand"),
+        }
+    }
+
+    #[test]
+    fn not_enough_cli_args() {
+        let args = ["/path/to/program", "/input/dir/path"];
+        let res = Cli::parse_args(args);
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::MissingRequiredArgument));
+    }
+
+    #[test]
+    fn too_many_cli_args() {
+        let args = ["/path/to/program", "/input/dir/path", "/output/dir/path", "extra arg"];
+        let res = Cli::parse_args(args);
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::UnknownArgument));
+    }
+
+    #[test]
+    fn no_args_shows_help() {
+        let args = ["/path/to/program"];
+        let res = Cli::parse_args(args);
+        assert!(
+            res.is_err_and(
+                |e| e.kind() == ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+            ),
+        );
+    }
+
+    #[test]
+    fn version_flag() {
+        let args = ["/path/to/program", "--version"];
+        let res = Cli::parse_args(args);
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::DisplayVersion));
+    }
+}
@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Leave the incoming song file where it is
+    Skip,
+    /// Replace whatever is already at the destination
+    Overwrite,
+    /// Give the incoming song file a " (2)", " (3)", ... suffix
+    #[default]
+    Rename,
+    /// Keep whichever of the two files is larger
+    KeepLarger,
+    /// Keep whichever of the two files has the higher bitrate, or the larger one if neither
+    /// does
+    KeepHigherBitrate,
+    /// Abort the run before anything is moved
+    Fail,
+}
+
+/// What a song file's destination clashed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollisionWith {
+    /// A file that was already at the destination before the run
+    ExistingFile,
+    /// Another song file in the same run, identified by its source path
+    PlannedMove(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollisionResolution {
+    /// The incoming song file is left where it is
+    Skipped,
+    /// The incoming song file replaces the other one, which is left where it is if it was part
+    /// of the run
+    Replaced,
+    /// The incoming song file is moved to a different destination
+    Renamed(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collision {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub with: CollisionWith,
+    pub resolution: CollisionResolution,
+}
+
+impl Display for Collision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} clashed with ", self.source.display(), self.destination.display())?;
+        match &self.with {
+            CollisionWith::ExistingFile => write!(f, "an existing file")?,
+            CollisionWith::PlannedMove(other) => write!(f, "{}", other.display())?,
+        }
+        match &self.resolution {
+            CollisionResolution::Skipped => write!(f, "; skipped"),
+            CollisionResolution::Replaced => write!(f, "; replaced"),
+            CollisionResolution::Renamed(path) => write!(f, "; renamed to {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CollisionError {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+impl Display for CollisionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Song file {:?} would be moved to {:?} which is already taken",
+            self.source,
+            self.destination,
+        )
+    }
+}
+
+impl Error for CollisionError {}
+
+/// Builds the `n`th alternative name for `destination`, e.g. `Intro (2).mp3`.
+pub fn numbered_destination(destination: &Path, n: usize) -> PathBuf {
+    let stem = destination.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let filename = match destination.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, n, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    destination.with_file_name(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbered_destination_keeps_extension() {
+        let destination = Path::new("/out/Artist/Album/Intro.mp3");
+        assert_eq!(
+            numbered_destination(destination, 2),
+            PathBuf::from("/out/Artist/Album/Intro (2).mp3"),
+        );
+        let destination = Path::new("/out/Artist/Album/Intro");
+        assert_eq!(
+            numbered_destination(destination, 3),
+            PathBuf::from("/out/Artist/Album/Intro (3)"),
+        );
+    }
+}
@@ -1,44 +1,113 @@
-use std::error::Error;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::{read_dir, create_dir_all};
 
+use crate::cli::OrganizeArgs;
+use crate::collision::CollisionPolicy;
+use crate::dedup::DuplicatePolicy;
+use crate::discover::WalkOptions;
+use crate::error::MoverError;
+use crate::infer::{PathPattern, PatternError};
+use crate::itunesdb::{ITunesDb, load_itunes_db};
+use crate::operation::Operation;
+use crate::playlist::PlaylistPaths;
+use crate::sanitize::Sanitizer;
+use crate::template::{PathTemplate, TemplateError};
+
 pub struct Config {
     pub input_path: String,
     pub output_path: String,
+    pub dry_run: bool,
+    pub walk_options: WalkOptions,
+    pub template: PathTemplate,
+    pub sanitizer: Sanitizer,
+    pub collision_policy: CollisionPolicy,
+    pub duplicate_policy: Option<DuplicatePolicy>,
+    pub operation: Operation,
+    pub unsorted_dir: Option<String>,
+    pub path_pattern: Option<PathPattern>,
+    pub various_artists: String,
+    pub itunes_db: Option<ITunesDb>,
+    pub playlist_paths: Option<PlaylistPaths>,
+}
+
+/// What a `Config` is built for, which decides how much of the filesystem it needs in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Purpose {
+    Run,
+    /// Watching the input directory, which is fine to start out empty
+    Watch,
+    /// Only checking that the args are valid, without creating the output directory
+    Check,
 }
 
 impl Config {
-    pub fn new(args: &[String]) -> Result<Config, Box<dyn Error>> {
-        Config::validate_number_of_args(&args)?;
-        Config::validate_input_dir_arg(&args[1])?;
-        Config::validate_output_dir_arg(&args[2])?;
-        let input_path = args[1].clone();
-        let output_path = args[2].clone();
-        Ok(Config { input_path, output_path })
+    pub fn new(args: &OrganizeArgs) -> Result<Config, MoverError> {
+        Config::build(args, Purpose::Run)
+    }
+
+    /// Like `new`, but for watching the input directory, which is fine to start out empty.
+    pub fn for_watch(args: &OrganizeArgs) -> Result<Config, MoverError> {
+        Config::build(args, Purpose::Watch)
     }
 
-    fn validate_number_of_args(args: &[String]) -> Result<(), &str> {
-        if args.len() != 3 {
-            return Err("Invalid number of args given")
+    /// Like `new`, but leaves the filesystem untouched and accepts an empty input directory.
+    pub fn check(args: &OrganizeArgs) -> Result<Config, MoverError> {
+        Config::build(args, Purpose::Check)
+    }
+
+    fn build(args: &OrganizeArgs, purpose: Purpose) -> Result<Config, MoverError> {
+        let template: PathTemplate = args.layout.template.parse()
+            .map_err(|e: TemplateError| MoverError::Config(e.to_string()))?;
+        let path_pattern = args.layout.path_pattern.as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(|e: PatternError| MoverError::Config(e.to_string()))?;
+        let sanitizer = args.naming.to_sanitizer()
+            .map_err(|e| MoverError::Config(e.to_string()))?;
+        Config::validate_input_dir_arg(&args.input, purpose != Purpose::Run)?;
+        if !args.dry_run && purpose != Purpose::Check {
+            Config::validate_output_dir_arg(&args.output).map_err(|source| {
+                MoverError::Filesystem { path: PathBuf::from(&args.output), source }
+            })?;
         }
-        Ok(())
+        let itunes_db = load_itunes_db(args.naming.itunes_db.as_deref(), Path::new(&args.input))
+            .map_err(|e| MoverError::Config(e.to_string()))?;
+        let input_path = args.input.clone();
+        let output_path = args.output.clone();
+        Ok(Config {
+            input_path,
+            output_path,
+            dry_run: args.dry_run,
+            walk_options: args.walk.to_walk_options(),
+            template,
+            sanitizer,
+            collision_policy: args.on_collision,
+            duplicate_policy: args.dedup,
+            operation: args.mode,
+            unsorted_dir: args.unsorted_dir.clone(),
+            path_pattern,
+            various_artists: args.naming.various_artists.clone(),
+            itunes_db,
+            playlist_paths: args.playlists,
+        })
     }
 
-    fn validate_input_dir_arg(input: &str) -> Result<(), &str> {
+    fn validate_input_dir_arg(input: &str, allow_empty: bool) -> Result<(), MoverError> {
         let input_path = PathBuf::from(input);
 
         if !input_path.exists() {
-            return Err("Input directory arg doesn't exist")
+            return Err(MoverError::Config(String::from("Input directory arg doesn't exist")))
         }
 
         if !input_path.is_dir() {
-            return Err("Input directory arg isn't a directory")
+            return Err(MoverError::Config(String::from("Input directory arg isn't a directory")))
         }
 
-        let contents = read_dir(input_path).unwrap();
-        if contents.count() == 0 {
-            return Err("Input directory arg contains no subdirectories")
+        let mut contents = read_dir(&input_path)
+            .map_err(|source| MoverError::Discovery { path: input_path.clone(), source })?;
+        if !allow_empty && contents.next().is_none() {
+            return Err(MoverError::Config(String::from("Input directory arg is empty")))
         }
 
         Ok(())
@@ -61,45 +130,15 @@ mod tests {
 
     use tempfile::tempdir;
 
+    use crate::cli::OrganizeArgs;
     use crate::config::Config;
 
-    #[test]
-    fn not_enough_cli_args() {
-        let dummy_args = ["/path/to/progam".to_string()];
-        let res = Config::validate_number_of_args(&dummy_args);
-        let expected_error_message = "Invalid number of args given";
-        assert_eq!(
-            res.is_err_and(|e| e.to_string() == expected_error_message),
-            true,
-        );
-    }
-
-    #[test]
-    fn too_many_cli_args() {
-        let dummy_args = [
-            "/path/to/program",
-            "/input/dir/path",
-            "/ouput/dir/path",
-            "extra arg"
-        ];
-        let dummy_args = dummy_args.map(|s| s.to_string());
-        let res = Config::validate_number_of_args(&dummy_args);
-        let expected_error_message = "Invalid number of args given";
-        assert_eq!(
-            res.is_err_and(|e| e.to_string() == expected_error_message),
-            true,
-        );
-    }
-
     #[test]
     fn input_dir_arg_doesnt_exist() {
         let nonexistent_path = "/tmp/nonexistent-dir";
-        let res = Config::validate_input_dir_arg(&nonexistent_path);
+        let res = Config::validate_input_dir_arg(nonexistent_path, false);
         let expected_error_message = "Input directory arg doesn't exist";
-        assert_eq!(
-            res.is_err_and(|e| e.to_string() == expected_error_message),
-            true,
-        );
+        assert!(res.is_err_and(|e| e.to_string() == expected_error_message));
     }
 
     #[test]
@@ -109,25 +148,22 @@ mod tests {
         let file_not_dir_path = indir_path.join("blah.txt");
         File::create(file_not_dir_path.clone()).unwrap();
         let res = Config::validate_input_dir_arg(
-            &file_not_dir_path.to_str().unwrap()
+            file_not_dir_path.to_str().unwrap(),
+            false,
         );
         let expected_error_message = "Input directory arg isn't a directory";
-        assert_eq!(
-            res.is_err_and(|e| e.to_string() == expected_error_message),
-            true,
-        );
+        assert!(res.is_err_and(|e| e.to_string() == expected_error_message));
     }
 
     #[test]
     fn input_dir_arg_is_empty() {
         let indir = tempdir().unwrap();
         let indir_str = indir.as_ref().to_str().unwrap();
-        let res = Config::validate_input_dir_arg(indir_str);
-        let expected_error_message = "Input directory arg contains no subdirectories";
-        assert_eq!(
-            res.is_err_and(|e| e.to_string() == expected_error_message),
-            true,
-        );
+        let res = Config::validate_input_dir_arg(indir_str, false);
+        let expected_error_message = "Input directory arg is empty";
+        assert!(res.is_err_and(|e| e.to_string() == expected_error_message));
+        // A directory being watched only has to exist
+        assert!(Config::validate_input_dir_arg(indir_str, true).is_ok());
     }
 
     #[test]
@@ -137,13 +173,12 @@ mod tests {
         let outdir_path = outdir.as_ref().join(subdir);
         let outdir_str = outdir_path.to_str().unwrap();
         let res = Config::validate_output_dir_arg(outdir_str);
-        assert_eq!(res.is_ok(), true);
-        assert_eq!(outdir_path.exists(), true);
+        assert!(res.is_ok());
+        assert!(outdir_path.exists());
     }
 
     #[test]
     fn config_instance_has_correct_args() {
-        let program_name = "/path/to/program";
         // Create valid input dir
         let input_dir = tempdir().unwrap();
         let input_path = input_dir.path().to_str().unwrap().to_string();
@@ -153,13 +188,44 @@ mod tests {
         // Create valid output dir
         let output_dir = tempdir().unwrap();
         let output_path = output_dir.path().to_str().unwrap().to_string();
-        let dummy_args = vec![
-            program_name.to_string(),
-            input_path.to_string(),
-            output_path.to_string(),
-        ];
+        let dummy_args = OrganizeArgs::new(input_path.clone(), output_path.clone());
         let config = Config::new(&dummy_args).unwrap();
         assert_eq!(config.input_path, input_path);
         assert_eq!(config.output_path, output_path);
+        assert!(!config.dry_run);
+    }
+
+    #[test]
+    fn dry_run_flag_doesnt_create_output_dir() {
+        // Create valid input dir
+        let input_dir = tempdir().unwrap();
+        let input_path = input_dir.path().to_str().unwrap().to_string();
+        create_dir(input_dir.path().join("subdir")).unwrap();
+        // Output dir that doesn't exist yet
+        let output_dir = tempdir().unwrap();
+        let output_path = output_dir.path().join("subdir");
+        let dummy_args = OrganizeArgs {
+            dry_run: true,
+            ..OrganizeArgs::new(input_path.clone(), output_path.to_str().unwrap().to_string())
+        };
+        let config = Config::new(&dummy_args).unwrap();
+        assert!(config.dry_run);
+        assert_eq!(config.input_path, input_path);
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn invalid_template_arg() {
+        let input_dir = tempdir().unwrap();
+        create_dir(input_dir.path().join("subdir")).unwrap();
+        let output_dir = tempdir().unwrap();
+        let mut dummy_args = OrganizeArgs::new(
+            input_dir.path().to_str().unwrap().to_string(),
+            output_dir.path().to_str().unwrap().to_string(),
+        );
+        dummy_args.layout.template = String::from("{artist}/{mood}.{ext}");
+        let res = Config::new(&dummy_args);
+        let expected_error_message = "Template contains unknown field: mood";
+        assert!(res.is_err_and(|e| e.to_string() == expected_error_message));
     }
 }
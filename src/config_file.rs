@@ -0,0 +1,451 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::read_to_string;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{
+    ConfigFileArgs, NamingArgs, OrganizeArgs, ScanArgs, TemplateArgs, VerifyArgs, WalkArgs,
+};
+use crate::collision::CollisionPolicy;
+use crate::dedup::DuplicatePolicy;
+use crate::discover::SymlinkPolicy;
+use crate::error::MoverError;
+use crate::operation::Operation;
+use crate::playlist::PlaylistPaths;
+use crate::sanitize::SanitizeProfile;
+
+/// Config file looked for in the input directory, hidden so that it isn't searched for tags.
+pub const INPUT_DIR_CONFIG_FILE: &str = ".mp3_mover.toml";
+
+#[derive(Debug)]
+pub enum ConfigFileError {
+    Read { path: PathBuf, source: std::io::Error },
+    Parse { path: PathBuf, message: String },
+    UnknownProfile { path: PathBuf, profile: String },
+    /// A profile was asked for but there's no config file to take it from
+    NoConfigFile { profile: String },
+}
+
+impl Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigFileError::Read { path, source } => {
+                write!(f, "Couldn't read config file {:?}: {}", path, source)
+            },
+            ConfigFileError::Parse { path, message } => {
+                write!(f, "Config file {:?} is invalid: {}", path, message.trim_end())
+            },
+            ConfigFileError::UnknownProfile { path, profile } => {
+                write!(f, "Config file {:?} has no profile named {}", path, profile)
+            },
+            ConfigFileError::NoConfigFile { profile } => {
+                write!(f, "Profile {} was given but no config file was found", profile)
+            },
+        }
+    }
+}
+
+impl Error for ConfigFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigFileError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Settings that can be given in a config file in place of flags, under the flags' names.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_pattern: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_collision: Option<CollisionPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<DuplicatePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Operation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsorted_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_report: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlists: Option<PlaylistPaths>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlinks: Option<SymlinkPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_hidden: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<NonZeroUsize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sanitize: Option<SanitizeProfile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replacement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_name_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub various_artists: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub itunes_db: Option<String>,
+}
+
+impl Settings {
+    /// The settings an organize run with `args` uses, e.g. to show which ones a config file
+    /// ended up giving.
+    pub fn from_organize_args(args: &OrganizeArgs) -> Settings {
+        Settings {
+            template: Some(args.layout.template.clone()),
+            path_pattern: args.layout.path_pattern.clone(),
+            on_collision: Some(args.on_collision),
+            dedup: args.dedup,
+            mode: Some(args.mode),
+            unsorted_dir: args.unsorted_dir.clone(),
+            json_report: args.json_report.clone(),
+            playlists: args.playlists,
+            max_depth: args.walk.max_depth,
+            symlinks: Some(args.walk.symlinks),
+            include_hidden: Some(args.walk.include_hidden),
+            jobs: args.walk.jobs,
+            sanitize: Some(args.naming.sanitize),
+            replacement: Some(args.naming.replacement.clone()),
+            max_name_length: Some(args.naming.max_name_length),
+            various_artists: Some(args.naming.various_artists.clone()),
+            itunes_db: args.naming.itunes_db.clone(),
+        }
+    }
+
+    /// These settings with any that `over` gives replaced by its own.
+    fn overlaid_with(self, over: Settings) -> Settings {
+        Settings {
+            template: over.template.or(self.template),
+            path_pattern: over.path_pattern.or(self.path_pattern),
+            on_collision: over.on_collision.or(self.on_collision),
+            dedup: over.dedup.or(self.dedup),
+            mode: over.mode.or(self.mode),
+            unsorted_dir: over.unsorted_dir.or(self.unsorted_dir),
+            json_report: over.json_report.or(self.json_report),
+            playlists: over.playlists.or(self.playlists),
+            max_depth: over.max_depth.or(self.max_depth),
+            symlinks: over.symlinks.or(self.symlinks),
+            include_hidden: over.include_hidden.or(self.include_hidden),
+            jobs: over.jobs.or(self.jobs),
+            sanitize: over.sanitize.or(self.sanitize),
+            replacement: over.replacement.or(self.replacement),
+            max_name_length: over.max_name_length.or(self.max_name_length),
+            various_artists: over.various_artists.or(self.various_artists),
+            itunes_db: over.itunes_db.or(self.itunes_db),
+        }
+    }
+
+    fn apply_to_organize(&self, args: &mut OrganizeArgs) {
+        let explicit_args = &args.config_file.explicit_args;
+        self.apply_to_layout(&mut args.layout, explicit_args);
+        set(&mut args.on_collision, &self.on_collision, explicit_args, "on_collision");
+        set_optional(&mut args.dedup, &self.dedup, explicit_args, "dedup");
+        set(&mut args.mode, &self.mode, explicit_args, "mode");
+        set_optional(&mut args.unsorted_dir, &self.unsorted_dir, explicit_args, "unsorted_dir");
+        set_optional(&mut args.json_report, &self.json_report, explicit_args, "json_report");
+        set_optional(&mut args.playlists, &self.playlists, explicit_args, "playlists");
+        self.apply_to_walk(&mut args.walk, explicit_args);
+        self.apply_to_naming(&mut args.naming, explicit_args);
+    }
+
+    fn apply_to_scan(&self, args: &mut ScanArgs) {
+        let explicit_args = &args.config_file.explicit_args;
+        self.apply_to_layout(&mut args.layout, explicit_args);
+        self.apply_to_walk(&mut args.walk, explicit_args);
+        self.apply_to_naming(&mut args.naming, explicit_args);
+    }
+
+    fn apply_to_verify(&self, args: &mut VerifyArgs) {
+        let explicit_args = &args.config_file.explicit_args;
+        self.apply_to_layout(&mut args.layout, explicit_args);
+        self.apply_to_walk(&mut args.walk, explicit_args);
+        self.apply_to_naming(&mut args.naming, explicit_args);
+    }
+
+    fn apply_to_layout(&self, layout: &mut TemplateArgs, explicit_args: &[String]) {
+        set(&mut layout.template, &self.template, explicit_args, "template");
+        set_optional(&mut layout.path_pattern, &self.path_pattern, explicit_args, "path_pattern");
+    }
+
+    fn apply_to_walk(&self, walk: &mut WalkArgs, explicit_args: &[String]) {
+        set_optional(&mut walk.max_depth, &self.max_depth, explicit_args, "max_depth");
+        set(&mut walk.symlinks, &self.symlinks, explicit_args, "symlinks");
+        // --no-include-hidden resets include_hidden rather than marking it as given
+        if !explicit_args.iter().any(|explicit_arg| explicit_arg == "no_include_hidden") {
+            set(&mut walk.include_hidden, &self.include_hidden, explicit_args, "include_hidden");
+        }
+        set_optional(&mut walk.jobs, &self.jobs, explicit_args, "jobs");
+    }
+
+    fn apply_to_naming(&self, naming: &mut NamingArgs, explicit_args: &[String]) {
+        set(&mut naming.sanitize, &self.sanitize, explicit_args, "sanitize");
+        set(&mut naming.replacement, &self.replacement, explicit_args, "replacement");
+        set(&mut naming.max_name_length, &self.max_name_length, explicit_args, "max_name_length");
+        set(&mut naming.various_artists, &self.various_artists, explicit_args, "various_artists");
+        set_optional(&mut naming.itunes_db, &self.itunes_db, explicit_args, "itunes_db");
+    }
+}
+
+/// Sets `arg` to `value` unless there's no value or the arg was given on the command line.
+fn set<T: Clone>(arg: &mut T, value: &Option<T>, explicit_args: &[String], id: &str) {
+    if let Some(value) = value {
+        if !explicit_args.iter().any(|explicit_arg| explicit_arg == id) {
+            *arg = value.clone();
+        }
+    }
+}
+
+fn set_optional<T: Clone>(arg: &mut Option<T>, value: &Option<T>, explicit_args: &[String], id: &str) {
+    set(arg, &value.clone().map(Some), explicit_args, id);
+}
+
+/// A config file's top-level settings along with the profiles, i.e. `[profile.NAME]` tables,
+/// that can be chosen to take precedence over them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub settings: Settings,
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, ConfigFileError> {
+        let text = read_to_string(path)
+            .map_err(|source| ConfigFileError::Read { path: path.to_path_buf(), source })?;
+        ConfigFile::parse(path, &text)
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<ConfigFile, ConfigFileError> {
+        let to_error = |e: toml::de::Error| ConfigFileError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+        let mut table: toml::Table = text.parse().map_err(to_error)?;
+        let profiles = match table.remove("profile") {
+            Some(profiles) => profiles.try_into().map_err(to_error)?,
+            None => BTreeMap::new(),
+        };
+        let settings = toml::Value::Table(table).try_into().map_err(to_error)?;
+        Ok(ConfigFile { path: path.to_path_buf(), settings, profiles })
+    }
+
+    /// The top-level settings, overlaid with those of `profile` if one is given.
+    pub fn settings_for(&self, profile: Option<&str>) -> Result<Settings, ConfigFileError> {
+        let Some(profile) = profile else {
+            return Ok(self.settings.clone())
+        };
+        let profile_settings = self.profiles.get(profile).ok_or_else(|| {
+            ConfigFileError::UnknownProfile { path: self.path.clone(), profile: profile.to_string() }
+        })?;
+        Ok(self.settings.clone().overlaid_with(profile_settings.clone()))
+    }
+}
+
+/// Directory holding the user's config files, following the XDG base directory spec.
+fn user_config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+/// The config file given with `--config`, or else the first of the user's config file and the
+/// input directory's one that exists.
+fn find_config_file(
+    config_file_args: &ConfigFileArgs,
+    input: &str,
+    user_config_dir: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(config) = &config_file_args.config {
+        return Some(PathBuf::from(config))
+    }
+    user_config_dir.map(|dir| dir.join("mp3_mover").join("config.toml"))
+        .into_iter()
+        .chain([Path::new(input).join(INPUT_DIR_CONFIG_FILE)])
+        .find(|path| path.is_file())
+}
+
+fn load_settings(
+    config_file_args: &ConfigFileArgs,
+    input: &str,
+) -> Result<Option<(PathBuf, Settings)>, ConfigFileError> {
+    let profile = config_file_args.profile.as_deref();
+    let Some(path) = find_config_file(config_file_args, input, user_config_dir()) else {
+        return match profile {
+            Some(profile) => Err(ConfigFileError::NoConfigFile { profile: profile.to_string() }),
+            None => Ok(None),
+        }
+    };
+    let settings = ConfigFile::load(&path)?.settings_for(profile)?;
+    Ok(Some((path, settings)))
+}
+
+/// Fills in the args of an organize run that weren't given on the command line from the
+/// config file, if one is found, returning its path.
+pub fn apply_config_file(args: &mut OrganizeArgs) -> Result<Option<PathBuf>, MoverError> {
+    let loaded = load_settings(&args.config_file, &args.input)
+        .map_err(|e| MoverError::Config(e.to_string()))?;
+    Ok(loaded.map(|(path, settings)| {
+        settings.apply_to_organize(args);
+        path
+    }))
+}
+
+/// Like `apply_config_file`, for the args of a scan.
+pub fn apply_config_file_to_scan(args: &mut ScanArgs) -> Result<Option<PathBuf>, MoverError> {
+    let loaded = load_settings(&args.config_file, &args.input)
+        .map_err(|e| MoverError::Config(e.to_string()))?;
+    Ok(loaded.map(|(path, settings)| {
+        settings.apply_to_scan(args);
+        path
+    }))
+}
+
+/// Like `apply_config_file`, for the args of a verify, looking in the library for a config
+/// file.
+pub fn apply_config_file_to_verify(args: &mut VerifyArgs) -> Result<Option<PathBuf>, MoverError> {
+    let loaded = load_settings(&args.config_file, &args.library)
+        .map_err(|e| MoverError::Config(e.to_string()))?;
+    Ok(loaded.map(|(path, settings)| {
+        settings.apply_to_verify(args);
+        path
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use crate::cli::{Cli, Command};
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+template = "{artist}/{album}/{title}.{ext}"
+mode = "copy"
+include-hidden = true
+
+[profile.car-usb]
+sanitize = "ascii"
+max-name-length = 64
+mode = "hardlink"
+"#;
+
+    fn organize_args(args: &[&str]) -> OrganizeArgs {
+        let args = ["/path/to/program"].iter().chain(args).chain(&["/in", "/out"]);
+        match Cli::parse_args(args).unwrap().command {
+            Command::Organize(organize_args) => organize_args,
+            _ => panic!("Expected organize subcommand"),
+        }
+    }
+
+    #[test]
+    fn profile_overlays_top_level_settings() {
+        let config_file = ConfigFile::parse(Path::new("config.toml"), CONFIG).unwrap();
+        let settings = config_file.settings_for(Some("car-usb")).unwrap();
+        let expected = Settings {
+            template: Some(String::from("{artist}/{album}/{title}.{ext}")),
+            mode: Some(Operation::Hardlink),
+            include_hidden: Some(true),
+            sanitize: Some(SanitizeProfile::Ascii),
+            max_name_length: Some(64),
+            ..Settings::default()
+        };
+        assert_eq!(settings, expected);
+        assert_eq!(config_file.settings_for(None).unwrap().mode, Some(Operation::Copy));
+        let res = config_file.settings_for(Some("phone"));
+        let expected_error_message = "Config file \"config.toml\" has no profile named phone";
+        assert!(res.is_err_and(|e| e.to_string() == expected_error_message));
+    }
+
+    #[test]
+    fn unknown_settings_rejected() {
+        let res = ConfigFile::parse(Path::new("config.toml"), "on-colision = \"skip\"\n");
+        assert!(res.is_err_and(|e| e.to_string().contains("unknown field `on-colision`")));
+        let res = ConfigFile::parse(Path::new("config.toml"), "[profile.car]\nmode = \"teleport\"\n");
+        assert!(res.is_err_and(|e| e.to_string().contains("unknown variant `teleport`")));
+    }
+
+    #[test]
+    fn command_line_args_take_precedence() {
+        let config_file = ConfigFile::parse(Path::new("config.toml"), CONFIG).unwrap();
+        let settings = config_file.settings_for(Some("car-usb")).unwrap();
+        let mut args = organize_args(&["--mode", "move", "--max-name-length", "100"]);
+        settings.apply_to_organize(&mut args);
+        assert_eq!(args.layout.template, "{artist}/{album}/{title}.{ext}");
+        assert_eq!(args.mode, Operation::Move);
+        assert!(args.walk.include_hidden);
+        assert_eq!(args.naming.sanitize, SanitizeProfile::Ascii);
+        assert_eq!(args.naming.max_name_length, 100);
+    }
+
+    #[test]
+    fn no_include_hidden_overrides_config_file() {
+        let config_file = ConfigFile::parse(Path::new("config.toml"), CONFIG).unwrap();
+        let settings = config_file.settings_for(Some("car-usb")).unwrap();
+        let mut args = organize_args(&["--no-include-hidden"]);
+        settings.apply_to_organize(&mut args);
+        assert!(!args.walk.include_hidden);
+        // The last of the two flags wins
+        let mut args = organize_args(&["--no-include-hidden", "--include-hidden"]);
+        settings.apply_to_organize(&mut args);
+        assert!(args.walk.include_hidden);
+        let mut args = organize_args(&["--include-hidden", "--no-include-hidden"]);
+        settings.apply_to_organize(&mut args);
+        assert!(!args.walk.include_hidden);
+    }
+
+    #[test]
+    fn config_file_found_in_order() {
+        let user_dir = tempdir().unwrap();
+        let input_dir = tempdir().unwrap();
+        let input = input_dir.as_ref().to_str().unwrap();
+        let user_config = user_dir.as_ref().join("mp3_mover").join("config.toml");
+        let input_config = input_dir.as_ref().join(INPUT_DIR_CONFIG_FILE);
+        let config_file_args = ConfigFileArgs::default();
+        let user_config_dir = || Some(user_dir.as_ref().to_path_buf());
+        assert_eq!(find_config_file(&config_file_args, input, user_config_dir()), None);
+        write(&input_config, CONFIG).unwrap();
+        assert_eq!(
+            find_config_file(&config_file_args, input, user_config_dir()),
+            Some(input_config.clone()),
+        );
+        create_dir_all(user_config.parent().unwrap()).unwrap();
+        write(&user_config, CONFIG).unwrap();
+        assert_eq!(
+            find_config_file(&config_file_args, input, user_config_dir()),
+            Some(user_config),
+        );
+        let config_file_args = ConfigFileArgs {
+            config: Some(String::from("/etc/mp3_mover.toml")),
+            ..ConfigFileArgs::default()
+        };
+        assert_eq!(
+            find_config_file(&config_file_args, input, user_config_dir()),
+            Some(PathBuf::from("/etc/mp3_mover.toml")),
+        );
+    }
+
+    #[test]
+    fn effective_settings_round_trip() {
+        let args = organize_args(&["--mode", "copy", "--max-depth", "2"]);
+        let settings = Settings::from_organize_args(&args);
+        let text = toml::to_string(&settings).unwrap();
+        let config_file = ConfigFile::parse(Path::new("config.toml"), &text).unwrap();
+        assert_eq!(config_file.settings, settings);
+    }
+}
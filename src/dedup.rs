@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::fs::{File, metadata};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::mpeg::id3v2_size;
+use crate::pipeline::NamedSong;
+
+/// Directory in the output directory that duplicates are moved into under
+/// `DuplicatePolicy::MoveExtras`.
+pub const DUPLICATES_DIR: &str = "duplicates";
+
+/// Which of the song files with the same audio is organised, the others being left where they
+/// are unless they're moved aside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Keep the copy with the highest bitrate
+    BestBitrate,
+    /// Keep the most recently modified copy
+    Newest,
+    /// Keep the copy found first
+    First,
+    /// Keep the copy found first and move the others into duplicates/ in the output directory
+    MoveExtras,
+}
+
+/// Identifies a song file's audio, leaving out its tags so that copies tagged differently
+/// still match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentHash {
+    len: u64,
+    hash: u64,
+}
+
+/// Start and end offsets of the parts of a file that hold its audio.
+type Payload = Vec<(u64, u64)>;
+
+fn read_at<const N: usize>(file: &mut File, offset: u64) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The bytes of an MP3 file between its ID3v2 tag at the start and any ID3v1 and APEv2 tags
+/// at the end.
+fn mp3_payload(file: &mut File, size: u64) -> io::Result<Payload> {
+    let mut header = [0; 10];
+    let read = file.read(&mut header)?;
+    let start = (id3v2_size(&header[..read]) as u64).min(size);
+    let mut end = size;
+    if end >= start + 128 && &read_at::<3>(file, end - 128)? == b"TAG" {
+        end -= 128;
+    }
+    if end >= start + 32 {
+        let footer = read_at::<32>(file, end - 32)?;
+        if footer.starts_with(b"APETAGEX") {
+            // The size covers the items and the footer but not the header, if there is one
+            let tag_size = u64::from(u32::from_le_bytes(footer[12..16].try_into().unwrap()));
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header_size = if flags & (1 << 31) != 0 { 32 } else { 0 };
+            end = end.saturating_sub(tag_size + header_size).max(start);
+        }
+    }
+    Ok(vec![(start, end)])
+}
+
+/// The frames of a FLAC file, after its metadata blocks.
+fn flac_payload(file: &mut File, size: u64) -> io::Result<Payload> {
+    let mut header = [0; 10];
+    let read = file.read(&mut header)?;
+    let mut offset = id3v2_size(&header[..read]) as u64;
+    if offset + 4 > size || &read_at::<4>(file, offset)? != b"fLaC" {
+        return Ok(vec![(0, size)])
+    }
+    offset += 4;
+    while offset + 4 <= size {
+        let block_header = read_at::<4>(file, offset)?;
+        let len = u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]);
+        offset += 4 + u64::from(len);
+        if block_header[0] & 0x80 != 0 {
+            break
+        }
+    }
+    Ok(vec![(offset.min(size), size)])
+}
+
+/// The contents of an MP4 file's `mdat` atoms, which hold its audio, or the whole file if it
+/// has none that can be found.
+fn mp4_payload(file: &mut File, size: u64) -> io::Result<Payload> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let header = read_at::<8>(file, offset)?;
+        let (atom_size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (size - offset, 8),
+            1 if offset + 16 <= size => {
+                (u64::from_be_bytes(read_at::<8>(file, offset + 8)?), 16)
+            },
+            atom_size => (u64::from(atom_size), 8),
+        };
+        if atom_size < header_len {
+            return Ok(vec![(0, size)])
+        }
+        let end = (offset + atom_size).min(size);
+        if &header[4..] == b"mdat" {
+            ranges.push((offset + header_len, end));
+        }
+        offset = end;
+    }
+    if ranges.is_empty() {
+        ranges.push((0, size));
+    }
+    Ok(ranges)
+}
+
+/// Where the audio is in the song file at `path`, going by its extension.
+fn payload_ranges(path: &Path) -> io::Result<Payload> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let extension = path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp3" => mp3_payload(&mut file, size),
+        "flac" => flac_payload(&mut file, size),
+        "m4a" | "m4b" | "mp4" | "alac" => mp4_payload(&mut file, size),
+        _ => Ok(vec![(0, size)]),
+    }
+}
+
+fn payload_len(ranges: &[(u64, u64)]) -> u64 {
+    ranges.iter().map(|(start, end)| end - start).sum()
+}
+
+/// Reads the parts of the file at `path` given by `ranges` one after the other.
+fn payload_reader(path: &Path, ranges: &[(u64, u64)]) -> io::Result<Box<dyn Read>> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for &(start, end) in ranges.iter() {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        reader = Box::new(reader.chain(file.take(end - start)));
+    }
+    Ok(reader)
+}
+
+/// Reads from `reader` until `buffer` is full or there's nothing left, returning how much was
+/// read.
+fn fill_buffer(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn hash_ranges(path: &Path, ranges: &[(u64, u64)]) -> io::Result<ContentHash> {
+    let mut reader = payload_reader(path, ranges)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break
+        }
+        hasher.write(&buffer[..read]);
+    }
+    Ok(ContentHash { len: payload_len(ranges), hash: hasher.finish() })
+}
+
+/// Compares the audio of two song files byte for byte, as a matching hash only makes it likely
+/// that they're the same.
+fn same_payload(a: (&Path, &[(u64, u64)]), b: (&Path, &[(u64, u64)])) -> io::Result<bool> {
+    if payload_len(a.1) != payload_len(b.1) {
+        return Ok(false)
+    }
+    let (mut a_reader, mut b_reader) = (payload_reader(a.0, a.1)?, payload_reader(b.0, b.1)?);
+    let (mut a_buffer, mut b_buffer) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let a_read = fill_buffer(&mut a_reader, &mut a_buffer)?;
+        let b_read = fill_buffer(&mut b_reader, &mut b_buffer)?;
+        if a_buffer[..a_read] != b_buffer[..b_read] {
+            return Ok(false)
+        }
+        if a_read == 0 {
+            return Ok(true)
+        }
+    }
+}
+
+/// Hashes the audio of the song file at `path`, leaving out its tags.
+pub fn content_hash(path: &Path) -> io::Result<ContentHash> {
+    hash_ranges(path, &payload_ranges(path)?)
+}
+
+fn pick_best<'s>(
+    copies: &[&'s NamedSong],
+    is_better: impl Fn(&NamedSong, &NamedSong) -> bool,
+) -> &'s NamedSong {
+    copies.iter().copied()
+        .reduce(|best, song| if is_better(song, best) { song } else { best })
+        .expect("There are always copies to pick from")
+}
+
+/// Picks the song file to keep out of `copies`, which all have the same audio, going with the
+/// first one on a tie. Copies that can be organised are preferred, so that the one kept isn't
+/// then skipped for a missing field.
+fn keep<'s>(copies: &[&'s NamedSong], policy: DuplicatePolicy) -> &'s NamedSong {
+    let placeable: Vec<&NamedSong> = copies.iter()
+        .copied()
+        .filter(|copy| copy.destination.is_ok())
+        .collect();
+    let copies = if placeable.is_empty() { copies } else { &placeable };
+    match policy {
+        DuplicatePolicy::First | DuplicatePolicy::MoveExtras => copies[0],
+        DuplicatePolicy::BestBitrate => {
+            let bitrate = |song: &NamedSong| {
+                song.metadata.as_ref().and_then(|metadata| metadata.bitrate)
+            };
+            pick_best(copies, |song, best| bitrate(song) > bitrate(best))
+        },
+        DuplicatePolicy::Newest => {
+            let modified = |song: &NamedSong| {
+                metadata(&song.path).and_then(|metadata| metadata.modified()).ok()
+            };
+            pick_best(copies, |song, best| modified(song) > modified(best))
+        },
+    }
+}
+
+/// Finds the song files among `named_songs` whose audio is the same as another's, mapping each
+/// one that isn't kept under `policy` to the one that is. Only song files with the same amount
+/// of audio are hashed, and those whose tags couldn't be read are left out.
+pub fn find_duplicates(
+    named_songs: &[NamedSong],
+    policy: DuplicatePolicy,
+) -> HashMap<PathBuf, PathBuf> {
+    let mut by_len: HashMap<u64, Vec<(&NamedSong, Payload)>> = HashMap::new();
+    for named_song in named_songs.iter().filter(|named_song| named_song.metadata.is_some()) {
+        match payload_ranges(&named_song.path) {
+            // Files with no audio at all can't be told apart
+            Ok(ranges) if payload_len(&ranges) == 0 => {},
+            Ok(ranges) => {
+                by_len.entry(payload_len(&ranges)).or_default().push((named_song, ranges))
+            },
+            Err(e) => warn!("Couldn't read {:?} to look for duplicates: {}", named_song.path, e),
+        }
+    }
+    let mut groups: Vec<Vec<(&NamedSong, Payload)>> = Vec::new();
+    let mut group_indices: HashMap<ContentHash, usize> = HashMap::new();
+    for candidates in by_len.into_values().filter(|candidates| candidates.len() > 1) {
+        for (named_song, ranges) in candidates {
+            match hash_ranges(&named_song.path, &ranges) {
+                Ok(hash) => {
+                    let i = *group_indices.entry(hash).or_insert_with(|| {
+                        groups.push(Vec::new());
+                        groups.len() - 1
+                    });
+                    groups[i].push((named_song, ranges));
+                },
+                Err(e) => {
+                    warn!("Couldn't hash {:?} to look for duplicates: {}", named_song.path, e)
+                },
+            }
+        }
+    }
+    let mut duplicates = HashMap::new();
+    for group in groups.into_iter().filter(|group| group.len() > 1) {
+        for copies in identical_copies(group).iter().filter(|copies| copies.len() > 1) {
+            let kept = keep(copies, policy);
+            for copy in copies.iter().filter(|copy| copy.path != kept.path) {
+                info!("Song file {:?} has the same audio as {:?}", copy.path, kept.path);
+                duplicates.insert(copy.path.clone(), kept.path.clone());
+            }
+        }
+    }
+    duplicates
+}
+
+/// Splits song files with the same hash into those whose audio really is the same, so that a
+/// hash collision can't set aside a different song.
+fn identical_copies(group: Vec<(&NamedSong, Payload)>) -> Vec<Vec<&NamedSong>> {
+    let mut copies: Vec<Vec<(&NamedSong, Payload)>> = Vec::new();
+    for (named_song, ranges) in group {
+        let mut matching = None;
+        for (i, same_audio) in copies.iter().enumerate() {
+            let (first, first_ranges) = &same_audio[0];
+            match same_payload((&first.path, first_ranges), (&named_song.path, &ranges)) {
+                Ok(true) => {
+                    matching = Some(i);
+                    break
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    warn!("Couldn't compare {:?} with {:?}: {}", named_song.path, first.path, e)
+                },
+            }
+        }
+        match matching {
+            Some(i) => copies[i].push((named_song, ranges)),
+            None => copies.push(vec![(named_song, ranges)]),
+        }
+    }
+    copies.into_iter()
+        .map(|same_audio| same_audio.into_iter().map(|(named_song, _)| named_song).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{OpenOptions, write};
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    use id3::{Tag, TagLike, Version};
+    use tempfile::tempdir;
+
+    use crate::metadata::TrackMetadata;
+    use crate::plan::SkipReason;
+
+    use super::*;
+
+    fn mp3_with_tag(path: &Path, audio: &[u8], title: &str) {
+        write(path, audio).unwrap();
+        let mut tag = Tag::new();
+        tag.set_title(title);
+        tag.write_to_path(path, Version::Id3v24).unwrap();
+    }
+
+    fn named_song(path: &Path, bitrate: Option<u32>) -> NamedSong {
+        NamedSong {
+            path: path.to_path_buf(),
+            metadata: Some(TrackMetadata { bitrate, ..TrackMetadata::default() }),
+            destination: Ok(PathBuf::from("Artist/Album/Song.mp3")),
+        }
+    }
+
+    #[test]
+    fn retagged_mp3_copies_have_same_hash() {
+        let dir = tempdir().unwrap();
+        let a = dir.as_ref().join("A.mp3");
+        let b = dir.as_ref().join("B.mp3");
+        let c = dir.as_ref().join("C.mp3");
+        mp3_with_tag(&a, &[1; 1000], "Song");
+        mp3_with_tag(&b, &[1; 1000], "Song (Remastered)");
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        OpenOptions::new().append(true).open(&b).unwrap().write_all(&id3v1).unwrap();
+        mp3_with_tag(&c, &[2; 1000], "Song");
+        assert_eq!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+        assert_ne!(content_hash(&a).unwrap(), content_hash(&c).unwrap());
+    }
+
+    #[test]
+    fn flac_metadata_blocks_left_out() {
+        let dir = tempdir().unwrap();
+        let flac = |comment: &[u8]| {
+            let mut bytes = b"fLaC".to_vec();
+            bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb]);
+            bytes.extend_from_slice(&[0x84, 0x00, 0x00, comment.len() as u8]);
+            bytes.extend_from_slice(comment);
+            bytes.extend_from_slice(&[3; 500]);
+            bytes
+        };
+        let (a, b) = (dir.as_ref().join("A.flac"), dir.as_ref().join("B.flac"));
+        write(&a, flac(b"title=Song")).unwrap();
+        write(&b, flac(b"title=Another Song")).unwrap();
+        assert_eq!(payload_ranges(&a).unwrap(), vec![(24, 524)]);
+        assert_eq!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+    }
+
+    #[test]
+    fn mp4_hash_covers_only_mdat() {
+        let dir = tempdir().unwrap();
+        let atom = |kind: &[u8], contents: &[u8]| {
+            let mut bytes = (8 + contents.len() as u32).to_be_bytes().to_vec();
+            bytes.extend_from_slice(kind);
+            bytes.extend_from_slice(contents);
+            bytes
+        };
+        let mp4 = |title: &[u8]| {
+            [atom(b"ftyp", b"M4A "), atom(b"mdat", &[4; 300]), atom(b"moov", title)].concat()
+        };
+        let (a, b) = (dir.as_ref().join("A.m4a"), dir.as_ref().join("B.m4a"));
+        write(&a, mp4(b"Song")).unwrap();
+        write(&b, mp4(b"Another Song")).unwrap();
+        assert_eq!(payload_ranges(&a).unwrap(), vec![(20, 320)]);
+        assert_eq!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+    }
+
+    #[test]
+    fn payloads_compared_byte_for_byte() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["A.mp3", "B.mp3", "C.mp3"].iter()
+            .map(|name| dir.as_ref().join(name))
+            .collect();
+        let mut audio = vec![7; 100_000];
+        mp3_with_tag(&paths[0], &audio, "Song");
+        audio[99_999] = 8;
+        mp3_with_tag(&paths[1], &audio, "Song");
+        mp3_with_tag(&paths[2], &[7; 100_000], "Song (Live)");
+        let ranges: Vec<Payload> = paths.iter().map(|path| payload_ranges(path).unwrap()).collect();
+        assert!(same_payload((&paths[0], &ranges[0]), (&paths[2], &ranges[2])).unwrap());
+        assert!(!same_payload((&paths[0], &ranges[0]), (&paths[1], &ranges[1])).unwrap());
+        // Even if their hashes had matched, the different song isn't counted as a copy
+        let named_songs: Vec<NamedSong> = paths.iter().map(|path| named_song(path, None)).collect();
+        let group = named_songs.iter().zip(ranges).collect();
+        let copies: Vec<Vec<&Path>> = identical_copies(group).into_iter()
+            .map(|same_audio| same_audio.into_iter().map(|song| song.path.as_path()).collect())
+            .collect();
+        assert_eq!(copies, vec![vec![&paths[0], &paths[2]], vec![&paths[1]]]);
+    }
+
+    #[test]
+    fn copy_that_can_be_organised_kept() {
+        let dir = tempdir().unwrap();
+        let (a, b) = (dir.as_ref().join("A.mp3"), dir.as_ref().join("B.mp3"));
+        mp3_with_tag(&a, &[5; 2000], "Song");
+        mp3_with_tag(&b, &[5; 2000], "Song");
+        let untagged = NamedSong {
+            destination: Err(SkipReason::MissingField(String::from("artist"))),
+            ..named_song(&a, Some(320))
+        };
+        let named_songs = vec![untagged, named_song(&b, Some(128))];
+        let policies = [
+            DuplicatePolicy::First,
+            DuplicatePolicy::MoveExtras,
+            DuplicatePolicy::BestBitrate,
+        ];
+        for policy in policies {
+            let duplicates = find_duplicates(&named_songs, policy);
+            let expected = HashMap::from([(a.clone(), b.clone())]);
+            assert_eq!(duplicates, expected, "Policy: {:?}", policy);
+        }
+    }
+
+    #[test]
+    fn policies_pick_which_copy_to_keep() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["A.mp3", "B.mp3", "C.mp3", "D.mp3"].iter()
+            .map(|name| dir.as_ref().join(name))
+            .collect();
+        for (path, title) in paths[..3].iter().zip(["One", "Two", "Three"]) {
+            mp3_with_tag(path, &[5; 2000], title);
+        }
+        mp3_with_tag(&paths[3], &[6; 2000], "Other");
+        let now = SystemTime::now();
+        File::options().write(true).open(&paths[0]).unwrap()
+            .set_modified(now - Duration::from_secs(60)).unwrap();
+        File::options().write(true).open(&paths[2]).unwrap().set_modified(now).unwrap();
+        File::options().write(true).open(&paths[1]).unwrap()
+            .set_modified(now - Duration::from_secs(120)).unwrap();
+        let named_songs = vec![
+            named_song(&paths[0], Some(128)),
+            named_song(&paths[1], Some(320)),
+            named_song(&paths[2], None),
+            named_song(&paths[3], Some(320)),
+        ];
+        let kept_copy = |policy| {
+            let duplicates = find_duplicates(&named_songs, policy);
+            assert_eq!(duplicates.len(), 2);
+            assert!(!duplicates.contains_key(&paths[3]));
+            paths[..3].iter().find(|path| !duplicates.contains_key(*path)).unwrap().clone()
+        };
+        assert_eq!(kept_copy(DuplicatePolicy::First), paths[0]);
+        assert_eq!(kept_copy(DuplicatePolicy::MoveExtras), paths[0]);
+        assert_eq!(kept_copy(DuplicatePolicy::BestBitrate), paths[1]);
+        assert_eq!(kept_copy(DuplicatePolicy::Newest), paths[2]);
+    }
+}
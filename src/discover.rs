@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use std::fs::{read_dir, symlink_metadata};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Extensions of the song file formats that tags can be read from.
+pub const SUPPORTED_EXTENSIONS: [&str; 6] = ["mp3", "flac", "m4a", "m4b", "mp4", "alac"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Ignore symlinks entirely
+    Skip,
+    /// Follow symlinks to files but don't descend into symlinked directories
+    FilesOnly,
+    /// Follow symlinks to both files and directories
+    #[default]
+    Follow,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// Maximum number of directory levels below the input directory to descend into, where
+    /// `Some(0)` means only files directly inside the input directory are found.
+    pub max_depth: Option<usize>,
+    pub symlinks: SymlinkPolicy,
+    pub include_hidden: bool,
+    /// Number of threads song files' tags are read on, or one per CPU if `None`.
+    pub jobs: Option<NonZeroUsize>,
+}
+
+pub(crate) fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false)
+}
+
+fn is_song_file(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy().to_lowercase();
+            SUPPORTED_EXTENSIONS.contains(&ext.as_str())
+        })
+        .unwrap_or(false)
+}
+
+/// Recursively finds song files under `dir`, sorted by path within each directory so that the
+/// order is stable across runs.
+pub fn find_song_files(dir: &Path, options: &WalkOptions) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = find_files(dir, options)?;
+    files.retain(|path| is_song_file(path));
+    Ok(files)
+}
+
+/// Like `find_song_files`, but finds files of every type so that the ones that aren't song
+/// files can be reported.
+pub fn find_files(dir: &Path, options: &WalkOptions) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut visited_dirs = HashSet::new();
+    if let Ok(canonical_dir) = dir.canonicalize() {
+        visited_dirs.insert(canonical_dir);
+    }
+    walk_dir(dir, 0, options, &mut visited_dirs, &mut files)?;
+    Ok(files)
+}
+
+fn walk_dir(
+    dir: &Path,
+    depth: usize,
+    options: &WalkOptions,
+    visited_dirs: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let mut entries = Vec::new();
+    for child in read_dir(dir)? {
+        match child {
+            Ok(val) => entries.push(val.path()),
+            Err(e) => {
+                warn!("During reading of dir {:?} encountered error {:?}; moving on", dir, e);
+            }
+        }
+    }
+    entries.sort();
+
+    for path in entries {
+        if !options.include_hidden && is_hidden(&path) {
+            info!("Skipping hidden path {:?}", path);
+            continue;
+        }
+        let is_symlink = symlink_metadata(&path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink && options.symlinks == SymlinkPolicy::Skip {
+            info!("Skipping symlink {:?}", path);
+            continue;
+        }
+
+        if path.is_dir() {
+            if is_symlink && options.symlinks == SymlinkPolicy::FilesOnly {
+                info!("Not descending into symlinked dir {:?}", path);
+                continue;
+            }
+            if options.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                continue;
+            }
+            // Symlinks can point back up the tree, so guard against walking a dir twice
+            match path.canonicalize() {
+                Ok(canonical_path) => {
+                    if !visited_dirs.insert(canonical_path) {
+                        warn!("Dir {:?} has already been visited, skipping", path);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!("Couldn't resolve dir {:?} due to error {:?}; moving on", path, e);
+                    continue;
+                }
+            }
+            if let Err(e) = walk_dir(&path, depth + 1, options, visited_dirs, files) {
+                warn!("During reading of dir {:?} encountered error {:?}; moving on", path, e);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, create_dir_all};
+    use std::os::unix::fs::symlink;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn create_nested_song_files(root: &Path) -> Vec<PathBuf> {
+        let song_files = [
+            root.join("A.mp3"),
+            root.join("F00").join("B.mp3"),
+            root.join("Artist").join("Album").join("CD1").join("C.mp3"),
+        ];
+        for song_file in song_files.iter() {
+            create_dir_all(song_file.parent().unwrap()).unwrap();
+            File::create(song_file).unwrap();
+        }
+        song_files.to_vec()
+    }
+
+    #[test]
+    fn find_song_files_at_every_depth() {
+        let indir = tempdir().unwrap();
+        let expected_song_files = create_nested_song_files(indir.as_ref());
+        File::create(indir.as_ref().join("F00").join("D.jpg")).unwrap();
+        File::create(indir.as_ref().join("F00").join("E.wav")).unwrap();
+        let song_files = find_song_files(indir.as_ref(), &WalkOptions::default()).unwrap();
+        assert_eq!(song_files.len(), expected_song_files.len());
+        for song_file in expected_song_files {
+            assert!(song_files.contains(&song_file));
+        }
+    }
+
+    #[test]
+    fn find_every_supported_format() {
+        let indir = tempdir().unwrap();
+        let song_files = ["A.mp3", "B.flac", "C.m4a", "D.m4b", "E.mp4", "F.alac", "G.MP3"];
+        for song_file in song_files.iter() {
+            File::create(indir.as_ref().join(song_file)).unwrap();
+        }
+        let found_song_files = find_song_files(indir.as_ref(), &WalkOptions::default()).unwrap();
+        let expected_song_files: Vec<PathBuf> = song_files.iter()
+            .map(|song_file| indir.as_ref().join(song_file))
+            .collect();
+        assert_eq!(found_song_files, expected_song_files);
+    }
+
+    #[test]
+    fn max_depth_limits_descent() {
+        let indir = tempdir().unwrap();
+        let song_files = create_nested_song_files(indir.as_ref());
+        let options = WalkOptions { max_depth: Some(1), ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert_eq!(found_song_files, song_files[..2].to_vec());
+        let options = WalkOptions { max_depth: Some(0), ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert_eq!(found_song_files, song_files[..1].to_vec());
+    }
+
+    #[test]
+    fn hidden_entries_skipped_unless_included() {
+        let indir = tempdir().unwrap();
+        let hidden_dir = indir.as_ref().join(".Trash");
+        create_dir_all(hidden_dir.clone()).unwrap();
+        File::create(hidden_dir.join("A.mp3")).unwrap();
+        File::create(indir.as_ref().join("._B.mp3")).unwrap();
+        let found_song_files = find_song_files(indir.as_ref(), &WalkOptions::default()).unwrap();
+        assert!(found_song_files.is_empty());
+        let options = WalkOptions { include_hidden: true, ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert_eq!(found_song_files.len(), 2);
+    }
+
+    #[test]
+    fn symlink_policies() {
+        let indir = tempdir().unwrap();
+        let elsewhere = tempdir().unwrap();
+        let linked_dir = elsewhere.as_ref().join("Album");
+        create_dir_all(linked_dir.clone()).unwrap();
+        File::create(linked_dir.join("A.mp3")).unwrap();
+        File::create(elsewhere.as_ref().join("B.mp3")).unwrap();
+        symlink(&linked_dir, indir.as_ref().join("Album")).unwrap();
+        symlink(elsewhere.as_ref().join("B.mp3"), indir.as_ref().join("B.mp3")).unwrap();
+
+        let options = WalkOptions { symlinks: SymlinkPolicy::Follow, ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert_eq!(found_song_files.len(), 2);
+
+        let options = WalkOptions { symlinks: SymlinkPolicy::FilesOnly, ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert_eq!(found_song_files, vec![indir.as_ref().join("B.mp3")]);
+
+        let options = WalkOptions { symlinks: SymlinkPolicy::Skip, ..WalkOptions::default() };
+        let found_song_files = find_song_files(indir.as_ref(), &options).unwrap();
+        assert!(found_song_files.is_empty());
+    }
+
+    #[test]
+    fn symlink_loop_is_walked_once() {
+        let indir = tempdir().unwrap();
+        let subdir = indir.as_ref().join("F00");
+        create_dir_all(subdir.clone()).unwrap();
+        File::create(subdir.join("A.mp3")).unwrap();
+        symlink(indir.as_ref(), subdir.join("loop")).unwrap();
+        let found_song_files = find_song_files(indir.as_ref(), &WalkOptions::default()).unwrap();
+        assert_eq!(found_song_files, vec![subdir.join("A.mp3")]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use crate::collision::CollisionError;
+
+#[derive(Debug)]
+pub enum MoverError {
+    /// The given args or config don't describe a valid run
+    Config(String),
+    /// The input directory couldn't be searched for song files
+    Discovery { path: PathBuf, source: std::io::Error },
+    /// A song file's tag couldn't be read
+    TagRead { path: PathBuf, reason: String },
+    /// A song file's tag lacks a field needed to build its destination
+    MissingField { path: PathBuf, field: String },
+    /// Creating a directory, placing a song file, or reading or writing a journal failed
+    Filesystem { path: PathBuf, source: std::io::Error },
+    /// Two song files would end up at the same destination under `CollisionPolicy::Fail`
+    Collision(CollisionError),
+}
+
+impl MoverError {
+    /// Exit code for the binary to report the error with. Codes grow with how much attention the
+    /// error needs, so that a run with several kinds of per-file error can exit with the highest.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MoverError::Config(_) => 2,
+            MoverError::MissingField { .. } => 3,
+            MoverError::TagRead { .. } => 4,
+            MoverError::Discovery { .. } => 5,
+            MoverError::Collision(_) => 6,
+            MoverError::Filesystem { .. } => 7,
+        }
+    }
+}
+
+impl Display for MoverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoverError::Config(message) => write!(f, "{}", message),
+            MoverError::Discovery { path, source } => {
+                write!(f, "Couldn't search {:?} for song files: {}", path, source)
+            },
+            MoverError::TagRead { path, reason } => {
+                write!(f, "Couldn't read tag of {:?}: {}", path, reason)
+            },
+            MoverError::MissingField { path, field } => {
+                write!(f, "Song file {:?} has missing field {}", path, field)
+            },
+            MoverError::Filesystem { path, source } => write!(f, "{:?}: {}", path, source),
+            MoverError::Collision(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for MoverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MoverError::Discovery { source, .. } | MoverError::Filesystem { source, .. } => {
+                Some(source)
+            },
+            MoverError::Collision(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CollisionError> for MoverError {
+    fn from(e: CollisionError) -> MoverError {
+        MoverError::Collision(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = [
+            MoverError::Config(String::from("Input directory arg doesn't exist")),
+            MoverError::Discovery {
+                path: PathBuf::from("/in"),
+                source: std::io::Error::from(ErrorKind::PermissionDenied),
+            },
+            MoverError::TagRead { path: PathBuf::from("/in/A.mp3"), reason: String::new() },
+            MoverError::MissingField {
+                path: PathBuf::from("/in/A.mp3"),
+                field: String::from("artist"),
+            },
+            MoverError::Filesystem {
+                path: PathBuf::from("/out/A.mp3"),
+                source: std::io::Error::from(ErrorKind::PermissionDenied),
+            },
+            MoverError::Collision(CollisionError {
+                source: PathBuf::from("/in/A.mp3"),
+                destination: PathBuf::from("/out/A.mp3"),
+            }),
+        ];
+        let mut exit_codes: Vec<i32> = errors.iter().map(MoverError::exit_code).collect();
+        exit_codes.sort();
+        exit_codes.dedup();
+        assert_eq!(exit_codes.len(), errors.len());
+        assert!(!exit_codes.contains(&0) && !exit_codes.contains(&1));
+    }
+}
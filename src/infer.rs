@@ -0,0 +1,322 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use crate::metadata::TrackMetadata;
+use crate::template::Field;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    Empty,
+    EmptyComponent,
+    UnmatchedBrace,
+    UnterminatedPlaceholder,
+    AdjacentPlaceholders,
+    UnknownField(String),
+    UnsupportedField(String),
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternError::Empty => write!(f, "Path pattern is empty"),
+            PatternError::EmptyComponent => {
+                write!(f, "Path pattern contains an empty path component")
+            },
+            PatternError::UnmatchedBrace => write!(f, "Path pattern contains an unmatched '}}'"),
+            PatternError::UnterminatedPlaceholder => {
+                write!(f, "Path pattern contains a placeholder with no closing '}}'")
+            },
+            PatternError::AdjacentPlaceholders => write!(
+                f,
+                "Path pattern contains placeholders with no text between them to tell them apart",
+            ),
+            PatternError::UnknownField(field) => {
+                write!(f, "Path pattern contains unknown field: {}", field)
+            },
+            PatternError::UnsupportedField(field) => {
+                write!(f, "Path pattern field {} can't be inferred from a path", field)
+            },
+        }
+    }
+}
+
+impl Error for PatternError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// A pattern such as `{artist} - {album}/{track} - {title}` describing how tag fields can be
+/// read from the last components of a song file's path, the last of which is matched without
+/// its extension.
+///
+/// Each placeholder matches as little text as it can, and has surrounding whitespace trimmed.
+/// Number fields only match digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    components: Vec<Vec<Segment>>,
+}
+
+impl FromStr for PathPattern {
+    type Err = PatternError;
+
+    fn from_str(pattern: &str) -> Result<PathPattern, PatternError> {
+        if pattern.is_empty() {
+            return Err(PatternError::Empty)
+        }
+        let mut components = Vec::new();
+        for raw_component in pattern.split('/') {
+            if raw_component.is_empty() {
+                return Err(PatternError::EmptyComponent)
+            }
+            components.push(parse_component(raw_component)?);
+        }
+        Ok(PathPattern { components })
+    }
+}
+
+fn parse_component(raw_component: &str) -> Result<Vec<Segment>, PatternError> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = raw_component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '}' => return Err(PatternError::UnmatchedBrace),
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(PatternError::UnterminatedPlaceholder),
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                    }
+                }
+                let name = name.trim();
+                let field = Field::from_name(name)
+                    .ok_or(PatternError::UnknownField(name.to_string()))?;
+                let is_derived = matches!(
+                    field,
+                    Field::Group | Field::MultiDisc | Field::Codec | Field::Filename | Field::Ext
+                );
+                if is_derived {
+                    return Err(PatternError::UnsupportedField(name.to_string()))
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                } else if matches!(segments.last(), Some(Segment::Field(_))) {
+                    return Err(PatternError::AdjacentPlaceholders)
+                }
+                segments.push(Segment::Field(field));
+            },
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+fn is_number_field(field: Field) -> bool {
+    matches!(
+        field,
+        Field::Year | Field::Track | Field::TotalTracks | Field::Disc | Field::TotalDiscs
+    )
+}
+
+fn match_segments<'p>(segments: &[Segment], text: &'p str) -> Option<Vec<(Field, &'p str)>> {
+    match segments {
+        [] if text.is_empty() => Some(Vec::new()),
+        [] => None,
+        [Segment::Literal(literal), rest @ ..] => {
+            match_segments(rest, text.strip_prefix(literal.as_str())?)
+        },
+        [Segment::Field(field), rest @ ..] => {
+            let is_valid = |value: &str| {
+                !value.is_empty()
+                    && (!is_number_field(*field) || value.chars().all(|c| c.is_ascii_digit()))
+            };
+            let Some(Segment::Literal(next_literal)) = rest.first() else {
+                let value = text.trim();
+                return is_valid(value).then(|| vec![(*field, value)])
+            };
+            // Try the shortest value first, so that e.g. the first " - " ends the artist
+            for (i, _) in text.match_indices(next_literal.as_str()) {
+                let value = text[..i].trim();
+                if !is_valid(value) {
+                    continue;
+                }
+                if let Some(mut captured) = match_segments(rest, &text[i..]) {
+                    captured.insert(0, (*field, value));
+                    return Some(captured)
+                }
+            }
+            None
+        },
+    }
+}
+
+impl PathPattern {
+    /// Reads the fields the pattern describes from `path`, returning nothing unless the whole
+    /// pattern matches.
+    pub(crate) fn infer<'p>(&self, path: &'p Path) -> Vec<(Field, &'p str)> {
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            return Vec::new()
+        };
+        let mut texts = vec![stem];
+        let parent_components = path.parent()
+            .into_iter()
+            .flat_map(|parent| parent.components().rev());
+        for component in parent_components {
+            match component {
+                Component::Normal(name) => match name.to_str() {
+                    Some(name) => texts.push(name),
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+        if texts.len() < self.components.len() {
+            return Vec::new()
+        }
+        let mut captured_per_component = Vec::new();
+        for (segments, text) in self.components.iter().rev().zip(texts) {
+            match match_segments(segments, text) {
+                Some(captured) => captured_per_component.push(captured),
+                None => return Vec::new(),
+            }
+        }
+        captured_per_component.into_iter().rev().flatten().collect()
+    }
+}
+
+impl TrackMetadata {
+    /// Fills in the fields missing from the tag with those `pattern` finds in `path`, noting
+    /// which ones were filled.
+    pub(crate) fn fill_from_path(&mut self, pattern: &PathPattern, path: &Path) {
+        fn fill_str(slot: &mut Option<String>, value: &str) -> bool {
+            if slot.as_ref().is_some_and(|existing| !existing.is_empty()) {
+                return false
+            }
+            *slot = Some(value.to_string());
+            true
+        }
+        fn fill_number<T: FromStr>(slot: &mut Option<T>, value: &str) -> bool {
+            if slot.is_some() {
+                return false
+            }
+            *slot = value.parse().ok();
+            slot.is_some()
+        }
+        for (field, value) in pattern.infer(path) {
+            let filled = match field {
+                Field::Artist => fill_str(&mut self.artist, value),
+                Field::AlbumArtist => fill_str(&mut self.album_artist, value),
+                Field::Album => fill_str(&mut self.album, value),
+                Field::Title => fill_str(&mut self.title, value),
+                Field::Genre => fill_str(&mut self.genre, value),
+                Field::Composer => fill_str(&mut self.composer, value),
+                Field::Date => fill_str(&mut self.date, value),
+                Field::Year => fill_number(&mut self.year, value),
+                Field::Track => fill_number(&mut self.track_number, value),
+                Field::TotalTracks => fill_number(&mut self.total_tracks, value),
+                Field::Disc => fill_number(&mut self.disc_number, value),
+                Field::TotalDiscs => fill_number(&mut self.total_discs, value),
+                Field::Group
+                | Field::MultiDisc
+                | Field::Codec
+                | Field::Filename
+                | Field::Ext => false,
+            };
+            if filled {
+                self.inferred.push(field);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> PathPattern {
+        pattern.parse().unwrap()
+    }
+
+    #[test]
+    fn infers_fields_from_dir_and_file_names() {
+        let pattern = pattern("{artist} - {album}/{track} - {title}");
+        let path = Path::new("/music/dump/The Beatles - Abbey Road/07 - Here Comes the Sun.mp3");
+        assert_eq!(pattern.infer(path), vec![
+            (Field::Artist, "The Beatles"),
+            (Field::Album, "Abbey Road"),
+            (Field::Track, "07"),
+            (Field::Title, "Here Comes the Sun"),
+        ]);
+    }
+
+    #[test]
+    fn shortest_match_wins() {
+        let pattern = pattern("{artist} - {title}");
+        let path = Path::new("Artist - Song - Live.mp3");
+        assert_eq!(pattern.infer(path), vec![(Field::Artist, "Artist"), (Field::Title, "Song - Live")]);
+    }
+
+    #[test]
+    fn number_fields_only_match_digits() {
+        let pattern = pattern("{track} {title}");
+        assert_eq!(
+            pattern.infer(Path::new("07 Song.mp3")),
+            vec![(Field::Track, "07"), (Field::Title, "Song")],
+        );
+        assert_eq!(pattern.infer(Path::new("Intro Song.mp3")), Vec::new());
+    }
+
+    #[test]
+    fn no_fields_unless_whole_pattern_matches() {
+        let pattern = pattern("{artist} - {album}/{track} - {title}");
+        assert_eq!(pattern.infer(Path::new("Abbey Road/07 - Here Comes the Sun.mp3")), Vec::new());
+        assert_eq!(pattern.infer(Path::new("07 - Here Comes the Sun.mp3")), Vec::new());
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert_eq!("".parse::<PathPattern>(), Err(PatternError::Empty));
+        assert_eq!("{artist}//{title}".parse::<PathPattern>(), Err(PatternError::EmptyComponent));
+        assert_eq!("{track}{title}".parse::<PathPattern>(), Err(PatternError::AdjacentPlaceholders));
+        assert_eq!(
+            "{mood}".parse::<PathPattern>(),
+            Err(PatternError::UnknownField(String::from("mood"))),
+        );
+        assert_eq!(
+            "{filename}".parse::<PathPattern>(),
+            Err(PatternError::UnsupportedField(String::from("filename"))),
+        );
+        assert_eq!(
+            "{codec}".parse::<PathPattern>(),
+            Err(PatternError::UnsupportedField(String::from("codec"))),
+        );
+        assert_eq!("{title".parse::<PathPattern>(), Err(PatternError::UnterminatedPlaceholder));
+    }
+
+    #[test]
+    fn tag_values_take_precedence() {
+        let pattern = pattern("{artist} - {album}/{track} - {title}");
+        let path = Path::new("The Beatles - Abbey Road/07 - Here Comes the Sun.mp3");
+        let mut metadata = TrackMetadata {
+            artist: Some(String::from("Beatles")),
+            album: Some(String::new()),
+            ..TrackMetadata::default()
+        };
+        metadata.fill_from_path(&pattern, path);
+        assert_eq!(metadata.artist.as_deref(), Some("Beatles"));
+        assert_eq!(metadata.album.as_deref(), Some("Abbey Road"));
+        assert_eq!(metadata.track_number, Some(7));
+        assert_eq!(metadata.title.as_deref(), Some("Here Comes the Sun"));
+        assert_eq!(metadata.inferred, vec![Field::Album, Field::Track, Field::Title]);
+    }
+}
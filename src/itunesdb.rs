@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::metadata::{Codec, TrackMetadata};
+
+/// Where an iPod keeps its iTunesDB, relative to its root.
+pub const ITUNES_DB_PATH: &str = "iPod_Control/iTunes/iTunesDB";
+
+/// `mhsd` section holding the `mhlt` track list.
+const TRACK_SECTION: u32 = 1;
+/// `mhsd` section holding the `mhlp` playlist list.
+const PLAYLIST_SECTION: u32 = 2;
+/// Newer `mhsd` section holding the same playlists plus podcasts, which older databases lack.
+const PODCAST_PLAYLIST_SECTION: u32 = 3;
+
+const MHOD_TITLE: u32 = 1;
+const MHOD_LOCATION: u32 = 2;
+const MHOD_ALBUM: u32 = 3;
+const MHOD_ARTIST: u32 = 4;
+const MHOD_GENRE: u32 = 5;
+const MHOD_COMPOSER: u32 = 12;
+const MHOD_ALBUM_ARTIST: u32 = 22;
+
+#[derive(Debug)]
+pub enum ITunesDbError {
+    Read { path: PathBuf, source: std::io::Error },
+    /// The database isn't laid out as an iTunesDB, or is cut short
+    Malformed { offset: usize, message: String },
+}
+
+impl Display for ITunesDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ITunesDbError::Read { path, source } => {
+                write!(f, "Couldn't read iTunesDB {:?}: {}", path, source)
+            },
+            ITunesDbError::Malformed { offset, message } => {
+                write!(f, "iTunesDB is malformed at byte {}: {}", offset, message)
+            },
+        }
+    }
+}
+
+impl Error for ITunesDbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ITunesDbError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn malformed(offset: usize, message: impl Into<String>) -> ITunesDbError {
+    ITunesDbError::Malformed { offset, message: message.into() }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ITunesDbError> {
+    bytes.get(offset..offset + 4)
+        .map(|field| u32::from_le_bytes(field.try_into().unwrap()))
+        .ok_or_else(|| malformed(offset, "database ends in the middle of a record"))
+}
+
+/// A record in the database: a four-letter tag followed by the length of the record's own
+/// header, whose layout depends on the tag. Records that hold others are followed by them.
+#[derive(Debug, Clone, Copy)]
+struct Record<'b> {
+    bytes: &'b [u8],
+    offset: usize,
+    header_len: usize,
+}
+
+impl<'b> Record<'b> {
+    fn read(bytes: &'b [u8], offset: usize, tag: &[u8; 4]) -> Result<Record<'b>, ITunesDbError> {
+        let found = bytes.get(offset..offset + 4)
+            .ok_or_else(|| malformed(offset, "database ends before a record"))?;
+        if found != tag {
+            let message = format!(
+                "expected {} record but found {}",
+                String::from_utf8_lossy(tag),
+                String::from_utf8_lossy(found),
+            );
+            return Err(malformed(offset, message))
+        }
+        let header_len = u32_at(bytes, offset + 4)? as usize;
+        if header_len < 12 || offset + header_len > bytes.len() {
+            return Err(malformed(offset, "record header has an invalid length"))
+        }
+        Ok(Record { bytes, offset, header_len })
+    }
+
+    /// The header field at `at`, or 0 for fields past the end of the header, which older
+    /// versions of the database leave out.
+    fn field(&self, at: usize) -> u32 {
+        if at + 4 > self.header_len {
+            return 0
+        }
+        u32::from_le_bytes(self.bytes[self.offset + at..self.offset + at + 4].try_into().unwrap())
+    }
+
+    fn byte(&self, at: usize) -> u8 {
+        if at >= self.header_len {
+            return 0
+        }
+        self.bytes[self.offset + at]
+    }
+
+    fn children_offset(&self) -> usize {
+        self.offset + self.header_len
+    }
+
+    /// Offset of the next record, for records whose total length, including what they hold,
+    /// follows their header length.
+    fn end(&self) -> Result<usize, ITunesDbError> {
+        let total_len = self.field(8) as usize;
+        if total_len < self.header_len || self.offset + total_len > self.bytes.len() {
+            return Err(malformed(self.offset, "record has an invalid total length"))
+        }
+        Ok(self.offset + total_len)
+    }
+}
+
+/// The string an `mhod` record holds, which is UTF-16 unless flagged as UTF-8.
+fn mhod_string(mhod: &Record) -> Result<String, ITunesDbError> {
+    let start = mhod.children_offset();
+    let encoding = u32_at(mhod.bytes, start)?;
+    let len = u32_at(mhod.bytes, start + 4)? as usize;
+    let text = mhod.bytes.get(start + 16..start + 16 + len)
+        .ok_or_else(|| malformed(mhod.offset, "string runs past the end of the database"))?;
+    if encoding == 2 {
+        return Ok(String::from_utf8_lossy(text).to_string())
+    }
+    let units: Vec<u16> = text.chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Ok(String::from_utf16_lossy(&units))
+}
+
+/// A track in an iPod's iTunesDB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbTrack {
+    pub id: u32,
+    /// Where the track's file is on the iPod, relative to its root
+    pub location: Option<PathBuf>,
+    pub metadata: TrackMetadata,
+}
+
+/// Turns a location as the database stores it, e.g. `:iPod_Control:Music:F00:ABCD.mp3`, into
+/// a path relative to the iPod's root.
+fn device_path(location: &str) -> PathBuf {
+    location.split(':').filter(|component| !component.is_empty()).collect()
+}
+
+fn non_zero<T: Default + PartialEq>(value: T) -> Option<T> {
+    (value != T::default()).then_some(value)
+}
+
+fn parse_track(mhit: &Record) -> Result<DbTrack, ITunesDbError> {
+    let non_zero_u16 = |at| non_zero(u16::try_from(mhit.field(at)).unwrap_or(0));
+    let file_type = mhit.field(24).to_be_bytes();
+    let codec = match &file_type {
+        b"MP3 " => Some(Codec::Mp3),
+        b"M4A " | b"M4P " | b"M4B " | b"MP4 " => Some(Codec::Aac),
+        _ => None,
+    };
+    let mut metadata = TrackMetadata {
+        track_number: non_zero_u16(44),
+        total_tracks: non_zero_u16(48),
+        disc_number: non_zero_u16(92),
+        total_discs: non_zero_u16(96),
+        year: non_zero(mhit.field(52) as i32),
+        compilation: mhit.byte(30) != 0,
+        duration: non_zero(mhit.field(40)).map(|millis| Duration::from_millis(u64::from(millis))),
+        bitrate: non_zero(mhit.field(56)),
+        // Stored as a 16.16 fixed-point number
+        sample_rate: non_zero(mhit.field(60) >> 16),
+        codec,
+        file_size: non_zero(u64::from(mhit.field(36))),
+        play_count: Some(mhit.field(80)),
+        rating: non_zero(mhit.byte(31) / 20),
+        ..TrackMetadata::default()
+    };
+    metadata.date = metadata.year.map(|year| year.to_string());
+    let mut location = None;
+    let mut offset = mhit.children_offset();
+    for _ in 0..mhit.field(12) {
+        let mhod = Record::read(mhit.bytes, offset, b"mhod")?;
+        let slot = match mhod.field(12) {
+            MHOD_TITLE => &mut metadata.title,
+            MHOD_ALBUM => &mut metadata.album,
+            MHOD_ARTIST => &mut metadata.artist,
+            MHOD_GENRE => &mut metadata.genre,
+            MHOD_COMPOSER => &mut metadata.composer,
+            MHOD_ALBUM_ARTIST => &mut metadata.album_artist,
+            MHOD_LOCATION => &mut location,
+            _ => {
+                offset = mhod.end()?;
+                continue
+            },
+        };
+        *slot = non_zero(mhod_string(&mhod)?);
+        offset = mhod.end()?;
+    }
+    let location = location.map(|location| device_path(&location));
+    Ok(DbTrack { id: mhit.field(16), location, metadata })
+}
+
+/// A playlist in an iPod's iTunesDB, listing its tracks by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbPlaylist {
+    pub name: String,
+    /// Whether this is the hidden playlist of every track on the iPod
+    pub master: bool,
+    pub track_ids: Vec<u32>,
+}
+
+fn parse_playlist(mhyp: &Record) -> Result<DbPlaylist, ITunesDbError> {
+    let mut name = String::new();
+    let mut offset = mhyp.children_offset();
+    for _ in 0..mhyp.field(12) {
+        let mhod = Record::read(mhyp.bytes, offset, b"mhod")?;
+        if mhod.field(12) == MHOD_TITLE {
+            name = mhod_string(&mhod)?;
+        }
+        offset = mhod.end()?;
+    }
+    let mut track_ids = Vec::new();
+    for _ in 0..mhyp.field(16) {
+        let mhip = Record::read(mhyp.bytes, offset, b"mhip")?;
+        track_ids.push(mhip.field(24));
+        offset = mhip.end()?;
+    }
+    Ok(DbPlaylist { name, master: mhyp.byte(20) != 0, track_ids })
+}
+
+fn parse_playlists(mhsd: &Record) -> Result<Vec<DbPlaylist>, ITunesDbError> {
+    let mhlp = Record::read(mhsd.bytes, mhsd.children_offset(), b"mhlp")?;
+    let mut playlists = Vec::new();
+    let mut offset = mhlp.children_offset();
+    for _ in 0..mhlp.field(8) {
+        let mhyp = Record::read(mhsd.bytes, offset, b"mhyp")?;
+        playlists.push(parse_playlist(&mhyp)?);
+        offset = mhyp.end()?;
+    }
+    Ok(playlists)
+}
+
+/// Key that a song file is looked up by: the last two components of its path, i.e. its
+/// `Fxx` directory and scrambled name, which are unique on an iPod. Its filesystem isn't case
+/// sensitive, so neither are they.
+fn location_key(path: &Path) -> Option<String> {
+    let mut components = path.components().rev();
+    let file_name = components.next()?.as_os_str().to_string_lossy();
+    let dir = components.next()?.as_os_str().to_string_lossy();
+    Some(format!("{}/{}", dir, file_name).to_lowercase())
+}
+
+/// The tracks an iPod's iTunesDB knows of, which can be looked up by the path of their file
+/// wherever the iPod's music has been copied to.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ITunesDb {
+    pub tracks: Vec<DbTrack>,
+    pub playlists: Vec<DbPlaylist>,
+    by_id: HashMap<u32, usize>,
+    by_location: HashMap<String, usize>,
+}
+
+impl ITunesDb {
+    pub fn read(path: &Path) -> Result<ITunesDb, ITunesDbError> {
+        let bytes = read(path)
+            .map_err(|source| ITunesDbError::Read { path: path.to_path_buf(), source })?;
+        ITunesDb::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<ITunesDb, ITunesDbError> {
+        let mhbd = Record::read(bytes, 0, b"mhbd")?;
+        let mut tracks = Vec::new();
+        let mut playlists = None;
+        let mut podcast_playlists = None;
+        let mut offset = mhbd.children_offset();
+        for _ in 0..mhbd.field(20) {
+            let mhsd = Record::read(bytes, offset, b"mhsd")?;
+            match mhsd.field(12) {
+                TRACK_SECTION => {
+                    let mhlt = Record::read(bytes, mhsd.children_offset(), b"mhlt")?;
+                    let mut track_offset = mhlt.children_offset();
+                    for _ in 0..mhlt.field(8) {
+                        let mhit = Record::read(bytes, track_offset, b"mhit")?;
+                        tracks.push(parse_track(&mhit)?);
+                        track_offset = mhit.end()?;
+                    }
+                },
+                PLAYLIST_SECTION => playlists = Some(parse_playlists(&mhsd)?),
+                PODCAST_PLAYLIST_SECTION => podcast_playlists = Some(parse_playlists(&mhsd)?),
+                _ => (),
+            }
+            offset = mhsd.end()?;
+        }
+        let playlists = playlists.or(podcast_playlists).unwrap_or_default();
+        let by_id = tracks.iter()
+            .enumerate()
+            .map(|(i, track)| (track.id, i))
+            .collect();
+        let by_location = tracks.iter()
+            .enumerate()
+            .filter_map(|(i, track)| Some((location_key(track.location.as_ref()?)?, i)))
+            .collect();
+        Ok(ITunesDb { tracks, playlists, by_id, by_location })
+    }
+
+    pub fn track(&self, id: u32) -> Option<&DbTrack> {
+        self.by_id.get(&id).map(|&i| &self.tracks[i])
+    }
+
+    /// The track whose file is at `path`, going by its `Fxx` directory and file name.
+    pub fn track_for_path(&self, path: &Path) -> Option<&DbTrack> {
+        self.by_location.get(&location_key(path)?).map(|&i| &self.tracks[i])
+    }
+}
+
+/// Finds the iTunesDB of the iPod that `input_path` is, or is inside of, e.g. when it's the
+/// iPod's `iPod_Control/Music` directory.
+pub fn find_itunes_db(input_path: &Path) -> Option<PathBuf> {
+    let input_path = std::path::absolute(input_path).ok()?;
+    input_path.ancestors()
+        .map(|dir| dir.join(ITUNES_DB_PATH))
+        .find(|path| path.is_file())
+}
+
+/// Reads the iTunesDB at `path` if given, or else the one found for `input_path`, if any. A
+/// database that was only found rather than asked for is skipped if it can't be read.
+pub fn load_itunes_db(
+    path: Option<&str>,
+    input_path: &Path,
+) -> Result<Option<ITunesDb>, ITunesDbError> {
+    if let Some(path) = path {
+        return ITunesDb::read(Path::new(path)).map(Some)
+    }
+    let Some(path) = find_itunes_db(input_path) else {
+        return Ok(None)
+    };
+    match ITunesDb::read(&path) {
+        Ok(db) => {
+            info!("Reading metadata missing from tags from iTunesDB {:?}", path);
+            Ok(Some(db))
+        },
+        Err(e) => {
+            warn!("Skipping iTunesDB found at {:?}: {}", path, e);
+            Ok(None)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/iTunesDB");
+
+    #[test]
+    fn tracks_read_from_fixture() {
+        let db = ITunesDb::parse(FIXTURE).unwrap();
+        assert_eq!(db.tracks.len(), 3);
+        let track = &db.tracks[0];
+        assert_eq!(track.id, 101);
+        assert_eq!(track.location, Some(PathBuf::from("iPod_Control/Music/F00/ABCD.mp3")));
+        let metadata = &track.metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Here Comes the Sun"));
+        assert_eq!(metadata.artist.as_deref(), Some("The Beatles"));
+        assert_eq!(metadata.album_artist.as_deref(), Some("The Beatles"));
+        assert_eq!(metadata.album.as_deref(), Some("Abbey Road"));
+        assert_eq!(metadata.composer.as_deref(), Some("George Harrison"));
+        assert_eq!((metadata.track_number, metadata.total_tracks), (Some(7), Some(17)));
+        assert_eq!((metadata.disc_number, metadata.total_discs), (Some(1), Some(1)));
+        assert_eq!(metadata.year, Some(1969));
+        assert_eq!(metadata.duration, Some(Duration::from_secs(185)));
+        assert_eq!(metadata.sample_rate, Some(44100));
+        assert_eq!(metadata.codec, Some(Codec::Mp3));
+        assert_eq!((metadata.play_count, metadata.rating), (Some(12), Some(4)));
+        // UTF-8 strings as well as UTF-16 ones
+        let metadata = &db.tracks[2].metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Café del Mar"));
+        assert_eq!(metadata.album.as_deref(), Some("Café del Mar Vol. 1"));
+        assert!(metadata.compilation);
+        assert_eq!(metadata.track_number, None);
+    }
+
+    #[test]
+    fn playlists_read_from_fixture() {
+        let db = ITunesDb::parse(FIXTURE).unwrap();
+        let names: Vec<(&str, bool)> = db.playlists.iter()
+            .map(|playlist| (playlist.name.as_str(), playlist.master))
+            .collect();
+        assert_eq!(names, vec![("iPod", true), ("Road Trip", false)]);
+        assert_eq!(db.playlists[1].track_ids, vec![103, 101]);
+        let title = db.track(103).and_then(|track| track.metadata.title.as_deref());
+        assert_eq!(title, Some("Café del Mar"));
+        assert_eq!(db.track(999), None);
+    }
+
+    #[test]
+    fn tracks_found_by_path_wherever_copied() {
+        let db = ITunesDb::parse(FIXTURE).unwrap();
+        let track = db.track_for_path(Path::new("/media/ipod/iPod_Control/Music/F11/EFGH.m4a"));
+        assert_eq!(track.map(|track| track.id), Some(102));
+        let track = db.track_for_path(Path::new("/home/me/dump/f11/efgh.M4A"));
+        assert_eq!(track.map(|track| track.id), Some(102));
+        assert_eq!(db.track_for_path(Path::new("/home/me/dump/F00/EFGH.m4a")), None);
+    }
+
+    #[test]
+    fn malformed_databases_rejected() {
+        let err = ITunesDb::parse(b"not a database").unwrap_err();
+        assert_eq!(err.to_string(), "iTunesDB is malformed at byte 0: expected mhbd record but found not ");
+        let truncated = &FIXTURE[..FIXTURE.len() / 2];
+        assert!(ITunesDb::parse(truncated).is_err());
+    }
+}
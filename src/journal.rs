@@ -0,0 +1,438 @@
+use std::fmt::Display;
+use std::fs::{
+    File, Metadata, OpenOptions, create_dir_all, read_link, read_to_string, remove_dir, remove_file,
+};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MoverError;
+use crate::operation::Operation;
+
+/// Journals are hidden files so that organising into the same output directory again doesn't
+/// pick them up.
+const JOURNAL_PREFIX: &str = ".mp3_mover-journal-";
+const JOURNAL_EXTENSION: &str = "jsonl";
+
+/// A single change made to the filesystem during a run, stored as one line of JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    CreateDir {
+        path: PathBuf,
+    },
+    PlaceFile {
+        operation: Operation,
+        source: PathBuf,
+        destination: PathBuf,
+        /// Whether a file that was already at the destination was replaced
+        replaced_existing: bool,
+    },
+    /// A file written by the run itself, such as a playlist
+    WriteFile {
+        path: PathBuf,
+        replaced_existing: bool,
+    },
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalEntry::CreateDir { path } => write!(f, "create dir {}", path.display()),
+            JournalEntry::PlaceFile { operation, source, destination, .. } => write!(
+                f,
+                "{} {} -> {}",
+                operation.verb(),
+                source.display(),
+                destination.display(),
+            ),
+            JournalEntry::WriteFile { path, .. } => write!(f, "write {}", path.display()),
+        }
+    }
+}
+
+/// Append-only record of the changes made during a run. Each entry is written and synced to disk
+/// as soon as the change has been made, so that if the program is killed part way through, at
+/// most the change it was in the middle of is missing from the journal.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Creates a new journal in `dir`, named after the current time so that the journals of
+    /// earlier runs are kept.
+    pub fn create(dir: &Path) -> std::io::Result<Journal> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let mut n = 1;
+        loop {
+            let name = match n {
+                1 => format!("{}{}.{}", JOURNAL_PREFIX, timestamp, JOURNAL_EXTENSION),
+                _ => format!("{}{}-{}.{}", JOURNAL_PREFIX, timestamp, n, JOURNAL_EXTENSION),
+            };
+            let path = std::path::absolute(dir.join(name))?;
+            match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    info!("Recording changes in journal {:?}", path);
+                    return Ok(Journal { path, file })
+                },
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, entry: &JournalEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /// Flushes the journal to disk.
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+pub fn read_journal(path: &Path) -> std::io::Result<Vec<JournalEntry>> {
+    let contents = read_to_string(path)?;
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(line).map_err(|e| Error::new(
+            ErrorKind::InvalidData,
+            format!("Line {} of journal {:?} is invalid: {}", i + 1, path, e),
+        ))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoFailure {
+    pub entry: JournalEntry,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoReport {
+    pub reverted: Vec<JournalEntry>,
+    pub failures: Vec<UndoFailure>,
+}
+
+impl Display for UndoReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Reverted ({}):", self.reverted.len())?;
+        for entry in self.reverted.iter() {
+            writeln!(f, "  {}", entry)?;
+        }
+        writeln!(f, "Couldn't revert ({}):", self.failures.len())?;
+        for failure in self.failures.iter() {
+            writeln!(f, "  {} ({})", failure.entry, failure.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reverts the changes recorded in the journal at `path`, newest first: song files are moved
+/// back to where they came from (or removed, if they were copies or links), written files are
+/// removed unless they replaced another, and created directories are removed if they're empty.
+pub fn undo(path: &Path) -> Result<UndoReport, MoverError> {
+    let entries = read_journal(path)
+        .map_err(|source| MoverError::Filesystem { path: path.to_path_buf(), source })?;
+    let mut report = UndoReport::default();
+    for entry in entries.into_iter().rev() {
+        let (res, replaced_existing) = match &entry {
+            JournalEntry::CreateDir { path } => (undo_create_dir(path), false),
+            JournalEntry::PlaceFile { operation, source, destination, replaced_existing } => {
+                (undo_place_file(*operation, source, destination), *replaced_existing)
+            },
+            JournalEntry::WriteFile { replaced_existing: true, .. } => {
+                // Removing the file would lose what was there before it as well
+                let reason = "the file it replaced can't be restored, so it was left in place";
+                (Err(String::from(reason)), false)
+            },
+            JournalEntry::WriteFile { path, .. } => (undo_write_file(path), false),
+        };
+        if res.is_ok() && replaced_existing {
+            report.failures.push(UndoFailure {
+                entry: entry.clone(),
+                reason: String::from("the file it replaced can't be restored"),
+            });
+        }
+        match res {
+            Ok(()) => report.reverted.push(entry),
+            Err(reason) => {
+                warn!("Couldn't revert {}: {}", entry, reason);
+                report.failures.push(UndoFailure { entry, reason });
+            },
+        }
+    }
+    Ok(report)
+}
+
+fn undo_create_dir(path: &Path) -> Result<(), String> {
+    match remove_dir(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => {
+            Err(String::from("directory isn't empty"))
+        },
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn undo_write_file(path: &Path) -> Result<(), String> {
+    match remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn undo_place_file(operation: Operation, source: &Path, destination: &Path) -> Result<(), String> {
+    let destination_exists = destination.symlink_metadata().is_ok();
+    if operation != Operation::Move {
+        // The original was left in place, so only the copy or link needs removing, as long as
+        // nothing else has been put at the destination since
+        if destination_exists && !is_placed_file(operation, source, destination) {
+            return Err(String::from("a different file is now at the destination"))
+        }
+        return match remove_file(destination) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+    if !destination_exists {
+        return Err(String::from("song file is no longer at its destination"))
+    }
+    if source.symlink_metadata().is_ok() {
+        return Err(String::from("another file is now at the song file's original path"))
+    }
+    if let Some(dir) = source.parent() {
+        create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    Operation::Move.perform(destination, source).map_err(|e| e.to_string())
+}
+
+/// Whether the file at `destination` still looks like the copy or link of `source` that was
+/// placed there.
+fn is_placed_file(operation: Operation, source: &Path, destination: &Path) -> bool {
+    if operation == Operation::Symlink {
+        let target = source.canonicalize().unwrap_or_else(|_| source.to_path_buf());
+        return read_link(destination).is_ok_and(|link| link == target)
+    }
+    let (Ok(source), Ok(destination)) = (source.metadata(), destination.symlink_metadata()) else {
+        return false
+    };
+    if operation == Operation::Hardlink {
+        return is_same_file(&source, &destination)
+    }
+    destination.is_file() && source.len() == destination.len()
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(a: &Metadata, b: &Metadata) -> bool {
+    b.is_file() && a.len() == b.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{hard_link, read, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn entries_round_trip_through_journal() {
+        let dir = tempdir().unwrap();
+        let entries = [
+            JournalEntry::CreateDir { path: PathBuf::from("/out/Artist") },
+            JournalEntry::PlaceFile {
+                operation: Operation::Copy,
+                source: PathBuf::from("/in/ABCD.mp3"),
+                destination: PathBuf::from("/out/Artist/Song.mp3"),
+                replaced_existing: false,
+            },
+        ];
+        let mut journal = Journal::create(dir.as_ref()).unwrap();
+        for entry in entries.iter() {
+            journal.record(entry).unwrap();
+        }
+        journal.sync().unwrap();
+        assert_eq!(journal.path().parent().unwrap(), dir.as_ref());
+        assert_eq!(read_journal(journal.path()).unwrap(), entries.to_vec());
+        let second_journal = Journal::create(dir.as_ref()).unwrap();
+        assert_ne!(second_journal.path(), journal.path());
+    }
+
+    #[test]
+    fn invalid_journal_line() {
+        let dir = tempdir().unwrap();
+        let path = dir.as_ref().join("journal.jsonl");
+        write(&path, "{\"op\":\"create_dir\",\"path\":\"/out\"}\n{\"op\":\"teleport\"}\n").unwrap();
+        let res = read_journal(&path);
+        assert!(res.is_err_and(|e| e.to_string().starts_with("Line 2")));
+    }
+
+    #[test]
+    fn undo_moves_files_back_and_removes_empty_dirs() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("F00").join("ABCD.mp3");
+        let artist_dir = outdir.as_ref().join("Artist");
+        let destination = artist_dir.join("Song.mp3");
+        create_dir_all(&artist_dir).unwrap();
+        write(&destination, b"song data").unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        journal.record(&JournalEntry::CreateDir { path: artist_dir.clone() }).unwrap();
+        journal.record(&JournalEntry::PlaceFile {
+            operation: Operation::Move,
+            source: source.clone(),
+            destination: destination.clone(),
+            replaced_existing: false,
+        }).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert_eq!(report.reverted.len(), 2);
+        assert!(report.failures.is_empty());
+        assert_eq!(read(&source).unwrap(), b"song data");
+        assert!(!artist_dir.try_exists().unwrap());
+    }
+
+    #[test]
+    fn undo_reports_what_cant_be_reverted() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let artist_dir = outdir.as_ref().join("Artist");
+        create_dir_all(&artist_dir).unwrap();
+        write(artist_dir.join("Other.mp3"), b"other data").unwrap();
+        let missing_move = JournalEntry::PlaceFile {
+            operation: Operation::Move,
+            source: indir.as_ref().join("ABCD.mp3"),
+            destination: artist_dir.join("Song.mp3"),
+            replaced_existing: false,
+        };
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        journal.record(&JournalEntry::CreateDir { path: artist_dir.clone() }).unwrap();
+        journal.record(&missing_move).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert!(report.reverted.is_empty());
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.failures[0].entry, missing_move);
+        assert_eq!(report.failures[1].reason, "directory isn't empty");
+        assert!(artist_dir.join("Other.mp3").try_exists().unwrap());
+    }
+
+    #[test]
+    fn undo_removes_copies() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("ABCD.mp3");
+        let destination = outdir.as_ref().join("Song.mp3");
+        write(&source, b"song data").unwrap();
+        write(&destination, b"song data").unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        journal.record(&JournalEntry::PlaceFile {
+            operation: Operation::Copy,
+            source: source.clone(),
+            destination: destination.clone(),
+            replaced_existing: false,
+        }).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert!(report.failures.is_empty());
+        assert!(!destination.try_exists().unwrap());
+        assert_eq!(read(&source).unwrap(), b"song data");
+    }
+
+    #[test]
+    fn undo_leaves_rewritten_files() {
+        let outdir = tempdir().unwrap();
+        let playlist = outdir.as_ref().join("Road Trip.m3u8");
+        write(&playlist, b"#EXTM3U\n").unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        let entry = JournalEntry::WriteFile { path: playlist.clone(), replaced_existing: true };
+        journal.record(&entry).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].entry, entry);
+        assert!(playlist.try_exists().unwrap());
+    }
+
+    #[test]
+    fn undo_removes_written_files() {
+        let outdir = tempdir().unwrap();
+        let playlist = outdir.as_ref().join("Road Trip.m3u8");
+        write(&playlist, b"#EXTM3U\n").unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        let entry = JournalEntry::WriteFile { path: playlist.clone(), replaced_existing: false };
+        journal.record(&entry).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert_eq!(report.reverted, vec![entry]);
+        assert!(!playlist.try_exists().unwrap());
+    }
+
+    #[test]
+    fn undo_leaves_files_that_replaced_the_placed_one() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("ABCD.mp3");
+        let copy_destination = outdir.as_ref().join("Song.mp3");
+        let link_destination = outdir.as_ref().join("Other Song.mp3");
+        write(&source, b"song data").unwrap();
+        // Both the copy and the hard link have since been replaced by other files
+        write(&copy_destination, b"a different song").unwrap();
+        write(&link_destination, b"song data").unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        journal.record(&JournalEntry::PlaceFile {
+            operation: Operation::Copy,
+            source: source.clone(),
+            destination: copy_destination.clone(),
+            replaced_existing: false,
+        }).unwrap();
+        journal.record(&JournalEntry::PlaceFile {
+            operation: Operation::Hardlink,
+            source: source.clone(),
+            destination: link_destination.clone(),
+            replaced_existing: false,
+        }).unwrap();
+
+        let report = undo(journal.path()).unwrap();
+        assert!(report.reverted.is_empty());
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(read(&copy_destination).unwrap(), b"a different song");
+        assert!(link_destination.try_exists().unwrap());
+
+        // A hard link that's still in place is removed
+        remove_file(&link_destination).unwrap();
+        hard_link(&source, &link_destination).unwrap();
+        let report = undo(journal.path()).unwrap();
+        assert_eq!(report.reverted.len(), 1);
+        assert!(!link_destination.try_exists().unwrap());
+        assert_eq!(read(&source).unwrap(), b"song data");
+    }
+}
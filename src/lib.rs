@@ -1,25 +1,55 @@
+pub mod album;
+pub mod cli;
+pub mod collision;
 pub mod config;
-
+pub mod config_file;
+pub mod dedup;
+pub mod discover;
+pub mod error;
+pub mod infer;
+pub mod itunesdb;
+pub mod journal;
+pub mod metadata;
+mod mpeg;
+pub mod operation;
+pub mod pipeline;
+pub mod plan;
+pub mod playlist;
+pub mod report;
+pub mod sanitize;
+pub mod scan;
+pub mod tags;
+pub mod template;
+pub mod verify;
+pub mod watch;
+
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
-use std::fs::{create_dir_all, rename, read_dir, DirEntry};
+use std::fs::{create_dir_all, write};
+use std::io::{Error, ErrorKind};
 
-use audiotags::{AudioTag,FlacTag,Id3v2Tag,Mp4Tag};
-use glob::{Paths, glob};
 use log::{info, warn};
 
+use collision::{CollisionPolicy, numbered_destination};
 use config::Config;
+use dedup::find_duplicates;
+use discover::WalkOptions;
+use error::MoverError;
+use journal::{Journal, JournalEntry};
+use operation::Operation;
+use pipeline::{Naming, Planner, discover, field_names, read_song_files};
+use plan::{Plan, PlannedMove};
+use playlist::{
+    Playlist, PlaylistPaths, final_paths, give_unique_paths, ipod_playlists, is_playlist,
+    rewritten_playlist,
+};
+use report::{PlaylistOutcome, RunReport};
+use scan::Scan;
 
 #[derive(Debug)]
-struct SongInfo<'a> {
-    artist: &'a str,
-    album: &'a str,
-    title: Option<&'a str>,
-}
-
-#[derive(Debug)]
-struct MissingSongInfo {
-    missing_field: String,
+pub struct MissingSongInfo {
+    pub missing_field: String,
 }
 
 impl Display for MissingSongInfo {
@@ -28,163 +58,334 @@ impl Display for MissingSongInfo {
     }
 }
 
-fn check_tag_info(tag: &impl AudioTag) -> Result<SongInfo, MissingSongInfo> {
-    let title = tag.title();
-
-    let artist = match tag.artist() {
-        Some(val) => val,
-        None => {
-            return Err(
-                MissingSongInfo{ missing_field: String::from("artist") }
-            )
+/// Creates `dir` along with any missing parents, returning the dirs that were created,
+/// outermost first.
+fn create_song_dir(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    info!("Creating directory {:?}", dir);
+    let mut missing_dirs = Vec::new();
+    for ancestor in dir.ancestors() {
+        if ancestor.as_os_str().is_empty() || ancestor.try_exists()? {
+            break
         }
-    };
+        missing_dirs.push(std::path::absolute(ancestor)?);
+    }
+    create_dir_all(dir)?;
+    missing_dirs.reverse();
+    Ok(missing_dirs)
+}
 
-    let album = match tag.album_title() {
-        Some(val) => val,
-        None => {
-            return Err(
-                MissingSongInfo { missing_field: String::from("album") }
-            )
-        }
-    };
 
-    Ok(SongInfo {
-        artist: artist,
-        album: album,
-        title: title
-    })
+fn move_song_file(planned_move: &PlannedMove, operation: Operation) -> std::io::Result<()> {
+    // The plan only checked the destination was free when it was built, so check again rather
+    // than letting rename silently replace a file that has appeared since
+    if !planned_move.overwrite && planned_move.destination.try_exists()? {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Destination {:?} already exists", planned_move.destination),
+        ))
+    }
+    operation.perform(&planned_move.source, &planned_move.destination)
 }
 
+pub fn run(config: Config) -> Result<RunReport, MoverError> {
+    let file_paths = discover(Path::new(&config.input_path), &config.walk_options)?;
+    organize(&config, file_paths, true)
+}
 
-fn create_song_dir(outdir: &impl AsRef<Path>,  artist: &str, album: &str) -> std::io::Result<()> {
-    let mut outdir_path = outdir.as_ref().to_path_buf();
-    outdir_path.push(artist);
-    outdir_path.push(album);
-    create_dir_all(outdir_path.clone())
+/// Like `run`, but organises only the given files rather than everything in the input
+/// directory. Playlists aren't exported, since they'd only list the given files.
+pub fn run_files(config: &Config, file_paths: Vec<PathBuf>) -> Result<RunReport, MoverError> {
+    organize(config, file_paths, false)
 }
 
+fn organize(
+    config: &Config,
+    mut file_paths: Vec<PathBuf>,
+    export_playlists: bool,
+) -> Result<RunReport, MoverError> {
+    let playlist_paths = config.playlist_paths.filter(|_| export_playlists);
+    let mut input_playlists = Vec::new();
+    if playlist_paths.is_some() {
+        (input_playlists, file_paths) = file_paths.into_iter().partition(|path| is_playlist(path));
+    }
+    let plan = build_plan_for_files(config, file_paths)?;
+    for collision in plan.collisions.iter() {
+        warn!("Collision: {}", collision);
+    }
+    let mut errors: Vec<MoverError> = plan.skipped.iter()
+        .filter_map(|skipped_file| skipped_file.error())
+        .collect();
+    if config.dry_run {
+        info!("Dry run requested, not touching the filesystem");
+        let playlists = match playlist_paths {
+            Some(_) => playlists_to_write(config, &plan, &[], &input_playlists, &mut errors),
+            None => Vec::new(),
+        };
+        let mut report = RunReport::new(plan, errors, true, None);
+        report.playlists = playlists.iter()
+            .map(|playlist| playlist_outcome(playlist, None))
+            .collect();
+        return Ok(report);
+    }
+    if plan.dirs.is_empty() && plan.moves.is_empty() && playlist_paths.is_none() {
+        return Ok(RunReport::new(plan, errors, false, None));
+    }
+    let outdir = Path::new(&config.output_path);
+    let mut journal = Journal::create(outdir).map_err(|source| MoverError::Filesystem {
+        path: outdir.to_path_buf(),
+        source,
+    })?;
+    let res = execute_plan(&plan, &mut journal).and_then(|mut run_errors| {
+        let Some(paths) = playlist_paths else {
+            return Ok((run_errors, Vec::new()))
+        };
+        let failed: Vec<PathBuf> = run_errors.iter()
+            .filter_map(|e| match e {
+                MoverError::Filesystem { path, .. } => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        let failed: Vec<&Path> = failed.iter().map(PathBuf::as_path).collect();
+        let playlists =
+            playlists_to_write(config, &plan, &failed, &input_playlists, &mut run_errors);
+        let policy = config.collision_policy;
+        let outcomes =
+            write_playlists(&playlists, paths, policy, &mut journal, &mut run_errors)?;
+        Ok((run_errors, outcomes))
+    });
+    journal.sync().map_err(|source| MoverError::Filesystem {
+        path: journal.path().to_path_buf(),
+        source,
+    })?;
+    let (run_errors, playlists) = res?;
+    errors.extend(run_errors);
+    let mut report = RunReport::new(plan, errors, false, Some(journal.path().to_path_buf()));
+    report.playlists = playlists;
+    Ok(report)
+}
 
-fn move_song_file(filepath: &PathBuf, song_info: &SongInfo, outdir: &PathBuf) -> std::io::Result<()> {
-    let mut outdir_path = outdir.clone();
-    let mut filename = String::new();
-    outdir_path.push(song_info.artist);
-    outdir_path.push(song_info.album);
-    match song_info.title {
-        None => {
-            let original_filename = filepath.as_path().file_name()
-                .expect("Audio file path from globbing shouldn't return a directory");
-            let lossy_filename = original_filename.to_string_lossy();
-            filename.push_str(&lossy_filename);
-        },
-        Some(title) => {
-            filename.push_str(title);
-            filename.push_str(".mp3");
+pub fn build_plan(config: &Config) -> Result<Plan, MoverError> {
+    let file_paths = discover(Path::new(&config.input_path), &config.walk_options)?;
+    build_plan_for_files(config, file_paths)
+}
+
+fn build_plan_for_files(config: &Config, file_paths: Vec<PathBuf>) -> Result<Plan, MoverError> {
+    let mut planner = Planner::new(config);
+    let song_files = read_song_files(file_paths, config.walk_options.jobs);
+    let named_songs = Naming::from_config(config).name_song_files(song_files);
+    let mut duplicates = match config.duplicate_policy {
+        Some(duplicate_policy) => find_duplicates(&named_songs, duplicate_policy),
+        None => HashMap::new(),
+    };
+    for named_song in named_songs {
+        match duplicates.remove(&named_song.path) {
+            Some(kept) => planner.add_duplicate(named_song.path, kept),
+            None => planner.add(named_song),
         }
     }
-    outdir_path.push(filename);
-    info!("Renaming {:?} to {:?}", filepath, outdir_path);
-    rename(filepath, outdir_path)
+    planner.finish()
 }
 
-fn find_song_files(dir: &PathBuf) -> Option<Paths> {
-    let pattern = "*.mp3";
-    let full_pattern = dir.join(pattern);
-    let glob_str = full_pattern.to_str()?;
-    Some(glob(glob_str)
-        .expect("Glob pattern is hardcoded so should not be an invalid pattern"))
+/// Carries out the plan, recording each change in the journal as it's made. A song file that
+/// can't be placed doesn't stop the rest, and the errors for such files are returned, but
+/// failing to write to the journal ends the run since the changes could no longer be undone.
+pub fn execute_plan(plan: &Plan, journal: &mut Journal) -> Result<Vec<MoverError>, MoverError> {
+    let mut errors = Vec::new();
+    for dir in plan.dirs.iter() {
+        match create_song_dir(dir) {
+            Ok(created_dirs) => {
+                for path in created_dirs {
+                    record(journal, &JournalEntry::CreateDir { path })?;
+                }
+            },
+            Err(source) => {
+                warn!("Couldn't create directory {:?}: {}", dir, source);
+                errors.push(MoverError::Filesystem { path: dir.clone(), source });
+            },
+        }
+    }
+    for planned_move in plan.moves.iter() {
+        let replaced_existing = planned_move.destination.try_exists().unwrap_or(false);
+        match move_song_file(planned_move, plan.operation) {
+            Ok(()) => {
+                let entry = JournalEntry::PlaceFile {
+                    operation: plan.operation,
+                    source: absolute_path(&planned_move.source, journal)?,
+                    destination: absolute_path(&planned_move.destination, journal)?,
+                    replaced_existing,
+                };
+                record(journal, &entry)?;
+            },
+            Err(source) => {
+                warn!("Couldn't {} {:?}: {}", plan.operation.verb(), planned_move.source, source);
+                errors.push(MoverError::Filesystem { path: planned_move.source.clone(), source });
+            },
+        }
+    }
+    Ok(errors)
 }
 
-pub fn run(config: Config) -> std::io::Result<()> {
-    let contents = read_dir(config.input_path)?;
-    for child in contents {
-        let elem = match child {
-            Ok(val) => val,
-            Err(e) => {
-                warn!("During input dir contents reading encountered error {:?}; moving on", e);
-                continue;
-            }
-        };
-        if !elem.path().is_dir() {
-            continue;
+/// The playlists from the iTunesDB, if any, and those found in the input directory, with
+/// entries pointing at where their song files end up once the plan has been carried out,
+/// apart from the `failed` ones, each with a path of its own. Errors reading the input
+/// playlists are added to `errors`.
+fn playlists_to_write(
+    config: &Config,
+    plan: &Plan,
+    failed: &[&Path],
+    input_playlists: &[PathBuf],
+    errors: &mut Vec<MoverError>,
+) -> Vec<Playlist> {
+    let final_paths = final_paths(plan, failed);
+    let (input_dir, output_dir) = (Path::new(&config.input_path), Path::new(&config.output_path));
+    let mut playlists = match &config.itunes_db {
+        Some(itunes_db) => ipod_playlists(itunes_db, &final_paths, output_dir, &config.sanitizer),
+        None => Vec::new(),
+    };
+    for path in input_playlists {
+        match rewritten_playlist(path, input_dir, output_dir, &final_paths) {
+            Ok(playlist) => playlists.push(playlist),
+            Err(source) => {
+                warn!("Couldn't read playlist {:?}: {}", path, source);
+                errors.push(MoverError::Filesystem { path: path.clone(), source });
+            },
         }
-        check_song_files(&elem, Path::new(&config.output_path))?;
     }
-    Ok(())
+    give_unique_paths(&mut playlists);
+    playlists
 }
 
+fn playlist_outcome(playlist: &Playlist, error: Option<String>) -> PlaylistOutcome {
+    PlaylistOutcome {
+        path: playlist.path.clone(),
+        entries: playlist.entries.len(),
+        unresolved: playlist.unresolved.clone(),
+        error,
+        skipped: false,
+    }
+}
 
-fn check_song_files(dir_entry: &DirEntry, outdir: &Path) -> std::io::Result<()> {
-    let song_file_paths = match find_song_files(&dir_entry.path()) {
-        Some(val) => val,
-        None => {
-            warn!(
-                "Found invalid unicode in glob string for dir: {:?}; ignoring an moving on",
-                dir_entry.path()
-            );
-            return Ok(())
-        }
-    };
+/// Where a playlist bound for `path` is written when a file is already there, following the
+/// collision policy as song files do, or `None` if it's skipped. Keeping the larger or higher
+/// bitrate file means nothing for playlists, so under those policies they're renamed instead.
+fn playlist_destination(path: &Path, policy: CollisionPolicy) -> std::io::Result<Option<PathBuf>> {
+    if !path.try_exists()? {
+        return Ok(Some(path.to_path_buf()))
+    }
+    match policy {
+        CollisionPolicy::Overwrite => Ok(Some(path.to_path_buf())),
+        CollisionPolicy::Skip => Ok(None),
+        CollisionPolicy::Fail => {
+            Err(Error::new(ErrorKind::AlreadyExists, "a file is already at the playlist's path"))
+        },
+        CollisionPolicy::Rename
+        | CollisionPolicy::KeepLarger
+        | CollisionPolicy::KeepHigherBitrate => {
+            let renamed = (2..)
+                .map(|n| numbered_destination(path, n))
+                .find(|candidate| !candidate.exists())
+                .expect("There are always more numbered names to try");
+            Ok(Some(renamed))
+        },
+    }
+}
 
-    for glob_res in song_file_paths {
-        let path = match glob_res {
-            Ok(val) => val,
-            Err(e) => {
-                warn!("Found a glob error {:?}, ignoring and moving onto next match", e);
-                continue;
-            }
-        };
-        let file_extension = match path.extension() {
-            Some(val) => val.to_str().expect("Expected valid unicode in file extension"),
-            None => {
-                info!(
-                    "The file {:?} has no file extension so the appropriate tag cannot be inferred, skipping",
-                    path,
-                );
+/// Writes the playlists, recording each in the journal along with any directories created
+/// for them, and resolving clashes with files already at their paths according to `policy`.
+/// Like song files, a playlist that can't be written doesn't stop the rest, and its error is
+/// added to `errors`.
+pub(crate) fn write_playlists(
+    playlists: &[Playlist],
+    paths: PlaylistPaths,
+    policy: CollisionPolicy,
+    journal: &mut Journal,
+    errors: &mut Vec<MoverError>,
+) -> Result<Vec<PlaylistOutcome>, MoverError> {
+    let mut outcomes = Vec::new();
+    for playlist in playlists {
+        let destination = match playlist_destination(&playlist.path, policy) {
+            Ok(Some(destination)) => destination,
+            Ok(None) => {
+                info!("Skipping playlist {:?}, a file is already there", playlist.path);
+                let outcome = PlaylistOutcome { skipped: true, ..playlist_outcome(playlist, None) };
+                outcomes.push(outcome);
                 continue
-            }
-        };
-        match file_extension {
-            "flac" => {
-                check_song_file_tag_info(
-                    &FlacTag::read_from_path(path.clone()).unwrap(), &path, outdir
-                )?
-            },
-            "mp3" => {
-                check_song_file_tag_info(
-                    &Id3v2Tag::read_from_path(path.clone()).unwrap(), &path, outdir
-                )?
             },
-            "mp4" => {
-                check_song_file_tag_info(
-                    &Mp4Tag::read_from_path(path.clone()).unwrap(), &path, outdir
-                )?
-            },
-            _ => {
-                info!(
-                    "Unsupported file extension encountered for file {:?}, skipping",
-                    path,
-                );
+            Err(source) => {
+                warn!("Couldn't write playlist {:?}: {}", playlist.path, source);
+                outcomes.push(playlist_outcome(playlist, Some(source.to_string())));
+                errors.push(MoverError::Filesystem { path: playlist.path.clone(), source });
                 continue
-            }
+            },
         };
+        // Entries are written relative to where the playlist actually ends up
+        let playlist = &Playlist { path: destination, ..playlist.clone() };
+        info!("Writing playlist {:?}", playlist.path);
+        let dir = playlist.path.parent().unwrap_or(Path::new(""));
+        let created_dirs = create_song_dir(dir);
+        for path in created_dirs.iter().flatten() {
+            record(journal, &JournalEntry::CreateDir { path: path.clone() })?;
+        }
+        let replaced_existing = playlist.path.try_exists().unwrap_or(false);
+        let res = created_dirs
+            .and_then(|_| playlist.contents(paths))
+            .and_then(|contents| write(&playlist.path, contents));
+        match res {
+            Ok(()) => {
+                let path = absolute_path(&playlist.path, journal)?;
+                record(journal, &JournalEntry::WriteFile { path, replaced_existing })?;
+                outcomes.push(playlist_outcome(playlist, None));
+            },
+            Err(source) => {
+                warn!("Couldn't write playlist {:?}: {}", playlist.path, source);
+                outcomes.push(playlist_outcome(playlist, Some(source.to_string())));
+                errors.push(MoverError::Filesystem { path: playlist.path.clone(), source });
+            },
+        }
     }
-    Ok(())
+    Ok(outcomes)
 }
 
+fn record(journal: &mut Journal, entry: &JournalEntry) -> Result<(), MoverError> {
+    journal.record(entry).map_err(|source| MoverError::Filesystem {
+        path: journal.path().to_path_buf(),
+        source,
+    })
+}
 
-fn check_song_file_tag_info(tag: &impl AudioTag, file_path: &PathBuf, outdir: &Path) -> std::io::Result<()> {
-    let tag_info = check_tag_info(tag);
-    match tag_info {
-        Ok(song_info) => {
-            create_song_dir(&outdir, song_info.artist, song_info.album)?;
-            move_song_file(&file_path, &song_info, &outdir.to_path_buf())?;
-        },
-        Err(e) => {
-            info!("Song file {:?} has missing field {}", file_path, e.missing_field);
+fn absolute_path(path: &Path, journal: &Journal) -> Result<PathBuf, MoverError> {
+    std::path::absolute(path).map_err(|source| MoverError::Filesystem {
+        path: journal.path().to_path_buf(),
+        source,
+    })
+}
+
+pub fn scan(
+    input_path: &Path,
+    walk_options: &WalkOptions,
+    naming: Naming,
+) -> Result<Scan, MoverError> {
+    let mut scan = Scan::new();
+    let file_paths = discover(input_path, walk_options)?;
+    let song_files = read_song_files(file_paths, walk_options.jobs);
+    for named_song in naming.name_song_files(song_files) {
+        let file_path = named_song.path;
+        match named_song.destination {
+            Ok(relative_path) => {
+                let metadata = named_song.metadata.unwrap_or_default();
+                scan.add_song(
+                    file_path,
+                    metadata.artist.as_deref(),
+                    metadata.album.as_deref(),
+                    metadata.title.as_deref(),
+                    relative_path,
+                    field_names(&metadata.inferred),
+                )
+            },
+            Err(reason) => scan.add_skipped(file_path, reason),
         }
     }
-    Ok(())
+    Ok(scan)
 }
 
 
@@ -192,13 +393,73 @@ fn check_song_file_tag_info(tag: &impl AudioTag, file_path: &PathBuf, outdir: &P
 mod tests {
     use std::fs::File;
 
-    use glob::GlobResult;
     use id3::{Tag,TagLike};
     use audiotags::{AudioTagEdit,Id3v2Tag};
     use tempfile::tempdir;
 
+    use crate::album::DEFAULT_VARIOUS_ARTISTS;
+    use crate::collision::CollisionPolicy;
+    use crate::discover::find_song_files;
+    use crate::infer::PathPattern;
+    use crate::plan::SkipReason;
+    use crate::sanitize::Sanitizer;
+    use crate::metadata::TrackMetadata;
+    use crate::tags::id3_metadata;
+    use crate::template::Field;
+    use crate::template::PathTemplate;
+
     use super::*;
 
+    fn song_tag(tag: Id3v2Tag) -> TrackMetadata {
+        id3_metadata(tag.into())
+    }
+
+    fn check_tag_info(
+        metadata: &TrackMetadata,
+        file_path: &Path,
+        template: &PathTemplate,
+        path_pattern: Option<&PathPattern>,
+    ) -> Result<(TrackMetadata, PathBuf), SkipReason> {
+        let sanitizer = Sanitizer::default();
+        let naming = Naming {
+            template,
+            sanitizer: &sanitizer,
+            path_pattern,
+            various_artists: DEFAULT_VARIOUS_ARTISTS,
+            itunes_db: None,
+        };
+        let named_song = naming.name_songs(vec![(file_path.to_path_buf(), metadata.clone())])
+            .remove(0);
+        let relative_path = named_song.destination?;
+        Ok((named_song.metadata.unwrap(), relative_path))
+    }
+
+    fn test_config(input_dir: &Path, output_dir: &Path, dry_run: bool) -> Config {
+        Config {
+            input_path: input_dir.to_str().unwrap().to_string(),
+            output_path: output_dir.to_str().unwrap().to_string(),
+            dry_run,
+            walk_options: WalkOptions::default(),
+            template: PathTemplate::default(),
+            path_pattern: None,
+            sanitizer: Sanitizer::default(),
+            collision_policy: CollisionPolicy::default(),
+            duplicate_policy: None,
+            operation: Operation::Move,
+            unsorted_dir: None,
+            various_artists: String::from(DEFAULT_VARIOUS_ARTISTS),
+            itunes_db: None,
+            playlist_paths: None,
+        }
+    }
+
+    fn check_default_tag_info(
+        metadata: &TrackMetadata,
+    ) -> Result<(TrackMetadata, PathBuf), SkipReason> {
+        let file_path = Path::new("/in/F00/ABCD.mp3");
+        check_tag_info(metadata, file_path, &PathTemplate::default(), None)
+    }
+
     #[test]
     fn correct_tag() {
         let mut tag = Id3v2Tag::new();
@@ -208,10 +469,12 @@ mod tests {
         tag.set_title(dummy_title);
         tag.set_artist(dummy_artist);
         tag.set_album_title(dummy_album);
-        let result = check_tag_info(&tag).unwrap();
-        assert_eq!(result.title, Some(dummy_title));
-        assert_eq!(result.artist, dummy_artist);
-        assert_eq!(result.album, dummy_album);
+        let song_tag = song_tag(tag);
+        let (result, relative_path) = check_default_tag_info(&song_tag).unwrap();
+        assert_eq!(result.title.as_deref(), Some(dummy_title));
+        assert_eq!(result.artist.as_deref(), Some(dummy_artist));
+        assert_eq!(result.album.as_deref(), Some(dummy_album));
+        assert_eq!(relative_path, PathBuf::from("Dummy Artist/Dummy Album/Dummy Title.mp3"));
     }
 
     #[test]
@@ -221,10 +484,12 @@ mod tests {
         let dummy_album = "Dummy Album";
         tag.set_artist(dummy_artist);
         tag.set_album_title(dummy_album);
-        let result = check_tag_info(&tag).unwrap();
+        let song_tag = song_tag(tag);
+        let (result, relative_path) = check_default_tag_info(&song_tag).unwrap();
         assert_eq!(result.title, None);
-        assert_eq!(result.artist, dummy_artist);
-        assert_eq!(result.album, dummy_album);
+        assert_eq!(result.artist.as_deref(), Some(dummy_artist));
+        assert_eq!(result.album.as_deref(), Some(dummy_album));
+        assert_eq!(relative_path, PathBuf::from("Dummy Artist/Dummy Album/ABCD.mp3"));
     }
 
     #[test]
@@ -234,8 +499,8 @@ mod tests {
         let dummy_album = "Dummy Album";
         tag.set_title(dummy_title);
         tag.set_album_title(dummy_album);
-        let result = check_tag_info(&tag).unwrap_err();
-        assert_eq!(result.to_string(), "Missing field: artist");
+        let result = check_default_tag_info(&song_tag(tag)).unwrap_err();
+        assert_eq!(result, SkipReason::MissingField(String::from("artist")));
     }
 
     #[test]
@@ -245,8 +510,23 @@ mod tests {
         let dummy_artist = "Dummy Artist";
         tag.set_title(dummy_title);
         tag.set_artist(dummy_artist);
-        let result = check_tag_info(&tag).unwrap_err();
-        assert_eq!(result.to_string(), "Missing field: album");
+        let result = check_default_tag_info(&song_tag(tag)).unwrap_err();
+        assert_eq!(result, SkipReason::MissingField(String::from("album")));
+    }
+
+    #[test]
+    fn tag_with_missing_fields_inferred_from_path() {
+        let mut tag = Id3v2Tag::new();
+        tag.set_title("Tagged Title");
+        let path_pattern: PathPattern = "{artist} - {album}/{track} - {title}".parse().unwrap();
+        let file_path = Path::new("/in/Dummy Artist - Dummy Album/07 - Path Title.mp3");
+        let song_tag = song_tag(tag);
+        let (metadata, relative_path) = check_tag_info(
+            &song_tag, file_path, &PathTemplate::default(), Some(&path_pattern)
+        ).unwrap();
+        assert_eq!(relative_path, PathBuf::from("Dummy Artist/Dummy Album/07 Tagged Title.mp3"));
+        assert_eq!(metadata.track_number, Some(7));
+        assert_eq!(metadata.inferred, vec![Field::Artist, Field::Album, Field::Track]);
     }
 
     #[test]
@@ -254,12 +534,40 @@ mod tests {
         let outdir = tempdir().unwrap();
         let artist = "Dummy Artist";
         let album = "Dummy Album";
-        let _ = create_song_dir(&outdir, &artist, &album).unwrap();
+        let song_dir = outdir.as_ref().join(artist).join(album);
+        let created_dirs = create_song_dir(&song_dir).unwrap();
         let mut outdir_path = outdir.as_ref().to_path_buf();
         outdir_path.push(artist);
         outdir_path.push(album);
         let was_correct_dir_created = outdir_path.try_exists().unwrap();
-        assert_eq!(was_correct_dir_created, true);
+        assert!(was_correct_dir_created);
+        // Check both levels of dir are reported as created, outermost first
+        assert_eq!(created_dirs, vec![outdir.as_ref().join(artist), outdir_path]);
+    }
+
+    #[test]
+    fn tag_with_fields_needed_by_custom_template() {
+        let mut tag = Id3v2Tag::new();
+        tag.set_title("Dummy Title");
+        tag.set_album_artist("Dummy Album Artist");
+        tag.set_album_title("Dummy Album");
+        tag.set_year(2001);
+        tag.set_track_number(7);
+        let template: PathTemplate =
+            "{albumartist|artist}/{year} - {album}/{track:02} {title}.{ext}".parse().unwrap();
+        let song_tag = song_tag(tag);
+        let file_path = Path::new("/in/F00/ABCD.mp3");
+        let (_, relative_path) = check_tag_info(&song_tag, file_path, &template, None).unwrap();
+        assert_eq!(
+            relative_path,
+            PathBuf::from("Dummy Album Artist/2001 - Dummy Album/07 Dummy Title.mp3"),
+        );
+        // The default template groups by the album artist, so doesn't need the artist either
+        let (_, relative_path) = check_default_tag_info(&song_tag).unwrap();
+        assert_eq!(
+            relative_path,
+            PathBuf::from("Dummy Album Artist/Dummy Album/07 Dummy Title.mp3"),
+        );
     }
 
     #[test]
@@ -267,13 +575,9 @@ mod tests {
         // Setup directory that song file should be moved into
         let outdir = tempdir().unwrap();
         let mut outdir_path = outdir.as_ref().to_path_buf();
-        let song_info = SongInfo {
-            artist: "Dummy Artist",
-            album: "Dummy Album",
-            title: Some("Dummy Title"),
-        };
-        outdir_path.push(song_info.artist);
-        outdir_path.push(song_info.album);
+        let (artist, album, title) = ("Dummy Artist", "Dummy Album", "Dummy Title");
+        outdir_path.push(artist);
+        outdir_path.push(album);
         create_dir_all(outdir_path.clone()).unwrap();
         // Setup directory where dummy file originally exists before attempted move, and setup
         // dummy file
@@ -284,20 +588,20 @@ mod tests {
         // Create dummy song file
         File::create(original_filepath.clone()).unwrap();
         // Attempt to move dummy file to the created output dir
-        let _ = move_song_file(
-            &original_filepath,
-            &song_info,
-            &outdir.as_ref().to_path_buf(),
-        ).unwrap();
+        let planned_move = PlannedMove {
+            source: original_filepath.clone(),
+            destination: outdir_path.join(format!("{}.mp3", title)),
+            overwrite: false,
+        };
+        move_song_file(&planned_move, Operation::Move).unwrap();
         // Define the expected path of the moved + renamed file
         let mut expected_new_filepath = outdir_path.clone();
-        let expected_filename = format!("{}.mp3", song_info.title.unwrap());
+        let expected_filename = format!("{}.mp3", title);
         expected_new_filepath.push(expected_filename);
         // Check if the dummy file was moved correctly
         let was_file_moved_correctly = expected_new_filepath.try_exists().unwrap();
-        assert_eq!(
+        assert!(
             was_file_moved_correctly,
-            true,
             "Expected filepath: {:?}",
             expected_new_filepath,
         )
@@ -307,13 +611,9 @@ mod tests {
     fn move_song_without_title_info() {
         let outdir = tempdir().unwrap();
         let mut outdir_path = outdir.as_ref().to_path_buf();
-        let song_info = SongInfo {
-            artist: "Dummy Artist",
-            album: "Dummy Album",
-            title: None,
-        };
-        outdir_path.push(song_info.artist);
-        outdir_path.push(song_info.album);
+        let (artist, album) = ("Dummy Artist", "Dummy Album");
+        outdir_path.push(artist);
+        outdir_path.push(album);
         create_dir_all(outdir_path.clone()).unwrap();
         // Setup directory where dummy file originally exists before attempted move, and setup
         // dummy file
@@ -323,25 +623,194 @@ mod tests {
         original_filepath.push(original_filename);
         // Create dummy song file
         File::create(original_filepath.clone()).unwrap();
+        let mut tag = Id3v2Tag::new();
+        tag.set_artist(artist);
+        tag.set_album_title(album);
+        let (_, relative_path) =
+            check_tag_info(&song_tag(tag), &original_filepath, &PathTemplate::default(), None)
+                .unwrap();
         // Attempt to move dummy file to the created output dir
-        let _ = move_song_file(
-            &original_filepath,
-            &song_info,
-            &outdir.as_ref().to_path_buf(),
-        ).unwrap();
-        // Define the expected path of the moved + renamed file
+        let planned_move = PlannedMove {
+            source: original_filepath.clone(),
+            destination: outdir.as_ref().join(relative_path),
+            overwrite: false,
+        };
+        move_song_file(&planned_move, Operation::Move).unwrap();
+        // Define the expected path of the moved file, which keeps its name
         let mut expected_new_filepath = outdir_path.clone();
         expected_new_filepath.push(original_filename);
         // Check if the dummy file was moved correctly
         let was_file_moved_correctly = expected_new_filepath.try_exists().unwrap();
-        assert_eq!(
+        assert!(
             was_file_moved_correctly,
-            true,
             "Expected filepath: {:?}",
             expected_new_filepath,
         )
     }
 
+    #[test]
+    fn move_song_doesnt_clobber_unless_planned() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("ABCD.mp3");
+        let destination = outdir.as_ref().join("Song.mp3");
+        File::create(source.clone()).unwrap();
+        File::create(destination.clone()).unwrap();
+        let mut planned_move = PlannedMove { source: source.clone(), destination, overwrite: false };
+        let res = move_song_file(&planned_move, Operation::Move);
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::AlreadyExists));
+        assert!(source.try_exists().unwrap());
+        planned_move.overwrite = true;
+        move_song_file(&planned_move, Operation::Move).unwrap();
+        assert!(!source.try_exists().unwrap());
+    }
+
+    #[test]
+    fn dry_run_plans_without_touching_filesystem() {
+        // Setup input dir with one subdir containing a tagged and an untagged song file
+        let indir = tempdir().unwrap();
+        let subdir = indir.as_ref().join("F00");
+        create_dir_all(subdir.clone()).unwrap();
+        let mut tag = Tag::new();
+        tag.set_title("Song");
+        tag.set_artist("Artist");
+        tag.set_album("Album");
+        File::create(subdir.join("ABCD.mp3")).unwrap();
+        tag.write_to_path(subdir.join("ABCD.mp3"), id3::Version::Id3v24).unwrap();
+        let mut untitled_tag = Tag::new();
+        untitled_tag.set_title("Other Song");
+        File::create(subdir.join("EFGH.mp3")).unwrap();
+        untitled_tag.write_to_path(subdir.join("EFGH.mp3"), id3::Version::Id3v24).unwrap();
+        let outdir = tempdir().unwrap();
+        let config = test_config(indir.as_ref(), outdir.as_ref(), true);
+        let report = run(config).unwrap();
+        assert_eq!(report.journal_path, None);
+        assert_eq!(report.counts.placed, 1);
+        assert_eq!(report.counts.missing_field, 1);
+        let plan = report.plan;
+        // Check the plan describes the move of the tagged file and the skip of the other
+        let album_dir = outdir.as_ref().join("Artist").join("Album");
+        assert!(plan.dirs.contains(&album_dir));
+        assert_eq!(plan.moves.len(), 1);
+        assert_eq!(plan.moves[0].source, subdir.join("ABCD.mp3"));
+        assert_eq!(plan.moves[0].destination, album_dir.join("Song.mp3"));
+        assert_eq!(plan.skipped.len(), 1);
+        assert_eq!(plan.skipped[0].path, subdir.join("EFGH.mp3"));
+        assert_eq!(plan.skipped[0].reason, SkipReason::MissingField(String::from("artist")));
+        // Check nothing was actually moved or created
+        assert!(subdir.join("ABCD.mp3").try_exists().unwrap());
+        assert!(!album_dir.try_exists().unwrap());
+    }
+
+    #[test]
+    fn unreadable_tag_collected_rather_than_panicking() {
+        let indir = tempdir().unwrap();
+        let mut tag = Tag::new();
+        tag.set_title("Song");
+        tag.set_artist("Artist");
+        tag.set_album("Album");
+        File::create(indir.as_ref().join("ABCD.mp3")).unwrap();
+        tag.write_to_path(indir.as_ref().join("ABCD.mp3"), id3::Version::Id3v24).unwrap();
+        // An MP3 without any ID3 header
+        std::fs::write(indir.as_ref().join("EFGH.mp3"), b"not really an mp3").unwrap();
+        File::create(indir.as_ref().join("cover.jpg")).unwrap();
+        let outdir = tempdir().unwrap();
+        let config = test_config(indir.as_ref(), outdir.as_ref(), false);
+        let report = run(config).unwrap();
+        let song_path = outdir.as_ref().join("Artist").join("Album").join("Song.mp3");
+        assert!(song_path.try_exists().unwrap());
+        assert_eq!(report.plan.skipped.len(), 2);
+        assert!(matches!(report.plan.skipped[0].reason, SkipReason::UnreadableTag(_)));
+        assert_eq!(report.counts.unreadable_tag, 1);
+        assert_eq!(report.counts.unsupported_extension, 1);
+        assert_eq!(report.errors.len(), 1);
+        match &report.errors[0] {
+            MoverError::TagRead { path, .. } => assert_eq!(path, &indir.as_ref().join("EFGH.mp3")),
+            e => panic!("Expected tag read error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn execute_plan_journals_changes_and_collects_errors() {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("ABCD.mp3");
+        let missing_source = indir.as_ref().join("EFGH.mp3");
+        File::create(source.clone()).unwrap();
+        let artist_dir = outdir.as_ref().join("Artist");
+        let album_dir = artist_dir.join("Album");
+        let mut plan = Plan::new();
+        plan.add_dir(album_dir.clone());
+        plan.add_move(source.clone(), album_dir.join("Song.mp3"), CollisionPolicy::Fail).unwrap();
+        plan.add_move(missing_source.clone(), album_dir.join("Other.mp3"), CollisionPolicy::Fail)
+            .unwrap();
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        let errors = execute_plan(&plan, &mut journal).unwrap();
+        // Check the missing source file didn't stop the other move
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            MoverError::Filesystem { path, .. } => assert_eq!(path, &missing_source),
+            e => panic!("Expected filesystem error, got {:?}", e),
+        }
+        let entries = journal::read_journal(journal.path()).unwrap();
+        assert_eq!(entries, vec![
+            JournalEntry::CreateDir { path: artist_dir },
+            JournalEntry::CreateDir { path: album_dir.clone() },
+            JournalEntry::PlaceFile {
+                operation: Operation::Move,
+                source,
+                destination: album_dir.join("Song.mp3"),
+                replaced_existing: false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn playlist_destination_follows_collision_policy() {
+        let outdir = tempdir().unwrap();
+        let path = outdir.as_ref().join("Road Trip.m3u8");
+        let res = playlist_destination(&path, CollisionPolicy::Fail).unwrap();
+        assert_eq!(res, Some(path.clone()));
+        File::create(&path).unwrap();
+        File::create(outdir.as_ref().join("Road Trip (2).m3u8")).unwrap();
+        let res = playlist_destination(&path, CollisionPolicy::Rename).unwrap();
+        assert_eq!(res, Some(outdir.as_ref().join("Road Trip (3).m3u8")));
+        let res = playlist_destination(&path, CollisionPolicy::Overwrite).unwrap();
+        assert_eq!(res, Some(path.clone()));
+        assert_eq!(playlist_destination(&path, CollisionPolicy::Skip).unwrap(), None);
+        assert!(playlist_destination(&path, CollisionPolicy::Fail).is_err());
+    }
+
+    #[test]
+    fn scan_reads_tag_info_without_moving() {
+        let indir = tempdir().unwrap();
+        let subdir = indir.as_ref().join("F00");
+        create_dir_all(subdir.clone()).unwrap();
+        let mut tag = Tag::new();
+        tag.set_title("Song");
+        tag.set_artist("Artist");
+        tag.set_album("Album");
+        File::create(subdir.join("ABCD.mp3")).unwrap();
+        tag.write_to_path(subdir.join("ABCD.mp3"), id3::Version::Id3v24).unwrap();
+        let (template, sanitizer) = (PathTemplate::default(), Sanitizer::default());
+        let naming = Naming {
+            template: &template,
+            sanitizer: &sanitizer,
+            path_pattern: None,
+            various_artists: DEFAULT_VARIOUS_ARTISTS,
+            itunes_db: None,
+        };
+        let scan = scan(indir.as_ref(), &WalkOptions::default(), naming).unwrap();
+        assert_eq!(scan.songs.len(), 1);
+        assert_eq!(scan.songs[0].path, subdir.join("ABCD.mp3"));
+        assert_eq!(scan.songs[0].artist, Some(String::from("Artist")));
+        assert_eq!(scan.songs[0].album, Some(String::from("Album")));
+        assert_eq!(scan.songs[0].destination, PathBuf::from("Artist/Album/Song.mp3"));
+        assert_eq!(scan.songs[0].title, Some(String::from("Song")));
+        assert!(scan.skipped.is_empty());
+        assert!(subdir.join("ABCD.mp3").try_exists().unwrap());
+    }
+
     #[test]
     fn find_three_song_files_in_dir() {
         // Setup input directory containing all MP3 files to be checked and moved
@@ -353,30 +822,29 @@ mod tests {
         let album = "Some Album";
         let artist = "Some Artist";
         for (i, song) in songs.iter().enumerate() {
-            tag.set_title(format!("{} {}", "Song", i.to_string()));
+            tag.set_title(format!("{} {}", "Song", i));
             tag.set_artist(artist);
             tag.set_album(album);
             File::create(indir_path.join(song)).unwrap();
             tag.write_to_path(indir_path.join(song), id3::Version::Id3v24).unwrap();
         }
         // Create some unsupported audio files in the input dir too
-        let other_files = ["A.m4a", "B.mp4", "C.flac"];
+        let other_files = ["A.wav", "B.ogg", "C.wma"];
         for unsupported_song_file in other_files.iter() {
             File::create(indir_path.join(unsupported_song_file)).unwrap();
         }
         // Call function to check for supported song files
-        let song_files: Vec<GlobResult> = find_song_files(&indir_path).unwrap().collect();
+        let song_files = find_song_files(&indir_path, &WalkOptions::default()).unwrap();
         // Check that we got the three MP3 files and none of the other unsupported files
         let supported_song_filepaths = songs.map(|song| indir_path.join(song));
         assert_eq!(song_files.len(), 3);
         for filepath in song_files {
-            let matched_song_filepath = filepath.unwrap();
-            assert_eq!(supported_song_filepaths.contains(&matched_song_filepath), true);
+            assert!(supported_song_filepaths.contains(&filepath));
         }
         let unsupported_song_filepaths = other_files.map(|song| indir_path.join(song));
         for filepath in unsupported_song_filepaths {
-            assert_eq!(
-                supported_song_filepaths.contains(&filepath), false
+            assert!(
+                !supported_song_filepaths.contains(&filepath)
             );
         }
     }
@@ -386,13 +854,13 @@ mod tests {
         let indir = tempdir().unwrap();
         let indir_path = indir.as_ref().to_path_buf();
         // Create some unsupported files in the input dir
-        let files = ["A.jpg", "B.mp4", "C.txt"];
+        let files = ["A.jpg", "B.wav", "C.txt"];
         for filename in files.iter() {
             File::create(indir_path.join(filename)).unwrap();
         }
         // Call function to check for supported files
-        let song_files: Vec<GlobResult> = find_song_files(&indir_path).unwrap().collect();
-        assert_eq!(song_files.is_empty(), true);
+        let song_files = find_song_files(&indir_path, &WalkOptions::default()).unwrap();
+        assert!(song_files.is_empty());
     }
 }
 
@@ -1,23 +1,216 @@
 use std::env;
+use std::path::Path;
 use std::process;
+use std::time::Duration;
 
 use log::{error, info};
 
+use mp3_mover::cli::{Cli, Command, ConfigArgs, ConfigCommand, NamingArgs, TemplateArgs};
 use mp3_mover::config::Config;
-use mp3_mover::run;
+use mp3_mover::config_file::{
+    Settings, apply_config_file, apply_config_file_to_scan, apply_config_file_to_verify,
+};
+use mp3_mover::error::MoverError;
+use mp3_mover::infer::{PathPattern, PatternError};
+use mp3_mover::itunesdb::{ITunesDb, load_itunes_db};
+use mp3_mover::journal::undo;
+use mp3_mover::pipeline::Naming;
+use mp3_mover::report::RunReport;
+use mp3_mover::sanitize::Sanitizer;
+use mp3_mover::template::{PathTemplate, TemplateError};
+use mp3_mover::verify::{fix_library, verify};
+use mp3_mover::watch::watch;
+use mp3_mover::{run, scan};
 
 fn main(){
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        error!("Problem parsing args: {}", err);
-        process::exit(1);
-    });
-
-    if let Err(e) = run(config) {
-        error!("Encountered error: {}", e);
-        process::exit(1);
+    let cli = Cli::parse_args(env::args_os()).unwrap_or_else(|err| err.exit());
+
+    match cli.command {
+        Command::Organize(mut args) => {
+            apply_config_file(&mut args).unwrap_or_else(|err| {
+                error!("Problem reading config file: {}", err);
+                process::exit(err.exit_code());
+            });
+            let config = Config::new(&args).unwrap_or_else(|err| {
+                error!("Problem parsing args: {}", err);
+                process::exit(err.exit_code());
+            });
+            let dry_run = config.dry_run;
+
+            let report = run(config).unwrap_or_else(|e| {
+                error!("Encountered error: {}", e);
+                process::exit(e.exit_code());
+            });
+
+            if let Err(e) = output_report(&report, dry_run, args.json_report.as_deref()) {
+                error!("Couldn't write report: {}", e);
+                process::exit(e.exit_code());
+            }
+            if let Some(exit_code) = report.errors.iter().map(MoverError::exit_code).max() {
+                process::exit(exit_code);
+            }
+        },
+        Command::Scan(mut args) => {
+            apply_config_file_to_scan(&mut args).unwrap_or_else(|err| {
+                error!("Problem reading config file: {}", err);
+                process::exit(err.exit_code());
+            });
+            let walk_options = args.walk.to_walk_options();
+            let input_path = Path::new(&args.input);
+            let parts = NamingParts::parse_or_exit(&args.layout, &args.naming, input_path);
+            let naming = parts.naming(&args.naming.various_artists);
+            let scan = scan(input_path, &walk_options, naming).unwrap_or_else(|e| {
+                error!("Encountered error: {}", e);
+                process::exit(e.exit_code());
+            });
+            print!("{}", scan);
+        },
+        Command::Watch(mut args) => {
+            apply_config_file(&mut args.organize).unwrap_or_else(|err| {
+                error!("Problem reading config file: {}", err);
+                process::exit(err.exit_code());
+            });
+            let config = Config::for_watch(&args.organize).unwrap_or_else(|err| {
+                error!("Problem parsing args: {}", err);
+                process::exit(err.exit_code());
+            });
+            let settle = Duration::from_secs(args.settle_secs);
+            let json_report = args.organize.json_report.as_deref();
+            watch(&config, settle, &mut |res| match res {
+                Ok(report) => {
+                    if let Err(e) = output_report(&report, config.dry_run, json_report) {
+                        error!("Couldn't write report: {}", e);
+                    }
+                },
+                Err(e) => error!("Encountered error: {}", e),
+            }).unwrap_or_else(|e| {
+                error!("Encountered error: {}", e);
+                process::exit(e.exit_code());
+            });
+        },
+        Command::Verify(mut args) => {
+            apply_config_file_to_verify(&mut args).unwrap_or_else(|err| {
+                error!("Problem reading config file: {}", err);
+                process::exit(err.exit_code());
+            });
+            let walk_options = args.walk.to_walk_options();
+            let library = Path::new(&args.library);
+            let parts = NamingParts::parse_or_exit(&args.layout, &args.naming, library);
+            let naming = parts.naming(&args.naming.various_artists);
+            let verification = verify(library, &walk_options, naming).unwrap_or_else(|e| {
+                error!("Encountered error: {}", e);
+                process::exit(e.exit_code());
+            });
+            print!("{}", verification);
+            if args.fix {
+                let fix = fix_library(library, &walk_options, &verification).unwrap_or_else(|e| {
+                    error!("Encountered error: {}", e);
+                    process::exit(e.exit_code());
+                });
+                print!("{}", fix);
+                for e in fix.report.errors.iter() {
+                    error!("{}", e);
+                }
+                if let Some(exit_code) = fix.report.errors.iter().map(MoverError::exit_code).max() {
+                    process::exit(exit_code);
+                }
+            }
+        },
+        Command::Undo(args) => {
+            let report = undo(Path::new(&args.journal)).unwrap_or_else(|e| {
+                error!("Encountered error: {}", e);
+                process::exit(e.exit_code());
+            });
+            print!("{}", report);
+            if !report.failures.is_empty() {
+                error!("Couldn't revert {} changes", report.failures.len());
+                process::exit(1);
+            }
+        },
+        Command::Config(ConfigArgs { command: ConfigCommand::Check(mut args) }) => {
+            let config_path = apply_config_file(&mut args).unwrap_or_else(|err| {
+                error!("Problem reading config file: {}", err);
+                process::exit(err.exit_code());
+            });
+            if let Err(err) = Config::check(&args) {
+                error!("Problem parsing args: {}", err);
+                process::exit(err.exit_code());
+            }
+            match &config_path {
+                Some(config_path) => println!("# Config file: {}", config_path.display()),
+                None => println!("# No config file found"),
+            }
+            println!("# Input: {}", args.input);
+            println!("# Output: {}", args.output);
+            let settings = toml::to_string(&Settings::from_organize_args(&args))
+                .expect("Settings should always serialize to TOML");
+            print!("{}", settings);
+        },
     }
 
     info!("Program completed sucecssfully");
 }
+
+/// What the scan and verify subcommands name song files with, parsed from their args.
+struct NamingParts {
+    template: PathTemplate,
+    sanitizer: Sanitizer,
+    path_pattern: Option<PathPattern>,
+    itunes_db: Option<ITunesDb>,
+}
+
+impl NamingParts {
+    /// Parses the naming args, exiting if any are invalid.
+    fn parse_or_exit(
+        layout: &TemplateArgs,
+        naming: &NamingArgs,
+        input_path: &Path,
+    ) -> NamingParts {
+        let exit = |err: MoverError| -> ! {
+            error!("Problem parsing args: {}", err);
+            process::exit(err.exit_code());
+        };
+        let template = layout.template.parse()
+            .unwrap_or_else(|err: TemplateError| exit(MoverError::Config(err.to_string())));
+        let sanitizer = naming.to_sanitizer()
+            .unwrap_or_else(|err| exit(MoverError::Config(err.to_string())));
+        let path_pattern = layout.path_pattern.as_deref()
+            .map(str::parse)
+            .transpose()
+            .unwrap_or_else(|err: PatternError| exit(MoverError::Config(err.to_string())));
+        let itunes_db = load_itunes_db(naming.itunes_db.as_deref(), input_path)
+            .unwrap_or_else(|err| exit(MoverError::Config(err.to_string())));
+        NamingParts { template, sanitizer, path_pattern, itunes_db }
+    }
+
+    fn naming<'n>(&'n self, various_artists: &'n str) -> Naming<'n> {
+        Naming {
+            template: &self.template,
+            sanitizer: &self.sanitizer,
+            path_pattern: self.path_pattern.as_ref(),
+            various_artists,
+            itunes_db: self.itunes_db.as_ref(),
+        }
+    }
+}
+
+/// Prints the report of an organize run, also writing it to `json_report` if given, and logs
+/// its errors.
+fn output_report(
+    report: &RunReport,
+    dry_run: bool,
+    json_report: Option<&str>,
+) -> Result<(), MoverError> {
+    if dry_run {
+        print!("{}", report.plan);
+    }
+    print!("{}", report);
+    for e in report.errors.iter() {
+        error!("{}", e);
+    }
+    if let Some(json_report) = json_report {
+        report.write_json(Path::new(json_report))?;
+    }
+    Ok(())
+}
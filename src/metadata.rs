@@ -0,0 +1,138 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::template::Field;
+
+/// How a song file's audio is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Mp3,
+    Flac,
+    Aac,
+    Alac,
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Mp3 => write!(f, "MP3"),
+            Codec::Flac => write!(f, "FLAC"),
+            Codec::Aac => write!(f, "AAC"),
+            Codec::Alac => write!(f, "ALAC"),
+        }
+    }
+}
+
+/// What's known about a track, read from its file's tag and audio stream whatever format they
+/// are in, that its destination is built from.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrackMetadata {
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub track_number: Option<u16>,
+    pub total_tracks: Option<u16>,
+    pub disc_number: Option<u16>,
+    pub total_discs: Option<u16>,
+    pub year: Option<i32>,
+    /// Release date as tagged, which may be a full date such as 2001-05-14 or only a year
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    /// Whether the tag flags the track as part of a compilation
+    pub compilation: bool,
+    pub duration: Option<Duration>,
+    /// Average bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    /// Sample rate in Hz
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    pub codec: Option<Codec>,
+    /// Size of the whole file in bytes
+    pub file_size: Option<u64>,
+    /// Times played, as counted by an iPod
+    pub play_count: Option<u32>,
+    /// Rating out of 5 stars, as set on an iPod
+    pub rating: Option<u8>,
+    /// Artist the track's album is grouped under when the tag has no album artist of its own
+    pub album_group: Option<String>,
+    /// Highest disc number among the song files of the track's album that are named with it, so
+    /// that disc 1 of a set is known to be part of one even without a total in its tag
+    pub album_discs: Option<u16>,
+    /// Fields that had no value in the tag and were read from the path instead
+    pub inferred: Vec<Field>,
+}
+
+impl TrackMetadata {
+    /// Fills in the fields this metadata lacks from `other`, treating empty text as missing.
+    pub fn fill_from(&mut self, other: &TrackMetadata) {
+        fn fill<T: Clone>(slot: &mut Option<T>, value: &Option<T>) {
+            if slot.is_none() {
+                slot.clone_from(value);
+            }
+        }
+        fn fill_str(slot: &mut Option<String>, value: &Option<String>) {
+            if slot.as_ref().is_none_or(|existing| existing.is_empty()) && value.is_some() {
+                slot.clone_from(value);
+            }
+        }
+        fill_str(&mut self.artist, &other.artist);
+        fill_str(&mut self.album_artist, &other.album_artist);
+        fill_str(&mut self.album, &other.album);
+        fill_str(&mut self.title, &other.title);
+        fill(&mut self.track_number, &other.track_number);
+        fill(&mut self.total_tracks, &other.total_tracks);
+        fill(&mut self.disc_number, &other.disc_number);
+        fill(&mut self.total_discs, &other.total_discs);
+        fill(&mut self.year, &other.year);
+        fill_str(&mut self.date, &other.date);
+        fill_str(&mut self.genre, &other.genre);
+        fill_str(&mut self.composer, &other.composer);
+        self.compilation |= other.compilation;
+        fill(&mut self.duration, &other.duration);
+        fill(&mut self.bitrate, &other.bitrate);
+        fill(&mut self.sample_rate, &other.sample_rate);
+        fill(&mut self.channels, &other.channels);
+        fill(&mut self.codec, &other.codec);
+        fill(&mut self.file_size, &other.file_size);
+        fill(&mut self.play_count, &other.play_count);
+        fill(&mut self.rating, &other.rating);
+    }
+
+    /// Average bitrate of `audio_bytes` of audio lasting `duration`, in kbit/s.
+    pub(crate) fn average_bitrate(audio_bytes: u64, duration: Duration) -> Option<u32> {
+        let millis = duration.as_millis();
+        if millis == 0 {
+            return None
+        }
+        u32::try_from(u128::from(audio_bytes) * 8 / millis).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_from_keeps_own_values() {
+        let mut metadata = TrackMetadata {
+            artist: Some(String::from("Artist")),
+            album: Some(String::new()),
+            ..TrackMetadata::default()
+        };
+        let other = TrackMetadata {
+            artist: Some(String::from("Other Artist")),
+            album: Some(String::from("Album")),
+            track_number: Some(3),
+            play_count: Some(12),
+            ..TrackMetadata::default()
+        };
+        metadata.fill_from(&other);
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.track_number, Some(3));
+        assert_eq!(metadata.play_count, Some(12));
+        assert_eq!(metadata.title, None);
+    }
+}
@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// How far past the ID3v2 tag to look for the first frame before giving up.
+const MAX_FRAME_SEARCH: usize = 64 * 1024;
+
+const BITRATES_V1: [[u32; 15]; 3] = [
+    [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+];
+const BITRATES_V2: [[u32; 15]; 2] = [
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const SAMPLE_RATES_V1: [u32; 3] = [44100, 48000, 32000];
+
+/// The audio properties of an MP3 file, worked out from its first frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mp3Properties {
+    pub(crate) duration: Option<Duration>,
+    /// Average bitrate in kbit/s
+    pub(crate) bitrate: u32,
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameHeader {
+    /// 1 for MPEG-1, 2 for MPEG-2 and 2.5
+    version: u8,
+    is_mpeg_2_5: bool,
+    layer: u8,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u8,
+}
+
+impl FrameHeader {
+    fn parse(bytes: [u8; 4]) -> Option<FrameHeader> {
+        let header = u32::from_be_bytes(bytes);
+        if header >> 21 != 0x7ff {
+            return None
+        }
+        let (version, is_mpeg_2_5) = match (header >> 19) & 0b11 {
+            0b11 => (1, false),
+            0b10 => (2, false),
+            0b00 => (2, true),
+            _ => return None,
+        };
+        let layer = match (header >> 17) & 0b11 {
+            0b11 => 1,
+            0b10 => 2,
+            0b01 => 3,
+            _ => return None,
+        };
+        let bitrate_index = ((header >> 12) & 0b1111) as usize;
+        let sample_rate_index = ((header >> 10) & 0b11) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            return None
+        }
+        let bitrate = match version {
+            1 => BITRATES_V1[layer as usize - 1][bitrate_index],
+            _ => BITRATES_V2[usize::from(layer != 1)][bitrate_index],
+        };
+        let sample_rate = SAMPLE_RATES_V1[sample_rate_index]
+            / if is_mpeg_2_5 { 4 } else { u32::from(version) };
+        let channels = if (header >> 6) & 0b11 == 0b11 { 1 } else { 2 };
+        Some(FrameHeader { version, is_mpeg_2_5, layer, bitrate, sample_rate, channels })
+    }
+
+    fn samples_per_frame(&self) -> u32 {
+        match (self.layer, self.version) {
+            (1, _) => 384,
+            (3, 2) => 576,
+            _ => 1152,
+        }
+    }
+
+    /// Where the Xing or Info header of a VBR file would be, just after the side information.
+    fn xing_offset(&self) -> usize {
+        match (self.version, self.channels) {
+            (1, 1) => 4 + 17,
+            (1, _) => 4 + 32,
+            (_, 1) => 4 + 9,
+            _ => 4 + 17,
+        }
+    }
+}
+
+/// Size of the ID3v2 tag at the start of `bytes`, if there's one, including its header and
+/// any footer.
+pub(crate) fn id3v2_size(bytes: &[u8]) -> usize {
+    if bytes.len() < 10 || &bytes[..3] != b"ID3" {
+        return 0
+    }
+    let size = bytes[6..10].iter().fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+    let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+/// Frame count from the Xing or Info header of a VBR (or LAME CBR) file's first frame.
+fn xing_frames(frame: &[u8], header: &FrameHeader) -> Option<u32> {
+    let xing = frame.get(header.xing_offset()..)?;
+    if xing.len() < 12 || !(xing.starts_with(b"Xing") || xing.starts_with(b"Info")) {
+        return None
+    }
+    let flags = u32::from_be_bytes(xing[4..8].try_into().ok()?);
+    if flags & 1 == 0 {
+        return None
+    }
+    Some(u32::from_be_bytes(xing[8..12].try_into().ok()?))
+}
+
+fn properties(bytes: &[u8], file_size: u64) -> Option<Mp3Properties> {
+    let start = id3v2_size(bytes).min(bytes.len());
+    let search_end = bytes.len().min(start + MAX_FRAME_SEARCH);
+    let (offset, header) = (start..search_end.saturating_sub(3)).find_map(|i| {
+        let header = FrameHeader::parse(bytes[i..i + 4].try_into().ok()?)?;
+        Some((i, header))
+    })?;
+    let audio_bytes = file_size.saturating_sub(offset as u64);
+    let (duration, bitrate) = match xing_frames(&bytes[offset..], &header) {
+        Some(frames) => {
+            let samples = u64::from(frames) * u64::from(header.samples_per_frame());
+            let duration = Duration::from_secs_f64(samples as f64 / f64::from(header.sample_rate));
+            let bitrate = crate::metadata::TrackMetadata::average_bitrate(audio_bytes, duration);
+            (Some(duration), bitrate.unwrap_or(header.bitrate))
+        },
+        // Without a frame count, take the first frame's bitrate to hold for the whole file
+        None => {
+            let millis = audio_bytes * 8 / u64::from(header.bitrate);
+            (Some(Duration::from_millis(millis)), header.bitrate)
+        },
+    };
+    Some(Mp3Properties {
+        duration,
+        bitrate,
+        sample_rate: header.sample_rate,
+        channels: header.channels,
+    })
+}
+
+/// Reads the audio properties of the MP3 file at `path`, or `None` if no MPEG audio frame can
+/// be found near its start.
+pub(crate) fn read_mp3_properties(path: &Path) -> std::io::Result<Option<Mp3Properties>> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut header = [0; 10];
+    let read = file.read(&mut header)?;
+    let tag_size = id3v2_size(&header[..read]);
+    file.seek(SeekFrom::Start(tag_size as u64))?;
+    let mut bytes = Vec::new();
+    file.take(MAX_FRAME_SEARCH as u64).read_to_end(&mut bytes)?;
+    let properties = properties(&bytes, file_size.saturating_sub(tag_size as u64));
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 Layer III, 128 kbit/s, 44.1 kHz, joint stereo
+    const CBR_HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x44];
+
+    #[test]
+    fn frame_headers_parsed() {
+        let header = FrameHeader::parse(CBR_HEADER).unwrap();
+        assert_eq!(header.bitrate, 128);
+        assert_eq!(header.sample_rate, 44100);
+        assert_eq!(header.channels, 2);
+        assert_eq!(header.samples_per_frame(), 1152);
+        // MPEG-2 Layer III, 64 kbit/s, 22.05 kHz, mono
+        let header = FrameHeader::parse([0xff, 0xf3, 0x80, 0xc4]).unwrap();
+        assert_eq!((header.version, header.bitrate, header.sample_rate), (2, 64, 22050));
+        assert_eq!(header.channels, 1);
+        assert_eq!(header.samples_per_frame(), 576);
+        assert_eq!(FrameHeader::parse([0xff, 0xfb, 0xf0, 0x44]), None);
+        assert_eq!(FrameHeader::parse(*b"ID3\x04"), None);
+    }
+
+    #[test]
+    fn cbr_duration_estimated_from_size() {
+        let mut bytes = vec![0; 100];
+        bytes.extend_from_slice(&CBR_HEADER);
+        // A second of 128 kbit/s audio after the junk
+        let file_size = 100 + 16_000;
+        let properties = properties(&bytes, file_size).unwrap();
+        assert_eq!(properties.duration, Some(Duration::from_secs(1)));
+        assert_eq!(properties.bitrate, 128);
+    }
+
+    #[test]
+    fn vbr_duration_read_from_xing_header() {
+        let mut bytes = CBR_HEADER.to_vec();
+        bytes.resize(4 + 32, 0);
+        bytes.extend_from_slice(b"Xing");
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        // Ten seconds of 1152-sample frames at 44.1 kHz
+        let frames: u32 = 44100 * 10 / 1152;
+        bytes.extend_from_slice(&frames.to_be_bytes());
+        let properties = properties(&bytes, 80_000).unwrap();
+        let duration = properties.duration.unwrap();
+        assert_eq!(duration.as_millis(), u128::from(frames) * 1152 * 1000 / 44100);
+        assert_eq!(properties.bitrate, 64);
+    }
+
+    #[test]
+    fn id3v2_tag_skipped() {
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        assert_eq!(id3v2_size(&bytes), 10 + 128);
+        bytes[5] = 0x10;
+        assert_eq!(id3v2_size(&bytes), 10 + 128 + 10);
+        assert_eq!(id3v2_size(b"not a tag"), 0);
+    }
+}
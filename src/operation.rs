@@ -0,0 +1,225 @@
+use std::fs::{File, copy, hard_link, remove_file, rename};
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::path::Path;
+
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Move song files, copying then deleting them if the output is on another filesystem
+    #[default]
+    Move,
+    /// Copy song files, leaving the originals untouched
+    Copy,
+    /// Hardlink song files (the output must be on the same filesystem as the input)
+    Hardlink,
+    /// Symlink to the original song files
+    Symlink,
+    /// Share the original song files' data blocks where the filesystem supports it (e.g. Btrfs,
+    /// XFS), falling back to a copy otherwise
+    Reflink,
+}
+
+impl Operation {
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Operation::Move => "move",
+            Operation::Copy => "copy",
+            Operation::Hardlink => "hardlink",
+            Operation::Symlink => "symlink",
+            Operation::Reflink => "reflink",
+        }
+    }
+
+    /// Places the song file at `source` at `destination`, replacing anything already there.
+    pub fn perform(&self, source: &Path, destination: &Path) -> std::io::Result<()> {
+        info!("Performing {} of {:?} to {:?}", self.verb(), source, destination);
+        match self {
+            Operation::Move => move_file(source, destination),
+            Operation::Copy => copy(source, destination).map(|_| ()),
+            Operation::Hardlink => {
+                remove_if_exists(destination)?;
+                hard_link(source, destination)
+            },
+            Operation::Symlink => {
+                remove_if_exists(destination)?;
+                symlink_file(&source.canonicalize()?, destination)
+            },
+            Operation::Reflink => match reflink(source, destination) {
+                Err(e) if e.kind() == ErrorKind::Unsupported => {
+                    warn!("Couldn't reflink {:?} ({}), copying instead", source, e);
+                    copy(source, destination).map(|_| ())
+                },
+                res => res,
+            },
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn move_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    match rename(source, destination) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            info!("{:?} is on another filesystem to {:?}, copying instead", source, destination);
+            copy(source, destination)?;
+            if let Err(e) = verify_copy(source, destination) {
+                remove_if_exists(destination)?;
+                return Err(e)
+            }
+            remove_file(source)
+        },
+        res => res,
+    }
+}
+
+/// Checks that `destination` holds exactly the same bytes as `source`, so that the source can
+/// safely be deleted.
+pub fn verify_copy(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let mismatch = || Error::new(
+        ErrorKind::InvalidData,
+        format!("Copy of {:?} at {:?} doesn't match the original", source, destination),
+    );
+    let source_file = File::open(source)?;
+    let destination_file = File::open(destination)?;
+    if source_file.metadata()?.len() != destination_file.metadata()?.len() {
+        return Err(mismatch())
+    }
+    let mut source_reader = BufReader::new(source_file);
+    let mut destination_reader = BufReader::new(destination_file);
+    let mut source_buf = [0; 8192];
+    let mut destination_buf = [0; 8192];
+    loop {
+        let read = source_reader.read(&mut source_buf)?;
+        if read == 0 {
+            return Ok(())
+        }
+        destination_reader.read_exact(&mut destination_buf[..read])?;
+        if source_buf[..read] != destination_buf[..read] {
+            return Err(mismatch())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(source, destination)
+}
+
+#[cfg(not(unix))]
+fn symlink_file(_source: &Path, _destination: &Path) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "Symlinks are only supported on Unix"))
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source_file = File::open(source)?;
+    let destination_file = File::create(destination)?;
+    // SAFETY: both file descriptors are valid for the duration of the call, and FICLONE takes
+    // the source descriptor as its argument
+    let res = unsafe {
+        libc::ioctl(destination_file.as_raw_fd(), libc::FICLONE, source_file.as_raw_fd())
+    };
+    if res == 0 {
+        return Ok(())
+    }
+    let e = Error::last_os_error();
+    drop(destination_file);
+    remove_if_exists(destination)?;
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) => {
+            Err(Error::new(ErrorKind::Unsupported, e))
+        },
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "Reflinks are only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, read_link, symlink_metadata, write};
+    use std::os::unix::fs::MetadataExt;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn setup_source() -> (tempfile::TempDir, tempfile::TempDir, std::path::PathBuf) {
+        let indir = tempdir().unwrap();
+        let outdir = tempdir().unwrap();
+        let source = indir.as_ref().join("ABCD.mp3");
+        write(&source, b"song data").unwrap();
+        (indir, outdir, source)
+    }
+
+    #[test]
+    fn move_operation() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        Operation::Move.perform(&source, &destination).unwrap();
+        assert!(!source.try_exists().unwrap());
+        assert_eq!(read(&destination).unwrap(), b"song data");
+    }
+
+    #[test]
+    fn copy_operation() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        Operation::Copy.perform(&source, &destination).unwrap();
+        assert_eq!(read(&source).unwrap(), b"song data");
+        assert_eq!(read(&destination).unwrap(), b"song data");
+    }
+
+    #[test]
+    fn hardlink_operation() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        Operation::Hardlink.perform(&source, &destination).unwrap();
+        let source_inode = source.metadata().unwrap().ino();
+        assert_eq!(destination.metadata().unwrap().ino(), source_inode);
+    }
+
+    #[test]
+    fn symlink_operation() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        Operation::Symlink.perform(&source, &destination).unwrap();
+        assert!(symlink_metadata(&destination).unwrap().file_type().is_symlink());
+        assert_eq!(read_link(&destination).unwrap(), source.canonicalize().unwrap());
+        assert_eq!(read(&destination).unwrap(), b"song data");
+    }
+
+    #[test]
+    fn reflink_operation_falls_back_to_copy() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        Operation::Reflink.perform(&source, &destination).unwrap();
+        assert_eq!(read(&source).unwrap(), b"song data");
+        assert_eq!(read(&destination).unwrap(), b"song data");
+    }
+
+    #[test]
+    fn verify_copy_detects_mismatch() {
+        let (_indir, outdir, source) = setup_source();
+        let destination = outdir.as_ref().join("Song.mp3");
+        write(&destination, b"song dat4").unwrap();
+        let res = verify_copy(&source, &destination);
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::InvalidData));
+        write(&destination, b"song data").unwrap();
+        assert!(verify_copy(&source, &destination).is_ok());
+    }
+}
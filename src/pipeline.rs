@@ -0,0 +1,318 @@
+//! The stages an organize run is made of, for running them separately or swapping one out:
+//!
+//! 1. [`discover`] finds the files in the input directory.
+//! 2. [`read_song_files`] reads their metadata as [`SongFile`]s.
+//! 3. [`Naming`] works out the destination of every song from their [`TrackMetadata`] all
+//!    together, giving [`NamedSong`]s.
+//! 4. [`find_duplicates`](crate::dedup::find_duplicates) optionally picks out the named songs
+//!    with the same audio as another, which the planner sets aside.
+//! 5. [`Planner`] collects the named songs into a [`Plan`], resolving collisions.
+//! 6. [`execute_plan`](crate::execute_plan) carries the plan out, recording it in a journal.
+//! 7. [`final_paths`](crate::playlist::final_paths) optionally maps playlist entries through
+//!    the plan, so playlists from the input directory or an iTunesDB follow the moved files.
+//!
+//! Each stage only takes what the one before it gives, so e.g. metadata read from somewhere
+//! other than the tags can be named and planned just the same:
+//!
+//! ```no_run
+//! # use std::path::PathBuf;
+//! # use mp3_mover::config::Config;
+//! # use mp3_mover::metadata::TrackMetadata;
+//! # use mp3_mover::pipeline::{Naming, Planner};
+//! # fn organize(config: &Config) -> Result<(), mp3_mover::error::MoverError> {
+//! let path = PathBuf::from("/in/F00/ABCD.mp3");
+//! let metadata = TrackMetadata {
+//!     artist: Some(String::from("Artist")),
+//!     album: Some(String::from("Album")),
+//!     title: Some(String::from("Song")),
+//!     ..TrackMetadata::default()
+//! };
+//! let mut planner = Planner::new(config);
+//! for named_song in Naming::from_config(config).name_songs(vec![(path, metadata)]) {
+//!     planner.add(named_song);
+//! }
+//! let plan = planner.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::album::AlbumGroups;
+use crate::collision::CollisionError;
+use crate::config::Config;
+use crate::dedup::{DUPLICATES_DIR, DuplicatePolicy};
+use crate::discover::{WalkOptions, find_files};
+use crate::error::MoverError;
+use crate::infer::PathPattern;
+use crate::itunesdb::ITunesDb;
+use crate::metadata::TrackMetadata;
+use crate::plan::{Plan, SkipReason};
+use crate::sanitize::Sanitizer;
+use crate::tags::{SongFile, read_dirs};
+use crate::template::{Field, PathTemplate};
+
+/// Finds the files to organise in the input directory, song files or not, in a stable order.
+pub fn discover(input_path: &Path, walk_options: &WalkOptions) -> Result<Vec<PathBuf>, MoverError> {
+    find_files(input_path, walk_options)
+        .map_err(|source| MoverError::Discovery { path: input_path.to_path_buf(), source })
+}
+
+/// Reads the tags of `file_paths` on up to `jobs` threads, or one per CPU if `None`, returning
+/// them grouped by directory in the order the directories were first seen in.
+pub fn read_song_files(file_paths: Vec<PathBuf>, jobs: Option<NonZeroUsize>) -> Vec<SongFile> {
+    let jobs = jobs
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map(NonZeroUsize::get)
+        .unwrap_or(1);
+    let mut all_song_files = Vec::new();
+    read_dirs(&group_by_dir(file_paths), jobs, &mut |song_files| {
+        for song_file in song_files.iter() {
+            let path = &song_file.path;
+            match &song_file.metadata {
+                Err(SkipReason::NoExtension) => info!(
+                    "The file {:?} has no file extension so the appropriate tag cannot be inferred, skipping",
+                    path,
+                ),
+                Err(SkipReason::UnsupportedExtension(_)) => info!(
+                    "Unsupported file extension encountered for file {:?}, skipping",
+                    path,
+                ),
+                Err(SkipReason::UnreadableTag(e)) => {
+                    warn!("Couldn't read tag of song file {:?}: {}", path, e)
+                },
+                _ => {},
+            }
+        }
+        all_song_files.extend(song_files);
+    });
+    all_song_files
+}
+
+/// Splits `file_paths` by the directory they're in, keeping the order the directories were
+/// first seen in.
+fn group_by_dir(file_paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut group_indices: HashMap<PathBuf, usize> = HashMap::new();
+    for path in file_paths {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let i = *group_indices.entry(dir).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[i].push(path);
+    }
+    groups
+}
+
+/// A song file along with where it's to be placed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedSong {
+    pub path: PathBuf,
+    /// `None` if the song file's tag couldn't be read
+    pub metadata: Option<TrackMetadata>,
+    /// Destination relative to the output directory, or why the song file has none
+    pub destination: Result<PathBuf, SkipReason>,
+}
+
+/// What decides where song files are placed: the template and sanitizer, with an iPod's
+/// iTunesDB and then the path pattern filling in fields missing from their tags, and
+/// compilations grouped into the various artists directory.
+#[derive(Debug, Clone, Copy)]
+pub struct Naming<'n> {
+    pub template: &'n PathTemplate,
+    pub sanitizer: &'n Sanitizer,
+    pub path_pattern: Option<&'n PathPattern>,
+    pub various_artists: &'n str,
+    pub itunes_db: Option<&'n ITunesDb>,
+}
+
+impl<'n> Naming<'n> {
+    pub fn from_config(config: &'n Config) -> Naming<'n> {
+        Naming {
+            template: &config.template,
+            sanitizer: &config.sanitizer,
+            path_pattern: config.path_pattern.as_ref(),
+            various_artists: &config.various_artists,
+            itunes_db: config.itunes_db.as_ref(),
+        }
+    }
+
+    /// Names the song files read, including those whose tags couldn't be read, which are named
+    /// from the iTunesDB instead if it knows them.
+    pub fn name_song_files(&self, song_files: Vec<SongFile>) -> Vec<NamedSong> {
+        let mut named_songs = Vec::new();
+        let mut songs = Vec::new();
+        for song_file in song_files {
+            let in_itunes_db = self.itunes_db
+                .is_some_and(|db| db.track_for_path(&song_file.path).is_some());
+            match (song_file.metadata, in_itunes_db) {
+                (Ok(metadata), _) => songs.push((song_file.path, metadata)),
+                (Err(SkipReason::UnreadableTag(e)), true) => {
+                    info!("Couldn't read tag of {:?} ({}), using iTunesDB", song_file.path, e);
+                    // Everything is filled in from the iTunesDB when the songs are named
+                    songs.push((song_file.path, TrackMetadata::default()));
+                },
+                (Err(reason), _) => named_songs.push(NamedSong {
+                    path: song_file.path,
+                    metadata: None,
+                    destination: Err(reason),
+                }),
+            }
+        }
+        named_songs.extend(self.name_songs(songs));
+        named_songs
+    }
+
+    /// Names songs together, so that each album's songs are kept in one place even when they're
+    /// spread over several directories, as they are in an iPod's `Fxx` ones. Fields missing
+    /// from their metadata are filled in first from the iTunesDB and then from their paths, if
+    /// there's a path pattern, before any albums are grouped.
+    pub fn name_songs(&self, songs: Vec<(PathBuf, TrackMetadata)>) -> Vec<NamedSong> {
+        let (paths, mut metadata): (Vec<PathBuf>, Vec<TrackMetadata>) = songs.into_iter().unzip();
+        if let Some(itunes_db) = self.itunes_db {
+            for (path, metadata) in paths.iter().zip(metadata.iter_mut()) {
+                if let Some(db_track) = itunes_db.track_for_path(path) {
+                    metadata.fill_from(&db_track.metadata);
+                }
+            }
+        }
+        if let Some(path_pattern) = self.path_pattern {
+            for (path, metadata) in paths.iter().zip(metadata.iter_mut()) {
+                metadata.fill_from_path(path_pattern, path);
+            }
+        }
+        let album_groups = AlbumGroups::new(&metadata);
+        for metadata in metadata.iter_mut() {
+            album_groups.assign(metadata, self.various_artists);
+        }
+        paths.into_iter().zip(metadata).map(|(path, metadata)| {
+            let destination = self.template.render(&metadata, &path, self.sanitizer)
+                .map_err(|e| {
+                    info!("Song file {:?} has missing field {}", path, e.missing_field);
+                    SkipReason::MissingField(e.missing_field)
+                });
+            NamedSong { path, metadata: Some(metadata), destination }
+        }).collect()
+    }
+}
+
+/// Collects named songs into a plan for the output directory, moving those that can't be
+/// placed into the unsorted dir if there is one.
+pub struct Planner<'c> {
+    config: &'c Config,
+    plan: Plan,
+    collision_error: Option<CollisionError>,
+}
+
+impl<'c> Planner<'c> {
+    pub fn new(config: &'c Config) -> Planner<'c> {
+        let mut plan = Plan::new();
+        plan.operation = config.operation;
+        Planner { config, plan, collision_error: None }
+    }
+
+    /// Adds the song file's move, or its skip. Once a collision has failed the plan, any
+    /// further song files are ignored.
+    pub fn add(&mut self, named_song: NamedSong) {
+        if self.collision_error.is_some() {
+            return
+        }
+        let file_path = named_song.path.as_path();
+        let collision_policy = self.config.collision_policy;
+        let res = match named_song.destination {
+            Ok(relative_path) => {
+                let inferred = named_song.metadata.as_ref()
+                    .map(|metadata| metadata.inferred.as_slice())
+                    .unwrap_or_default();
+                if !inferred.is_empty() {
+                    self.plan.add_inferred(file_path.to_path_buf(), field_names(inferred));
+                }
+                let destination = Path::new(&self.config.output_path).join(relative_path);
+                if let Some(dir) = destination.parent() {
+                    self.plan.add_dir(dir.to_path_buf());
+                }
+                self.plan.add_move(file_path.to_path_buf(), destination, collision_policy)
+            },
+            Err(reason) => {
+                let Some(destination) = self.unsorted_destination(file_path, &reason) else {
+                    self.plan.add_skipped(file_path.to_path_buf(), reason);
+                    return
+                };
+                if let Some(dir) = destination.parent() {
+                    self.plan.add_dir(dir.to_path_buf());
+                }
+                self.plan.add_unsorted(file_path.to_path_buf(), destination, reason, collision_policy)
+            },
+        };
+        if let Err(e) = res {
+            self.collision_error = Some(e);
+        }
+    }
+
+    /// Sets aside a song file with the same audio as `kept`, leaving it where it is or moving it
+    /// into the duplicates dir, keeping its path relative to the input directory, depending on
+    /// the duplicate policy.
+    pub fn add_duplicate(&mut self, path: PathBuf, kept: PathBuf) {
+        if self.collision_error.is_some() {
+            return
+        }
+        let reason = SkipReason::Duplicate(kept);
+        if self.config.duplicate_policy != Some(DuplicatePolicy::MoveExtras) {
+            self.plan.add_skipped(path, reason);
+            return
+        }
+        let input_path = Path::new(&self.config.input_path);
+        let relative_path = path.strip_prefix(input_path).unwrap_or(&path);
+        let destination = Path::new(&self.config.output_path)
+            .join(DUPLICATES_DIR)
+            .join(relative_path);
+        if let Some(dir) = destination.parent() {
+            self.plan.add_dir(dir.to_path_buf());
+        }
+        let collision_policy = self.config.collision_policy;
+        if let Err(e) = self.plan.add_unsorted(path, destination, reason, collision_policy) {
+            self.collision_error = Some(e);
+        }
+    }
+
+    /// Where in the unsorted dir a song file skipped for `reason` goes, keeping its path
+    /// relative to the input directory, if anywhere.
+    fn unsorted_destination(&self, file_path: &Path, reason: &SkipReason) -> Option<PathBuf> {
+        let input_path = Path::new(&self.config.input_path);
+        // What an earlier run put in the unsorted dir stays put until it's been fixed
+        self.config.unsorted_dir.as_deref()
+            .map(Path::new)
+            .filter(|dir| !file_path.starts_with(dir))
+            .zip(unsorted_subdir(reason))
+            .map(|(dir, subdir)| {
+                let relative_path = file_path.strip_prefix(input_path).unwrap_or(file_path);
+                dir.join(subdir).join(relative_path)
+            })
+    }
+
+    pub fn finish(self) -> Result<Plan, MoverError> {
+        match self.collision_error {
+            Some(e) => Err(e.into()),
+            None => Ok(self.plan),
+        }
+    }
+}
+
+pub(crate) fn field_names(fields: &[Field]) -> Vec<String> {
+    fields.iter().map(|field| String::from(field.name())).collect()
+}
+
+/// Subdirectory of the unsorted dir that song files skipped for `reason` are moved into, if
+/// they're moved there at all.
+fn unsorted_subdir(reason: &SkipReason) -> Option<String> {
+    match reason {
+        SkipReason::MissingField(field) => Some(format!("missing-{}", field)),
+        SkipReason::UnreadableTag(_) => Some(String::from("unreadable-tag")),
+        _ => None,
+    }
+}
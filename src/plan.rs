@@ -0,0 +1,500 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Display;
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+
+use crate::collision::{
+    Collision, CollisionError, CollisionPolicy, CollisionResolution, CollisionWith,
+    numbered_destination,
+};
+use crate::error::MoverError;
+use crate::operation::Operation;
+use crate::tags::read_file;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    MissingField(String),
+    UnreadableTag(String),
+    NoExtension,
+    UnsupportedExtension(String),
+    AlreadyInPlace,
+    Collision(PathBuf),
+    /// Has the same audio as the song file that was kept instead
+    Duplicate(PathBuf),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::MissingField(field) => write!(f, "missing field: {}", field),
+            SkipReason::UnreadableTag(reason) => write!(f, "unreadable tag: {}", reason),
+            SkipReason::NoExtension => write!(f, "no file extension"),
+            SkipReason::UnsupportedExtension(ext) => {
+                write!(f, "unsupported file extension: {}", ext)
+            },
+            SkipReason::AlreadyInPlace => write!(f, "already at its destination"),
+            SkipReason::Collision(destination) => {
+                write!(f, "destination already taken: {}", destination.display())
+            },
+            SkipReason::Duplicate(kept) => write!(f, "duplicate of {}", kept.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedMove {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Whether a file already at the destination should be replaced
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+impl SkippedFile {
+    /// The error to report for the file, if it was skipped because of a problem with its tag
+    /// rather than by choice.
+    pub fn error(&self) -> Option<MoverError> {
+        match &self.reason {
+            SkipReason::MissingField(field) => Some(MoverError::MissingField {
+                path: self.path.clone(),
+                field: field.clone(),
+            }),
+            SkipReason::UnreadableTag(reason) => Some(MoverError::TagRead {
+                path: self.path.clone(),
+                reason: reason.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Tag fields of a song file that were read from its path instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InferredFields {
+    pub path: PathBuf,
+    pub fields: Vec<String>,
+}
+
+/// Everything a run would do to the filesystem, computed up front so that it can be inspected
+/// (or printed, in dry-run mode) before any file is touched.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// How each song file is placed at its destination
+    pub operation: Operation,
+    pub dirs: BTreeSet<PathBuf>,
+    pub moves: Vec<PlannedMove>,
+    pub skipped: Vec<SkippedFile>,
+    /// Song files that can't be organised and are moved to the unsorted dir instead, or that
+    /// are duplicates moved into the duplicates dir, along with why. They also appear in
+    /// `moves`.
+    pub unsorted: Vec<SkippedFile>,
+    pub inferred: Vec<InferredFields>,
+    pub collisions: Vec<Collision>,
+    /// Index of the move to each destination, keyed by `destination_key`
+    destinations: HashMap<PathBuf, usize>,
+}
+
+fn file_size(path: &Path) -> u64 {
+    metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+fn bitrate(path: &Path) -> Option<u32> {
+    read_file(path).ok().and_then(|metadata| metadata.bitrate)
+}
+
+/// What destinations are compared by. FAT, exFAT and Samba shares don't tell "Intro.mp3" and
+/// "intro.mp3" apart, so destinations that only differ in case count as the same.
+fn destination_key(destination: &Path) -> PathBuf {
+    PathBuf::from(destination.to_string_lossy().to_lowercase())
+}
+
+impl Plan {
+    pub fn new() -> Plan {
+        Plan::default()
+    }
+
+    pub fn add_dir(&mut self, dir: PathBuf) {
+        if !dir.exists() {
+            self.dirs.insert(dir);
+        }
+    }
+
+    /// Adds the move of `source` to `destination`, resolving any clash with a file already at
+    /// the destination or with an earlier move in the plan according to `policy`.
+    pub fn add_move(
+        &mut self,
+        source: PathBuf,
+        destination: PathBuf,
+        policy: CollisionPolicy,
+    ) -> Result<(), CollisionError> {
+        if source == destination {
+            self.add_skipped(source, SkipReason::AlreadyInPlace);
+            return Ok(())
+        }
+        // Only changing the case of the name finds the song file itself on a case insensitive
+        // filesystem
+        let is_renamed_in_place = destination_key(&source) == destination_key(&destination);
+        let with = match self.destinations.get(&destination_key(&destination)) {
+            Some(&i) => CollisionWith::PlannedMove(self.moves[i].source.clone()),
+            None if destination.exists() && !is_renamed_in_place => CollisionWith::ExistingFile,
+            None => {
+                self.push_move(source, destination, false);
+                return Ok(())
+            },
+        };
+
+        let replace = match policy {
+            CollisionPolicy::Fail => return Err(CollisionError { source, destination }),
+            CollisionPolicy::Skip => false,
+            CollisionPolicy::Overwrite => true,
+            CollisionPolicy::KeepLarger => {
+                let other_size = match &with {
+                    CollisionWith::ExistingFile => file_size(&destination),
+                    CollisionWith::PlannedMove(other_source) => file_size(other_source),
+                };
+                file_size(&source) > other_size
+            },
+            CollisionPolicy::KeepHigherBitrate => {
+                let other = match &with {
+                    CollisionWith::ExistingFile => destination.as_path(),
+                    CollisionWith::PlannedMove(other_source) => other_source.as_path(),
+                };
+                (bitrate(&source), file_size(&source)) > (bitrate(other), file_size(other))
+            },
+            CollisionPolicy::Rename => {
+                let renamed = (2..)
+                    .map(|n| numbered_destination(&destination, n))
+                    .find(|candidate| {
+                        !candidate.exists()
+                            && !self.destinations.contains_key(&destination_key(candidate))
+                    })
+                    .expect("There are always more numbered names to try");
+                self.collisions.push(Collision {
+                    source: source.clone(),
+                    destination,
+                    with,
+                    resolution: CollisionResolution::Renamed(renamed.clone()),
+                });
+                self.push_move(source, renamed, false);
+                return Ok(())
+            },
+        };
+
+        if !replace {
+            self.collisions.push(Collision {
+                source: source.clone(),
+                destination: destination.clone(),
+                with,
+                resolution: CollisionResolution::Skipped,
+            });
+            self.add_skipped(source, SkipReason::Collision(destination));
+            return Ok(())
+        }
+        self.collisions.push(Collision {
+            source: source.clone(),
+            destination: destination.clone(),
+            with: with.clone(),
+            resolution: CollisionResolution::Replaced,
+        });
+        match with {
+            CollisionWith::ExistingFile => self.push_move(source, destination, true),
+            CollisionWith::PlannedMove(other_source) => {
+                // Moving both would clobber the earlier file, so it stays where it is instead
+                let i = self.destinations[&destination_key(&destination)];
+                self.moves[i].source = source;
+                self.add_skipped(other_source, SkipReason::Collision(destination));
+            },
+        }
+        Ok(())
+    }
+
+    fn push_move(&mut self, source: PathBuf, destination: PathBuf, overwrite: bool) {
+        self.destinations.insert(destination_key(&destination), self.moves.len());
+        self.moves.push(PlannedMove { source, destination, overwrite });
+    }
+
+    pub fn add_skipped(&mut self, path: PathBuf, reason: SkipReason) {
+        self.skipped.push(SkippedFile { path, reason });
+    }
+
+    pub fn add_inferred(&mut self, path: PathBuf, fields: Vec<String>) {
+        self.inferred.push(InferredFields { path, fields });
+    }
+
+    /// Plans the move of a song file that can't be organised to `destination` in the unsorted
+    /// dir.
+    pub fn add_unsorted(
+        &mut self,
+        source: PathBuf,
+        destination: PathBuf,
+        reason: SkipReason,
+        policy: CollisionPolicy,
+    ) -> Result<(), CollisionError> {
+        self.add_move(source.clone(), destination, policy)?;
+        // When the song file isn't going to be moved after all, it's the last one skipped
+        let was_skipped = self.skipped.last().is_some_and(|skipped| skipped.path == source);
+        if !was_skipped {
+            self.unsorted.push(SkippedFile { path: source, reason });
+        }
+        Ok(())
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Directories to create ({}):", self.dirs.len())?;
+        for dir in self.dirs.iter() {
+            writeln!(f, "  {}", dir.display())?;
+        }
+        writeln!(f, "Files to {} ({}):", self.operation.verb(), self.moves.len())?;
+        for planned in self.moves.iter() {
+            writeln!(f, "  {} -> {}", planned.source.display(), planned.destination.display())?;
+        }
+        writeln!(f, "Files to skip ({}):", self.skipped.len())?;
+        for skipped in self.skipped.iter() {
+            writeln!(f, "  {} ({})", skipped.path.display(), skipped.reason)?;
+        }
+        writeln!(f, "Unsorted files ({}):", self.unsorted.len())?;
+        for unsorted in self.unsorted.iter() {
+            writeln!(f, "  {} ({})", unsorted.path.display(), unsorted.reason)?;
+        }
+        writeln!(f, "Fields inferred from paths ({}):", self.inferred.len())?;
+        for inferred in self.inferred.iter() {
+            writeln!(f, "  {} ({})", inferred.path.display(), inferred.fields.join(", "))?;
+        }
+        writeln!(f, "Collisions ({}):", self.collisions.len())?;
+        for collision in self.collisions.iter() {
+            writeln!(f, "  {}", collision)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, write};
+    use std::path::PathBuf;
+
+    use id3::TagLike;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn existing_dir_not_added_to_plan() {
+        let outdir = tempdir().unwrap();
+        let mut plan = Plan::new();
+        plan.add_dir(outdir.as_ref().to_path_buf());
+        plan.add_dir(outdir.as_ref().join("Artist"));
+        assert_eq!(plan.dirs.len(), 1);
+        assert!(plan.dirs.contains(&outdir.as_ref().join("Artist")));
+    }
+
+    #[test]
+    fn same_dir_only_added_once() {
+        let mut plan = Plan::new();
+        plan.add_dir(PathBuf::from("/nonexistent/Artist/Album"));
+        plan.add_dir(PathBuf::from("/nonexistent/Artist/Album"));
+        assert_eq!(plan.dirs.len(), 1);
+    }
+
+    #[test]
+    fn plan_display_lists_every_entry() {
+        let mut plan = Plan::new();
+        plan.add_dir(PathBuf::from("/out/Artist/Album"));
+        plan.add_move(
+            PathBuf::from("/in/F00/ABCD.mp3"),
+            PathBuf::from("/out/Artist/Album/Song.mp3"),
+            CollisionPolicy::Rename,
+        ).unwrap();
+        plan.add_move(
+            PathBuf::from("/in/F00/EFGH.mp3"),
+            PathBuf::from("/out/Artist/Album/Song.mp3"),
+            CollisionPolicy::Rename,
+        ).unwrap();
+        plan.add_skipped(
+            PathBuf::from("/in/F00/IJKL.mp3"),
+            SkipReason::MissingField(String::from("artist")),
+        );
+        plan.add_unsorted(
+            PathBuf::from("/in/F00/MNOP.mp3"),
+            PathBuf::from("/unsorted/missing-album/F00/MNOP.mp3"),
+            SkipReason::MissingField(String::from("album")),
+            CollisionPolicy::Rename,
+        ).unwrap();
+        plan.add_inferred(PathBuf::from("/in/F00/ABCD.mp3"), vec![String::from("album")]);
+        let expected = "\
+Directories to create (1):
+  /out/Artist/Album
+Files to move (3):
+  /in/F00/ABCD.mp3 -> /out/Artist/Album/Song.mp3
+  /in/F00/EFGH.mp3 -> /out/Artist/Album/Song (2).mp3
+  /in/F00/MNOP.mp3 -> /unsorted/missing-album/F00/MNOP.mp3
+Files to skip (1):
+  /in/F00/IJKL.mp3 (missing field: artist)
+Unsorted files (1):
+  /in/F00/MNOP.mp3 (missing field: album)
+Fields inferred from paths (1):
+  /in/F00/ABCD.mp3 (album)
+Collisions (1):
+  /in/F00/EFGH.mp3 -> /out/Artist/Album/Song.mp3 clashed with /in/F00/ABCD.mp3; renamed to /out/Artist/Album/Song (2).mp3
+";
+        assert_eq!(plan.to_string(), expected);
+    }
+
+    /// Creates two song files of different sizes in `indir` and an existing file at
+    /// `outdir/Song.mp3` which is bigger than the first and smaller than the second.
+    fn setup_collision(indir: &Path, outdir: &Path) -> (PathBuf, PathBuf, PathBuf) {
+        let small = indir.join("A.mp3");
+        let large = indir.join("B.mp3");
+        let destination = outdir.join("Song.mp3");
+        write(&small, [0; 1]).unwrap();
+        write(&large, [0; 3]).unwrap();
+        write(&destination, [0; 2]).unwrap();
+        (small, large, destination)
+    }
+
+    #[test]
+    fn skip_policy() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, _, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        let mut plan = Plan::new();
+        plan.add_move(small.clone(), destination.clone(), CollisionPolicy::Skip).unwrap();
+        assert!(plan.moves.is_empty());
+        assert_eq!(plan.skipped[0].path, small);
+        assert_eq!(plan.skipped[0].reason, SkipReason::Collision(destination));
+        assert_eq!(plan.collisions[0].with, CollisionWith::ExistingFile);
+        assert_eq!(plan.collisions[0].resolution, CollisionResolution::Skipped);
+    }
+
+    #[test]
+    fn overwrite_policy() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, large, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        let mut plan = Plan::new();
+        plan.add_move(small.clone(), destination.clone(), CollisionPolicy::Overwrite).unwrap();
+        plan.add_move(large.clone(), destination.clone(), CollisionPolicy::Overwrite).unwrap();
+        // The second move replaces the first, which is left in place
+        assert_eq!(
+            plan.moves,
+            vec![PlannedMove { source: large, destination: destination.clone(), overwrite: true }],
+        );
+        assert_eq!(plan.skipped[0].path, small);
+        assert_eq!(plan.collisions.len(), 2);
+        assert_eq!(plan.collisions[0].with, CollisionWith::ExistingFile);
+        assert_eq!(plan.collisions[1].with, CollisionWith::PlannedMove(small));
+    }
+
+    #[test]
+    fn rename_policy_skips_taken_names() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, large, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        File::create(outdir.as_ref().join("Song (2).mp3")).unwrap();
+        let mut plan = Plan::new();
+        plan.add_move(small, destination.clone(), CollisionPolicy::Rename).unwrap();
+        plan.add_move(large, destination, CollisionPolicy::Rename).unwrap();
+        assert_eq!(plan.moves[0].destination, outdir.as_ref().join("Song (3).mp3"));
+        assert_eq!(plan.moves[1].destination, outdir.as_ref().join("Song (4).mp3"));
+        assert!(!plan.moves.iter().any(|planned| planned.overwrite));
+    }
+
+    #[test]
+    fn keep_larger_policy() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, large, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        let mut plan = Plan::new();
+        plan.add_move(small.clone(), destination.clone(), CollisionPolicy::KeepLarger).unwrap();
+        plan.add_move(large.clone(), destination.clone(), CollisionPolicy::KeepLarger).unwrap();
+        assert_eq!(
+            plan.moves,
+            vec![PlannedMove { source: large, destination, overwrite: true }],
+        );
+        assert_eq!(plan.skipped[0].path, small);
+        assert_eq!(plan.collisions[0].resolution, CollisionResolution::Skipped);
+        assert_eq!(plan.collisions[1].resolution, CollisionResolution::Replaced);
+    }
+
+    /// Writes a tagged MP3 file with `audio_len` bytes of audio at the MPEG-1 Layer III bitrate
+    /// with index `bitrate_index`.
+    fn write_mp3(path: &Path, bitrate_index: u8, audio_len: usize) {
+        let mut audio = vec![0xff, 0xfb, bitrate_index << 4, 0x44];
+        audio.resize(audio_len, 0);
+        write(path, &audio).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_title("Song");
+        tag.write_to_path(path, id3::Version::Id3v24).unwrap();
+    }
+
+    #[test]
+    fn keep_higher_bitrate_policy() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let small = indir.as_ref().join("A.mp3");
+        let large = indir.as_ref().join("B.mp3");
+        let destination = outdir.as_ref().join("Song.mp3");
+        // 320 kbit/s
+        write_mp3(&small, 0b1110, 16_000);
+        // 128 kbit/s, with more audio
+        write_mp3(&large, 0b1001, 48_000);
+        write_mp3(&destination, 0b1001, 32_000);
+        let mut plan = Plan::new();
+        let policy = CollisionPolicy::KeepHigherBitrate;
+        plan.add_move(large.clone(), destination.clone(), policy).unwrap();
+        plan.add_move(small.clone(), destination.clone(), policy).unwrap();
+        assert_eq!(
+            plan.moves,
+            vec![PlannedMove { source: small, destination, overwrite: true }],
+        );
+        assert_eq!(plan.skipped[0].path, large);
+        // The larger file replaces the existing one as they have the same bitrate
+        assert_eq!(plan.collisions[0].resolution, CollisionResolution::Replaced);
+        assert_eq!(plan.collisions[1].resolution, CollisionResolution::Replaced);
+    }
+
+    #[test]
+    fn destinations_differing_in_case_collide() {
+        let mut plan = Plan::new();
+        let destination = PathBuf::from("/out/Artist/Album/Intro.mp3");
+        plan.add_move(PathBuf::from("/in/A.mp3"), destination, CollisionPolicy::Rename).unwrap();
+        let other_destination = PathBuf::from("/out/artist/Album/intro.mp3");
+        plan.add_move(PathBuf::from("/in/B.mp3"), other_destination, CollisionPolicy::Rename)
+            .unwrap();
+        assert_eq!(plan.moves[1].destination, PathBuf::from("/out/artist/Album/intro (2).mp3"));
+        assert_eq!(plan.collisions.len(), 1);
+    }
+
+    #[test]
+    fn fail_policy() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, _, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        let mut plan = Plan::new();
+        let res = plan.add_move(small, destination, CollisionPolicy::Fail);
+        assert!(res.is_err());
+        assert!(plan.moves.is_empty());
+    }
+
+    #[test]
+    fn unsorted_file_skipped_on_collision_isnt_listed_as_unsorted() {
+        let (indir, outdir) = (tempdir().unwrap(), tempdir().unwrap());
+        let (small, _, destination) = setup_collision(indir.as_ref(), outdir.as_ref());
+        let mut plan = Plan::new();
+        let reason = SkipReason::UnreadableTag(String::from("NoTag"));
+        plan.add_unsorted(small.clone(), destination, reason, CollisionPolicy::Skip).unwrap();
+        assert!(plan.moves.is_empty());
+        assert!(plan.unsorted.is_empty());
+        assert_eq!(plan.skipped[0].path, small);
+    }
+
+    #[test]
+    fn source_already_at_destination() {
+        let mut plan = Plan::new();
+        let path = PathBuf::from("/out/Artist/Album/Song.mp3");
+        plan.add_move(path.clone(), path.clone(), CollisionPolicy::Fail).unwrap();
+        assert!(plan.moves.is_empty());
+        assert_eq!(plan.skipped[0].reason, SkipReason::AlreadyInPlace);
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::collision::numbered_destination;
+use crate::itunesdb::ITunesDb;
+use crate::plan::{Plan, SkipReason};
+use crate::sanitize::Sanitizer;
+
+/// Extension of the M3U playlists written, which are always UTF-8.
+pub const PLAYLIST_EXTENSION: &str = "m3u8";
+
+/// Extensions of the playlist formats found in the input directory that can be rewritten.
+pub const PLAYLIST_EXTENSIONS: [&str; 3] = ["m3u", "m3u8", "pls"];
+
+/// How the entries of written playlists point at their song files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaylistPaths {
+    /// Relative to the playlist's directory, so the library can be moved as a whole
+    Relative,
+    Absolute,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    pub duration: Option<Duration>,
+    /// Shown by players in place of the file name, e.g. "Artist - Title"
+    pub title: Option<String>,
+}
+
+/// A playlist to be written to `path`, along with the entries that couldn't be matched to a
+/// song file and so were left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Playlist {
+    pub path: PathBuf,
+    pub entries: Vec<PlaylistEntry>,
+    pub unresolved: Vec<String>,
+}
+
+/// Streams and other entries given as URLs rather than paths, which are kept as they are.
+fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.contains("://"))
+}
+
+impl Playlist {
+    /// The playlist in the format its extension calls for: PLS for `.pls`, and otherwise
+    /// extended M3U, which is always UTF-8 as the `.m3u8` extension requires.
+    pub fn contents(&self, paths: PlaylistPaths) -> std::io::Result<String> {
+        let is_pls = self.path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pls"));
+        match is_pls {
+            true => self.to_pls(paths),
+            false => self.to_m3u8(paths),
+        }
+    }
+
+    pub fn to_m3u8(&self, paths: PlaylistPaths) -> std::io::Result<String> {
+        let mut m3u8 = String::from("#EXTM3U\n");
+        for entry in self.entries.iter() {
+            if let Some(title) = &entry.title {
+                m3u8.push_str(&format!("#EXTINF:{},{}\n", length_secs(entry), title));
+            }
+            m3u8.push_str(&self.location(entry, paths)?);
+            m3u8.push('\n');
+        }
+        Ok(m3u8)
+    }
+
+    pub fn to_pls(&self, paths: PlaylistPaths) -> std::io::Result<String> {
+        let mut pls = String::from("[playlist]\n");
+        for (i, entry) in self.entries.iter().enumerate() {
+            let n = i + 1;
+            pls.push_str(&format!("File{}={}\n", n, self.location(entry, paths)?));
+            if let Some(title) = &entry.title {
+                pls.push_str(&format!("Title{}={}\n", n, title));
+            }
+            pls.push_str(&format!("Length{}={}\n", n, length_secs(entry)));
+        }
+        pls.push_str(&format!("NumberOfEntries={}\nVersion=2\n", self.entries.len()));
+        Ok(pls)
+    }
+
+    /// Where `entry` is written to point at, relative to the playlist's own directory or
+    /// absolute.
+    fn location(&self, entry: &PlaylistEntry, paths: PlaylistPaths) -> std::io::Result<String> {
+        if is_url(&entry.path) {
+            return Ok(entry.path.to_string_lossy().to_string())
+        }
+        let path = std::path::absolute(&entry.path)?;
+        let path = match paths {
+            PlaylistPaths::Relative => {
+                let dir = self.path.parent().unwrap_or(Path::new(""));
+                relative_path(&std::path::absolute(dir)?, &path)
+            },
+            PlaylistPaths::Absolute => path,
+        };
+        Ok(path.to_string_lossy().to_string())
+    }
+}
+
+/// Length of the entry in whole seconds, or -1 if unknown, as both M3U and PLS have it.
+fn length_secs(entry: &PlaylistEntry) -> i64 {
+    entry.duration.map(|duration| duration.as_secs() as i64).unwrap_or(-1)
+}
+
+/// `path` relative to `dir`, both being absolute, going up out of `dir` as far as needed.
+pub fn relative_path(dir: &Path, path: &Path) -> PathBuf {
+    let dir: Vec<Component> = dir.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = dir.iter().zip(path.iter()).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..dir.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// `path` with `.` components dropped and `..` ones taken out along with what they go up
+/// from, without looking at the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir
+                if matches!(normalized.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normalized.pop();
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Where each song file in the plan ends up once it has been carried out: its destination if
+/// it's placed there, or else where it already is. Duplicates end up wherever the copy that
+/// was kept does. Song files in `failed` are left where they are.
+pub fn final_paths(plan: &Plan, failed: &[&Path]) -> HashMap<PathBuf, PathBuf> {
+    let mut paths: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for planned_move in plan.moves.iter() {
+        let path = match failed.contains(&planned_move.source.as_path()) {
+            true => planned_move.source.clone(),
+            false => planned_move.destination.clone(),
+        };
+        paths.insert(planned_move.source.clone(), path);
+    }
+    for skipped in plan.skipped.iter() {
+        if !matches!(skipped.reason, SkipReason::Duplicate(_)) {
+            paths.insert(skipped.path.clone(), skipped.path.clone());
+        }
+    }
+    for skipped in plan.skipped.iter() {
+        if let SkipReason::Duplicate(kept) = &skipped.reason {
+            let path = paths.get(kept).cloned().unwrap_or_else(|| kept.clone());
+            paths.insert(skipped.path.clone(), path);
+        }
+    }
+    paths
+}
+
+/// The playlists of the iPod that `db` came from, other than the one of every track, to be
+/// written to `output_dir` with their entries pointing at where the song files in
+/// `final_paths` end up. Tracks whose file wasn't among the song files are left out.
+pub fn ipod_playlists(
+    db: &ITunesDb,
+    final_paths: &HashMap<PathBuf, PathBuf>,
+    output_dir: &Path,
+    sanitizer: &Sanitizer,
+) -> Vec<Playlist> {
+    let by_track: HashMap<u32, &PathBuf> = final_paths.iter()
+        .filter_map(|(source, path)| Some((db.track_for_path(source)?.id, path)))
+        .collect();
+    let mut playlists = Vec::new();
+    for db_playlist in db.playlists.iter().filter(|playlist| !playlist.master) {
+        let name = sanitizer.sanitize_value(&db_playlist.name);
+        let file_name = format!("{}.{}", name, PLAYLIST_EXTENSION);
+        let path = output_dir.join(sanitizer.sanitize_component(&file_name, true));
+        let mut entries = Vec::new();
+        let mut unresolved = Vec::new();
+        for &id in db_playlist.track_ids.iter() {
+            let track = db.track(id);
+            match (track, by_track.get(&id)) {
+                (Some(track), Some(&path)) => {
+                    let metadata = &track.metadata;
+                    let title = match (&metadata.artist, &metadata.title) {
+                        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+                        (None, Some(title)) => Some(title.clone()),
+                        _ => None,
+                    };
+                    let path = path.clone();
+                    entries.push(PlaylistEntry { path, duration: metadata.duration, title });
+                },
+                _ => {
+                    let location = track.and_then(|track| track.location.as_ref());
+                    let entry = match location {
+                        Some(location) => location.display().to_string(),
+                        None => format!("track {}", id),
+                    };
+                    warn!("Leaving {} out of playlist {:?}, its file wasn't found", entry, path);
+                    unresolved.push(entry);
+                },
+            }
+        }
+        playlists.push(Playlist { path, entries, unresolved });
+    }
+    playlists
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        let ext = ext.to_string_lossy().to_lowercase();
+        PLAYLIST_EXTENSIONS.contains(&ext.as_str())
+    })
+}
+
+/// A playlist's text, which is UTF-8 for `.m3u8` files but often Latin-1 for older formats.
+fn decode(bytes: Vec<u8>) -> String {
+    let text = String::from_utf8(bytes)
+        .unwrap_or_else(|e| e.into_bytes().into_iter().map(char::from).collect());
+    match text.strip_prefix('\u{feff}') {
+        Some(text) => text.to_string(),
+        None => text,
+    }
+}
+
+/// Turns an entry as written in a playlist in `dir` into a path, accepting the backslashes of
+/// playlists made on Windows.
+fn entry_path(entry: &str, dir: &Path) -> PathBuf {
+    let path = PathBuf::from(entry);
+    if is_url(&path) {
+        return path
+    }
+    dir.join(entry.replace('\\', "/"))
+}
+
+fn parse_m3u(text: &str, dir: &Path) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut info = None;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = extinf.split_once(',').map(|(secs, title)| {
+                let duration = secs.trim().parse::<u64>().ok().map(Duration::from_secs);
+                (duration, title.trim().to_string())
+            });
+        } else if !line.starts_with('#') {
+            let (duration, title) = info.take().unzip();
+            let title = title.filter(|title: &String| !title.is_empty());
+            let path = entry_path(line, dir);
+            entries.push(PlaylistEntry { path, duration: duration.flatten(), title });
+        }
+    }
+    entries
+}
+
+/// The `FileN`, `TitleN` and `LengthN` keys of a PLS playlist's Nth entry, which can come in
+/// any order.
+#[derive(Debug, Default)]
+struct PlsKeys {
+    file: Option<String>,
+    title: Option<String>,
+    duration: Option<Duration>,
+}
+
+fn parse_pls(text: &str, dir: &Path) -> Vec<PlaylistEntry> {
+    let mut entries: BTreeMap<u32, PlsKeys> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue
+        };
+        let split_at = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (field, n) = key.split_at(split_at);
+        let Ok(n) = n.parse() else {
+            continue
+        };
+        let entry = entries.entry(n).or_default();
+        match field.to_lowercase().as_str() {
+            "file" => entry.file = Some(value.to_string()),
+            "title" => entry.title = Some(value.to_string()).filter(|title| !title.is_empty()),
+            "length" => entry.duration = value.parse::<u64>().ok().map(Duration::from_secs),
+            _ => (),
+        }
+    }
+    entries.into_values()
+        .filter_map(|keys| {
+            let path = entry_path(&keys.file?, dir);
+            Some(PlaylistEntry { path, duration: keys.duration, title: keys.title })
+        })
+        .collect()
+}
+
+/// Reads the entries of the M3U or PLS playlist at `path`, resolving them against its
+/// directory.
+pub fn read_playlist(path: &Path) -> std::io::Result<Vec<PlaylistEntry>> {
+    let text = decode(read(path)?);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let is_pls = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pls"));
+    match is_pls {
+        true => Ok(parse_pls(&text, dir)),
+        false => Ok(parse_m3u(&text, dir)),
+    }
+}
+
+/// The playlist at `path` in the input directory, to be written to the same place in the
+/// output directory with its entries pointing at where the song files in `final_paths` end
+/// up. Entries for other files that exist are kept as they are, and the rest are left out.
+/// An `.m3u` playlist is written as `.m3u8`, since it's written as UTF-8 whatever it was read
+/// as and players take plain `.m3u` files to be Latin-1.
+pub fn rewritten_playlist(
+    path: &Path,
+    input_dir: &Path,
+    output_dir: &Path,
+    final_paths: &HashMap<PathBuf, PathBuf>,
+) -> std::io::Result<Playlist> {
+    let by_source: HashMap<PathBuf, &PathBuf> = final_paths.iter()
+        .map(|(source, path)| (normalize(source), path))
+        .collect();
+    // Playlists made on case-insensitive filesystems don't always match the case of the files
+    let by_lowercase_source: HashMap<String, &PathBuf> = by_source.iter()
+        .map(|(source, &path)| (source.to_string_lossy().to_lowercase(), path))
+        .collect();
+    let mut entries = Vec::new();
+    let mut unresolved = Vec::new();
+    for mut entry in read_playlist(path)? {
+        if is_url(&entry.path) {
+            entries.push(entry);
+            continue
+        }
+        let source = normalize(&entry.path);
+        let final_path = by_source.get(&source)
+            .or_else(|| by_lowercase_source.get(&source.to_string_lossy().to_lowercase()));
+        match final_path {
+            Some(&final_path) => entry.path = final_path.clone(),
+            None if source.is_file() => entry.path = source,
+            None => {
+                warn!("Leaving {:?} out of playlist {:?}, its file wasn't found", source, path);
+                unresolved.push(source.display().to_string());
+                continue
+            },
+        }
+        entries.push(entry);
+    }
+    let relative_path = path.strip_prefix(input_dir).unwrap_or(path);
+    let mut path = output_dir.join(relative_path);
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("m3u")) {
+        path.set_extension(PLAYLIST_EXTENSION);
+    }
+    Ok(Playlist { path, entries, unresolved })
+}
+
+/// Gives each of the playlists bound for the same path as an earlier one, such as an iPod
+/// playlist and one in the input directory with the same name, a numbered name so that it
+/// doesn't replace the other. Paths that only differ in case count as the same, as they do
+/// for song files.
+pub fn give_unique_paths(playlists: &mut [Playlist]) {
+    let key = |path: &Path| path.to_string_lossy().to_lowercase();
+    let mut taken: HashSet<String> = HashSet::new();
+    for playlist in playlists.iter_mut() {
+        if taken.contains(&key(&playlist.path)) {
+            let renamed = (2..)
+                .map(|n| numbered_destination(&playlist.path, n))
+                .find(|candidate| !taken.contains(&key(candidate)))
+                .expect("There are always more numbered names to try");
+            warn!("Another playlist is bound for {:?}, writing to {:?}", playlist.path, renamed);
+            playlist.path = renamed;
+        }
+        taken.insert(key(&playlist.path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use crate::collision::CollisionPolicy;
+    use crate::sanitize::SanitizeProfile;
+
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/iTunesDB");
+
+    fn sanitizer() -> Sanitizer {
+        Sanitizer::new(SanitizeProfile::Posix, "_", 255).unwrap()
+    }
+
+    #[test]
+    fn relative_paths_go_up_as_needed() {
+        let relative = relative_path(Path::new("/music/playlists"), Path::new("/music/Artist/Song.mp3"));
+        assert_eq!(relative, PathBuf::from("../Artist/Song.mp3"));
+        let relative = relative_path(Path::new("/music"), Path::new("/music/Artist/Song.mp3"));
+        assert_eq!(relative, PathBuf::from("Artist/Song.mp3"));
+    }
+
+    #[test]
+    fn final_paths_follow_moves_and_kept_copies() {
+        let mut plan = Plan::new();
+        plan.add_move(PathBuf::from("/in/A.mp3"), PathBuf::from("/out/A.mp3"), CollisionPolicy::Fail).unwrap();
+        plan.add_move(PathBuf::from("/in/B.mp3"), PathBuf::from("/out/B.mp3"), CollisionPolicy::Fail).unwrap();
+        plan.add_skipped(PathBuf::from("/in/C.mp3"), SkipReason::Duplicate(PathBuf::from("/in/A.mp3")));
+        plan.add_skipped(PathBuf::from("/in/D.mp3"), SkipReason::AlreadyInPlace);
+        let paths = final_paths(&plan, &[Path::new("/in/B.mp3")]);
+        assert_eq!(paths[Path::new("/in/A.mp3")], PathBuf::from("/out/A.mp3"));
+        assert_eq!(paths[Path::new("/in/B.mp3")], PathBuf::from("/in/B.mp3"));
+        assert_eq!(paths[Path::new("/in/C.mp3")], PathBuf::from("/out/A.mp3"));
+        assert_eq!(paths[Path::new("/in/D.mp3")], PathBuf::from("/in/D.mp3"));
+    }
+
+    #[test]
+    fn m3u_entries_read_with_their_info() {
+        let m3u = "#EXTM3U\n\
+            #EXTINF:185,The Beatles - Here Comes the Sun\n\
+            ..\\Abbey Road\\07 Here Comes the Sun.mp3\n\
+            \n\
+            # a comment\n\
+            Something.mp3\n\
+            http://radio.example.com/stream\n";
+        let entries = parse_m3u(m3u, Path::new("/in/Playlists"));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, PathBuf::from("/in/Playlists/../Abbey Road/07 Here Comes the Sun.mp3"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(185)));
+        assert_eq!(entries[0].title.as_deref(), Some("The Beatles - Here Comes the Sun"));
+        assert_eq!((entries[1].duration, entries[1].title.as_deref()), (None, None));
+        assert_eq!(entries[2].path, PathBuf::from("http://radio.example.com/stream"));
+    }
+
+    #[test]
+    fn pls_entries_read_in_order() {
+        let pls = "[playlist]\n\
+            File2=B.mp3\n\
+            Title2=Song B\n\
+            File1=A.mp3\n\
+            Length1=200\n\
+            NumberOfEntries=2\n\
+            Version=2\n";
+        let entries = parse_pls(pls, Path::new("/in"));
+        let paths: Vec<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("/in/A.mp3"), Path::new("/in/B.mp3")]);
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(200)));
+        assert_eq!(entries[1].title.as_deref(), Some("Song B"));
+        let playlist = Playlist { path: PathBuf::from("/in/mix.pls"), entries, unresolved: Vec::new() };
+        let rewritten = playlist.contents(PlaylistPaths::Relative).unwrap();
+        assert_eq!(
+            rewritten,
+            "[playlist]\nFile1=A.mp3\nLength1=200\nFile2=B.mp3\nTitle2=Song B\nLength2=-1\n\
+             NumberOfEntries=2\nVersion=2\n",
+        );
+    }
+
+    #[test]
+    fn input_playlists_rewritten_through_final_paths() {
+        let indir = tempdir().unwrap();
+        let playlist_path = indir.as_ref().join("Playlists").join("mix.m3u");
+        create_dir_all(playlist_path.parent().unwrap()).unwrap();
+        write(indir.as_ref().join("Elsewhere.mp3"), b"song data").unwrap();
+        write(&playlist_path, b"../Music/ABCD.mp3\n../music/efgh.MP3\n../Elsewhere.mp3\n../Gone.mp3\n").unwrap();
+        let final_paths = HashMap::from([
+            (indir.as_ref().join("Music/ABCD.mp3"), PathBuf::from("/out/Artist/Song A.mp3")),
+            (indir.as_ref().join("Music/EFGH.mp3"), PathBuf::from("/out/Artist/Song B.mp3")),
+        ]);
+        let playlist = rewritten_playlist(&playlist_path, indir.as_ref(), Path::new("/out"), &final_paths).unwrap();
+        assert_eq!(playlist.path, PathBuf::from("/out/Playlists/mix.m3u8"));
+        let paths: Vec<&Path> = playlist.entries.iter().map(|entry| entry.path.as_path()).collect();
+        let elsewhere = indir.as_ref().join("Elsewhere.mp3");
+        assert_eq!(paths, vec![Path::new("/out/Artist/Song A.mp3"), Path::new("/out/Artist/Song B.mp3"), &elsewhere]);
+        let gone = indir.as_ref().join("Gone.mp3");
+        assert_eq!(playlist.unresolved, vec![gone.display().to_string()]);
+        let m3u = playlist.contents(PlaylistPaths::Relative).unwrap();
+        assert!(m3u.starts_with("#EXTM3U\n../Artist/Song A.mp3\n../Artist/Song B.mp3\n"));
+    }
+
+    #[test]
+    fn latin1_m3u_written_as_m3u8() {
+        let indir = tempdir().unwrap();
+        let playlist_path = indir.as_ref().join("café.m3u");
+        write(indir.as_ref().join("Café.mp3"), b"song data").unwrap();
+        // "Café.mp3" in Latin-1
+        write(&playlist_path, b"Caf\xe9.mp3\n").unwrap();
+        let playlist = rewritten_playlist(&playlist_path, indir.as_ref(), Path::new("/out"), &HashMap::new()).unwrap();
+        assert_eq!(playlist.path, PathBuf::from("/out/café.m3u8"));
+        let m3u8 = playlist.contents(PlaylistPaths::Absolute).unwrap();
+        assert_eq!(m3u8, format!("#EXTM3U\n{}\n", indir.as_ref().join("Café.mp3").display()));
+    }
+
+    #[test]
+    fn playlists_with_same_path_renamed() {
+        let playlist = |path: &str| Playlist {
+            path: PathBuf::from(path),
+            entries: Vec::new(),
+            unresolved: Vec::new(),
+        };
+        let mut playlists = vec![
+            playlist("/out/Road Trip.m3u8"),
+            playlist("/out/road trip.m3u8"),
+            playlist("/out/Road Trip (2).m3u8"),
+            playlist("/out/Road Trip.pls"),
+        ];
+        give_unique_paths(&mut playlists);
+        let paths: Vec<&Path> = playlists.iter().map(|playlist| playlist.path.as_path()).collect();
+        assert_eq!(paths, vec![
+            Path::new("/out/Road Trip.m3u8"),
+            Path::new("/out/road trip (2).m3u8"),
+            Path::new("/out/Road Trip (2) (2).m3u8"),
+            Path::new("/out/Road Trip.pls"),
+        ]);
+    }
+
+    #[test]
+    fn ipod_playlists_point_at_final_paths() {
+        let db = ITunesDb::parse(FIXTURE).unwrap();
+        let final_paths = HashMap::from([(
+            PathBuf::from("/in/iPod_Control/Music/F00/ABCD.mp3"),
+            PathBuf::from("/out/The Beatles/Abbey Road/07 Here Comes the Sun.mp3"),
+        )]);
+        let playlists = ipod_playlists(&db, &final_paths, Path::new("/out"), &sanitizer());
+        assert_eq!(playlists.len(), 1);
+        let playlist = &playlists[0];
+        assert_eq!(playlist.path, PathBuf::from("/out/Road Trip.m3u8"));
+        assert_eq!(playlist.unresolved, vec![String::from("iPod_Control/Music/F11/IJKL.mp3")]);
+        let m3u8 = playlist.to_m3u8(PlaylistPaths::Relative).unwrap();
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\n\
+             #EXTINF:185,The Beatles - Here Comes the Sun\n\
+             The Beatles/Abbey Road/07 Here Comes the Sun.mp3\n",
+        );
+        let m3u8 = playlist.to_m3u8(PlaylistPaths::Absolute).unwrap();
+        assert!(m3u8.ends_with("\n/out/The Beatles/Abbey Road/07 Here Comes the Sun.mp3\n"));
+    }
+}
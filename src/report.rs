@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::write;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::MoverError;
+use crate::operation::Operation;
+use crate::plan::{Plan, SkipReason};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeKind {
+    /// Placed at its destination, or would have been in a dry run
+    Placed,
+    MissingField,
+    UnreadableTag,
+    UnsupportedExtension,
+    AlreadyInPlace,
+    /// Left where it was because its destination was taken
+    CollisionSkipped,
+    /// Has the same audio as another song file, which was kept instead
+    Duplicate,
+    /// Placing the song file at its destination failed
+    Failed,
+}
+
+impl OutcomeKind {
+    fn label(&self) -> &'static str {
+        match self {
+            OutcomeKind::Placed => "placed",
+            OutcomeKind::MissingField => "missing field",
+            OutcomeKind::UnreadableTag => "unreadable tag",
+            OutcomeKind::UnsupportedExtension => "unsupported extension",
+            OutcomeKind::AlreadyInPlace => "already in place",
+            OutcomeKind::CollisionSkipped => "skipped on collision",
+            OutcomeKind::Duplicate => "duplicate",
+            OutcomeKind::Failed => "failed",
+        }
+    }
+}
+
+fn outcome_kind(reason: &SkipReason) -> OutcomeKind {
+    match reason {
+        SkipReason::MissingField(_) => OutcomeKind::MissingField,
+        SkipReason::UnreadableTag(_) => OutcomeKind::UnreadableTag,
+        SkipReason::NoExtension | SkipReason::UnsupportedExtension(_) => {
+            OutcomeKind::UnsupportedExtension
+        },
+        SkipReason::AlreadyInPlace => OutcomeKind::AlreadyInPlace,
+        SkipReason::Collision(_) => OutcomeKind::CollisionSkipped,
+        SkipReason::Duplicate(_) => OutcomeKind::Duplicate,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    pub kind: OutcomeKind,
+    /// The destination of a placed song file, or why it wasn't placed
+    pub detail: String,
+    /// Tag fields that were read from the song file's path instead
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inferred: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RunCounts {
+    pub placed: usize,
+    pub missing_field: usize,
+    pub unreadable_tag: usize,
+    pub unsupported_extension: usize,
+    pub already_in_place: usize,
+    pub collision_skipped: usize,
+    pub duplicate: usize,
+    pub failed: usize,
+    /// Song files with at least one tag field read from their path, whatever their outcome
+    pub inferred_from_path: usize,
+}
+
+impl RunCounts {
+    fn add(&mut self, kind: OutcomeKind) {
+        let count = match kind {
+            OutcomeKind::Placed => &mut self.placed,
+            OutcomeKind::MissingField => &mut self.missing_field,
+            OutcomeKind::UnreadableTag => &mut self.unreadable_tag,
+            OutcomeKind::UnsupportedExtension => &mut self.unsupported_extension,
+            OutcomeKind::AlreadyInPlace => &mut self.already_in_place,
+            OutcomeKind::CollisionSkipped => &mut self.collision_skipped,
+            OutcomeKind::Duplicate => &mut self.duplicate,
+            OutcomeKind::Failed => &mut self.failed,
+        };
+        *count += 1;
+    }
+
+    fn rows(&self) -> [(&'static str, usize); 9] {
+        [
+            (OutcomeKind::Placed.label(), self.placed),
+            (OutcomeKind::MissingField.label(), self.missing_field),
+            (OutcomeKind::UnreadableTag.label(), self.unreadable_tag),
+            (OutcomeKind::UnsupportedExtension.label(), self.unsupported_extension),
+            (OutcomeKind::AlreadyInPlace.label(), self.already_in_place),
+            (OutcomeKind::CollisionSkipped.label(), self.collision_skipped),
+            (OutcomeKind::Duplicate.label(), self.duplicate),
+            (OutcomeKind::Failed.label(), self.failed),
+            ("inferred from path", self.inferred_from_path),
+        ]
+    }
+}
+
+/// A playlist written during a run, or that would have been in a dry run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlaylistOutcome {
+    pub path: PathBuf,
+    pub entries: usize,
+    /// Entries left out because their song file wasn't found
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved: Vec<String>,
+    /// Why writing the playlist failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the playlist was left unwritten because a file was already at its path
+    pub skipped: bool,
+}
+
+/// What happened to every song file found during a run. The plan and the errors behind the
+/// outcomes are kept for callers but left out of the JSON form.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub operation: Operation,
+    pub dry_run: bool,
+    pub journal_path: Option<PathBuf>,
+    pub counts: RunCounts,
+    pub files: Vec<FileOutcome>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub playlists: Vec<PlaylistOutcome>,
+    #[serde(skip)]
+    pub plan: Plan,
+    #[serde(skip)]
+    pub errors: Vec<MoverError>,
+}
+
+impl RunReport {
+    pub fn new(
+        plan: Plan,
+        errors: Vec<MoverError>,
+        dry_run: bool,
+        journal_path: Option<PathBuf>,
+    ) -> RunReport {
+        let failures: HashMap<&Path, String> = errors.iter()
+            .filter_map(|e| match e {
+                MoverError::Filesystem { path, .. } => Some((path.as_path(), e.to_string())),
+                _ => None,
+            })
+            .collect();
+        let unsorted: HashMap<&Path, &SkipReason> = plan.unsorted.iter()
+            .map(|unsorted| (unsorted.path.as_path(), &unsorted.reason))
+            .collect();
+        let mut files = Vec::with_capacity(plan.moves.len() + plan.skipped.len());
+        for planned_move in plan.moves.iter() {
+            let source = planned_move.source.as_path();
+            let destination = planned_move.destination.display();
+            let (kind, detail) = match (failures.get(source), unsorted.get(source)) {
+                (Some(e), _) => (OutcomeKind::Failed, e.clone()),
+                (None, Some(reason)) => {
+                    (outcome_kind(reason), format!("{}; unsorted to {}", reason, destination))
+                },
+                (None, None) => (OutcomeKind::Placed, destination.to_string()),
+            };
+            let path = planned_move.source.clone();
+            files.push(FileOutcome { path, kind, detail, inferred: Vec::new() });
+        }
+        for skipped in plan.skipped.iter() {
+            let kind = outcome_kind(&skipped.reason);
+            let detail = skipped.reason.to_string();
+            let path = skipped.path.clone();
+            files.push(FileOutcome { path, kind, detail, inferred: Vec::new() });
+        }
+        let inferred: HashMap<&Path, &Vec<String>> = plan.inferred.iter()
+            .map(|inferred| (inferred.path.as_path(), &inferred.fields))
+            .collect();
+        for file in files.iter_mut() {
+            if let Some(fields) = inferred.get(file.path.as_path()) {
+                file.inferred = fields.to_vec();
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut counts = RunCounts::default();
+        for file in files.iter() {
+            counts.add(file.kind);
+            if !file.inferred.is_empty() {
+                counts.inferred_from_path += 1;
+            }
+        }
+        RunReport {
+            operation: plan.operation,
+            dry_run,
+            journal_path,
+            counts,
+            files,
+            playlists: Vec::new(),
+            plan,
+            errors,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<(), MoverError> {
+        let to_error = |source| MoverError::Filesystem { path: path.to_path_buf(), source };
+        let json = self.to_json().map_err(|e| to_error(Error::new(ErrorKind::InvalidData, e)))?;
+        write(path, json).map_err(to_error)
+    }
+}
+
+impl Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = if self.dry_run { ", dry run" } else { "" };
+        writeln!(f, "Summary ({}{}):", self.operation.verb(), mode)?;
+        for (label, count) in self.counts.rows() {
+            writeln!(f, "  {:<24}{:>8}", label, count)?;
+        }
+        // Files that were never going to be placed would drown out the ones needing attention
+        let listed_kinds = [
+            OutcomeKind::MissingField,
+            OutcomeKind::UnreadableTag,
+            OutcomeKind::CollisionSkipped,
+            OutcomeKind::Duplicate,
+            OutcomeKind::Failed,
+        ];
+        let needing_attention = self.files.iter().filter(|file| listed_kinds.contains(&file.kind));
+        for file in needing_attention {
+            writeln!(f, "  {} ({})", file.path.display(), file.detail)?;
+        }
+        if !self.playlists.is_empty() {
+            writeln!(f, "Playlists ({}):", self.playlists.len())?;
+        }
+        for playlist in self.playlists.iter() {
+            match &playlist.error {
+                Some(error) => writeln!(f, "  {} (failed: {})", playlist.path.display(), error)?,
+                None if playlist.skipped => writeln!(
+                    f,
+                    "  {} (skipped: destination already taken)",
+                    playlist.path.display(),
+                )?,
+                None => writeln!(
+                    f,
+                    "  {} ({} entries, {} not found)",
+                    playlist.path.display(),
+                    playlist.entries,
+                    playlist.unresolved.len(),
+                )?,
+            }
+            for entry in playlist.unresolved.iter() {
+                writeln!(f, "    not found: {}", entry)?;
+            }
+        }
+        if let Some(journal_path) = &self.journal_path {
+            writeln!(f, "Changes recorded in journal {}", journal_path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::CollisionPolicy;
+
+    use super::*;
+
+    fn report() -> RunReport {
+        let mut plan = Plan::new();
+        plan.add_move(
+            PathBuf::from("/in/A.mp3"), PathBuf::from("/out/Artist/Album/Song A.mp3"),
+            CollisionPolicy::Fail,
+        ).unwrap();
+        plan.add_move(
+            PathBuf::from("/in/B.mp3"), PathBuf::from("/out/Artist/Album/Song B.mp3"),
+            CollisionPolicy::Fail,
+        ).unwrap();
+        plan.add_inferred(PathBuf::from("/in/A.mp3"), vec![String::from("album")]);
+        plan.add_skipped(PathBuf::from("/in/C.mp3"), SkipReason::MissingField(String::from("artist")));
+        plan.add_skipped(PathBuf::from("/in/D.wav"), SkipReason::UnsupportedExtension(String::from("wav")));
+        let errors = vec![
+            MoverError::MissingField {
+                path: PathBuf::from("/in/C.mp3"),
+                field: String::from("artist"),
+            },
+            MoverError::Filesystem {
+                path: PathBuf::from("/in/B.mp3"),
+                source: Error::from(ErrorKind::PermissionDenied),
+            },
+        ];
+        RunReport::new(plan, errors, false, None)
+    }
+
+    #[test]
+    fn counts_and_outcomes() {
+        let report = report();
+        let expected_counts = RunCounts {
+            placed: 1,
+            missing_field: 1,
+            unsupported_extension: 1,
+            failed: 1,
+            inferred_from_path: 1,
+            ..RunCounts::default()
+        };
+        assert_eq!(report.counts, expected_counts);
+        let kinds: Vec<OutcomeKind> = report.files.iter().map(|file| file.kind).collect();
+        assert_eq!(kinds, vec![
+            OutcomeKind::Placed,
+            OutcomeKind::Failed,
+            OutcomeKind::MissingField,
+            OutcomeKind::UnsupportedExtension,
+        ]);
+        assert_eq!(report.files[0].detail, "/out/Artist/Album/Song A.mp3");
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json().unwrap()).unwrap();
+        assert_eq!(json["operation"], "move");
+        assert_eq!(json["counts"]["placed"], 1);
+        assert_eq!(json["counts"]["failed"], 1);
+        assert_eq!(json["files"][2]["path"], "/in/C.mp3");
+        assert_eq!(json["files"][2]["kind"], "missing_field");
+        assert_eq!(json["files"][2]["detail"], "missing field: artist");
+        assert_eq!(json["files"][0]["inferred"], serde_json::json!(["album"]));
+        assert_eq!(json["files"][2].get("inferred"), None);
+        assert_eq!(json.get("plan"), None);
+    }
+
+    #[test]
+    fn table_lists_counts_and_files_needing_attention() {
+        let table = report().to_string();
+        assert_eq!(table.lines().next(), Some("Summary (move):"));
+        assert!(table.contains("  placed                         1"));
+        assert!(table.contains("  /in/C.mp3 (missing field: artist)"));
+        assert!(!table.contains("Song A"));
+        assert!(!table.contains("D.wav"));
+        assert!(!table.contains("Playlists"));
+        let mut report = report();
+        report.playlists.push(PlaylistOutcome {
+            path: PathBuf::from("/out/Road Trip.m3u8"),
+            entries: 2,
+            unresolved: vec![String::from("iPod_Control/Music/F11/IJKL.mp3")],
+            error: None,
+            skipped: false,
+        });
+        let table = report.to_string();
+        let expected = "Playlists (1):\n  /out/Road Trip.m3u8 (2 entries, 1 not found)\n    \
+            not found: iPod_Control/Music/F11/IJKL.mp3\n";
+        assert!(table.contains(expected));
+    }
+}
@@ -0,0 +1,351 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::discover::SUPPORTED_EXTENSIONS;
+
+pub const DEFAULT_REPLACEMENT: &str = "_";
+pub const DEFAULT_MAX_NAME_LENGTH: usize = 255;
+
+const WINDOWS_ILLEGAL_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SanitizeProfile {
+    /// Only replace the characters a POSIX filesystem can't store ('/' and NUL)
+    Posix,
+    /// Also follow the rules of FAT32/exFAT/NTFS and Samba shares: no <>:"\|?* or control
+    /// characters, no trailing dots or spaces, and no reserved device names such as CON
+    #[default]
+    Windows,
+    /// Windows rules, plus transliterating or replacing any non-ASCII characters
+    Ascii,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidSanitizer {
+    EmptyReplacement,
+    Replacement(String),
+    MaxNameLength { max_name_length: usize, min_name_length: usize },
+}
+
+impl Display for InvalidSanitizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidSanitizer::EmptyReplacement => write!(f, "Replacement can't be empty"),
+            InvalidSanitizer::Replacement(replacement) => write!(
+                f,
+                "Replacement {:?} contains characters that aren't allowed by the sanitize profile",
+                replacement,
+            ),
+            InvalidSanitizer::MaxNameLength { max_name_length, min_name_length } => write!(
+                f,
+                "Max name length {} is too short to keep a file extension, it must be at least {}",
+                max_name_length,
+                min_name_length,
+            ),
+        }
+    }
+}
+
+impl Error for InvalidSanitizer {}
+
+/// Makes tag values safe to use as path components on the target filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sanitizer {
+    profile: SanitizeProfile,
+    replacement: String,
+    max_name_length: usize,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Sanitizer {
+        Sanitizer {
+            profile: SanitizeProfile::default(),
+            replacement: String::from(DEFAULT_REPLACEMENT),
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+        }
+    }
+}
+
+fn fold_to_ascii(c: char) -> Option<&'static str> {
+    let folded = match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' => "A",
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È' | 'É' | 'Ê' | 'Ë' => "E",
+        'è' | 'é' | 'ê' | 'ë' => "e",
+        'Ì' | 'Í' | 'Î' | 'Ï' => "I",
+        'ì' | 'í' | 'î' | 'ï' => "i",
+        'Ð' => "D",
+        'ð' => "d",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' => "O",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => "o",
+        'Ù' | 'Ú' | 'Û' | 'Ü' => "U",
+        'ù' | 'ú' | 'û' | 'ü' => "u",
+        'Ý' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Þ' => "Th",
+        'þ' => "th",
+        'ß' => "ss",
+        '‘' | '’' => "'",
+        '“' | '”' => "\"",
+        '–' | '—' => "-",
+        '…' => "...",
+        _ => return None,
+    };
+    Some(folded)
+}
+
+fn truncate_to_char_boundary(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+impl Sanitizer {
+    pub fn new(
+        profile: SanitizeProfile,
+        replacement: &str,
+        max_name_length: usize,
+    ) -> Result<Sanitizer, InvalidSanitizer> {
+        if replacement.is_empty() {
+            return Err(InvalidSanitizer::EmptyReplacement)
+        }
+        // A file name needs room for its extension plus the replacement standing in for a stem
+        // that got cut away entirely
+        let longest_extension = SUPPORTED_EXTENSIONS.iter()
+            .map(|ext| ext.len() + 1)
+            .max()
+            .unwrap_or(0);
+        let min_name_length = longest_extension + replacement.len();
+        if max_name_length < min_name_length {
+            return Err(InvalidSanitizer::MaxNameLength { max_name_length, min_name_length })
+        }
+        let sanitizer = Sanitizer {
+            profile,
+            replacement: replacement.to_string(),
+            max_name_length,
+        };
+        // It stands in for `.` and `..` components, so can't be one itself
+        let is_valid_replacement = replacement.chars()
+            .all(|c| sanitizer.is_allowed(c))
+            && sanitizer.strip_trailing(replacement) == replacement
+            && replacement != "."
+            && replacement != "..";
+        if !is_valid_replacement {
+            return Err(InvalidSanitizer::Replacement(replacement.to_string()))
+        }
+        Ok(sanitizer)
+    }
+
+    fn is_allowed(&self, c: char) -> bool {
+        match self.profile {
+            SanitizeProfile::Posix => c != '/' && c != '\0',
+            SanitizeProfile::Windows => !c.is_control() && !WINDOWS_ILLEGAL_CHARS.contains(&c),
+            SanitizeProfile::Ascii => {
+                c.is_ascii() && !c.is_control() && !WINDOWS_ILLEGAL_CHARS.contains(&c)
+            },
+        }
+    }
+
+    fn strip_trailing<'a>(&self, text: &'a str) -> &'a str {
+        match self.profile {
+            SanitizeProfile::Posix => text,
+            SanitizeProfile::Windows | SanitizeProfile::Ascii => {
+                text.trim_end_matches(['.', ' '])
+            },
+        }
+    }
+
+    /// Replaces the characters in a tag value that can't appear in a path component, including
+    /// path separators so that a value like `AC/DC` stays a single component.
+    pub fn sanitize_value(&self, value: &str) -> String {
+        let mut sanitized = String::with_capacity(value.len());
+        for c in value.chars() {
+            if self.is_allowed(c) {
+                sanitized.push(c);
+                continue;
+            }
+            // Folding can give characters that are no more allowed, such as `"` for `“`
+            match fold_to_ascii(c) {
+                Some(folded)
+                    if self.profile == SanitizeProfile::Ascii
+                        && folded.chars().all(|c| self.is_allowed(c)) => {
+                    sanitized.push_str(folded)
+                },
+                _ => sanitized.push_str(&self.replacement),
+            }
+        }
+        sanitized
+    }
+
+    /// Applies the whole-name rules to a rendered path component: length truncation (keeping
+    /// the extension of file names), trailing dots and spaces, reserved names, and `.`/`..`
+    /// which would otherwise escape the output directory.
+    pub fn sanitize_component(&self, component: &str, is_file_name: bool) -> String {
+        let (stem, extension) = match component.rsplit_once('.') {
+            Some((stem, extension)) if is_file_name && !stem.is_empty() => (stem, Some(extension)),
+            _ => (component, None),
+        };
+        let extension_len = extension.map(|ext| ext.len() + 1).unwrap_or(0);
+        let max_stem_len = self.max_name_length.saturating_sub(extension_len);
+        let stem = truncate_to_char_boundary(stem, max_stem_len);
+        let mut sanitized = self.strip_trailing(stem).to_string();
+        if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+            sanitized = self.replacement.clone();
+        }
+        if self.profile != SanitizeProfile::Posix {
+            let base_name = sanitized.split('.').next().unwrap_or_default();
+            let is_reserved = WINDOWS_RESERVED_NAMES.iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(base_name));
+            if is_reserved {
+                sanitized.insert_str(base_name.len(), &self.replacement);
+            }
+        }
+        if let Some(extension) = extension {
+            sanitized.push('.');
+            sanitized.push_str(extension);
+        }
+        let sanitized = truncate_to_char_boundary(&sanitized, self.max_name_length);
+        self.strip_trailing(sanitized).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(profile: SanitizeProfile) -> Sanitizer {
+        Sanitizer::new(profile, DEFAULT_REPLACEMENT, DEFAULT_MAX_NAME_LENGTH).unwrap()
+    }
+
+    #[test]
+    fn path_separator_replaced_in_every_profile() {
+        for profile in [SanitizeProfile::Posix, SanitizeProfile::Windows, SanitizeProfile::Ascii] {
+            assert_eq!(sanitizer(profile).sanitize_value("AC/DC"), "AC_DC");
+        }
+    }
+
+    #[test]
+    fn posix_profile_keeps_windows_illegal_chars() {
+        let sanitizer = sanitizer(SanitizeProfile::Posix);
+        assert_eq!(sanitizer.sanitize_value("Live: 1/2?"), "Live: 1_2?");
+        assert_eq!(sanitizer.sanitize_component("Trailing dots...", false), "Trailing dots...");
+    }
+
+    #[test]
+    fn windows_profile_replaces_illegal_chars() {
+        let sanitizer = sanitizer(SanitizeProfile::Windows);
+        assert_eq!(sanitizer.sanitize_value("Live: 1/2?"), "Live_ 1_2_");
+        assert_eq!(sanitizer.sanitize_value("What*\"Is\"<This>|\\"), "What__Is__This___");
+        assert_eq!(sanitizer.sanitize_value("Tab\there"), "Tab_here");
+        assert_eq!(sanitizer.sanitize_value("Café"), "Café");
+    }
+
+    #[test]
+    fn windows_profile_strips_trailing_dots_and_spaces() {
+        let sanitizer = sanitizer(SanitizeProfile::Windows);
+        assert_eq!(sanitizer.sanitize_component("Greatest Hits Vol. 2...", false), "Greatest Hits Vol. 2");
+        assert_eq!(sanitizer.sanitize_component("Song. .mp3", true), "Song.mp3");
+    }
+
+    #[test]
+    fn windows_profile_avoids_reserved_names() {
+        let sanitizer = sanitizer(SanitizeProfile::Windows);
+        assert_eq!(sanitizer.sanitize_component("CON", false), "CON_");
+        assert_eq!(sanitizer.sanitize_component("aux.mp3", true), "aux_.mp3");
+        assert_eq!(sanitizer.sanitize_component("Con.Fusion", false), "Con_.Fusion");
+        assert_eq!(sanitizer.sanitize_component("Console", false), "Console");
+    }
+
+    #[test]
+    fn ascii_profile_transliterates() {
+        let sanitizer = sanitizer(SanitizeProfile::Ascii);
+        assert_eq!(sanitizer.sanitize_value("Björk – Jóga"), "Bjork - Joga");
+        assert_eq!(sanitizer.sanitize_value("坂本龍一"), "____");
+    }
+
+    #[test]
+    fn ascii_profile_replaces_illegal_folds() {
+        let sanitizer = sanitizer(SanitizeProfile::Ascii);
+        assert_eq!(sanitizer.sanitize_value("“Quoted”"), "_Quoted_");
+    }
+
+    #[test]
+    fn traversal_components_replaced() {
+        for profile in [SanitizeProfile::Posix, SanitizeProfile::Windows, SanitizeProfile::Ascii] {
+            let sanitizer = sanitizer(profile);
+            assert_eq!(sanitizer.sanitize_component("..", false), "_");
+            assert_eq!(sanitizer.sanitize_component(".", false), "_");
+            assert_eq!(sanitizer.sanitize_component("", false), "_");
+        }
+    }
+
+    #[test]
+    fn truncation_respects_utf8_and_extension() {
+        let sanitizer = Sanitizer::new(SanitizeProfile::Posix, "_", 9).unwrap();
+        // Each 'é' is two bytes, so the cut has to land before the one straddling byte 9
+        assert_eq!(sanitizer.sanitize_component("ééééééé", false), "éééé");
+        assert_eq!(sanitizer.sanitize_component("éééééé.mp3", true), "éé.mp3");
+        assert_eq!(sanitizer.sanitize_component("Short.mp3", true), "Short.mp3");
+    }
+
+    #[test]
+    fn custom_replacement() {
+        let sanitizer = Sanitizer::new(SanitizeProfile::Windows, "-", DEFAULT_MAX_NAME_LENGTH).unwrap();
+        assert_eq!(sanitizer.sanitize_value("AC/DC"), "AC-DC");
+    }
+
+    #[test]
+    fn invalid_replacement() {
+        let res = Sanitizer::new(SanitizeProfile::Windows, ":", DEFAULT_MAX_NAME_LENGTH);
+        assert!(res.is_err());
+        let res = Sanitizer::new(SanitizeProfile::Posix, "/", DEFAULT_MAX_NAME_LENGTH);
+        assert!(res.is_err());
+        let res = Sanitizer::new(SanitizeProfile::Posix, ":", DEFAULT_MAX_NAME_LENGTH);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn traversal_replacement() {
+        for replacement in [".", ".."] {
+            let res = Sanitizer::new(SanitizeProfile::Posix, replacement, DEFAULT_MAX_NAME_LENGTH);
+            assert_eq!(res, Err(InvalidSanitizer::Replacement(replacement.to_string())));
+        }
+    }
+
+    #[test]
+    fn empty_replacement() {
+        let res = Sanitizer::new(SanitizeProfile::Posix, "", DEFAULT_MAX_NAME_LENGTH);
+        assert_eq!(res, Err(InvalidSanitizer::EmptyReplacement));
+    }
+
+    #[test]
+    fn max_name_length_too_short() {
+        let res = Sanitizer::new(SanitizeProfile::Posix, "_", 0);
+        assert!(res.is_err());
+        // ".flac" plus a one byte replacement
+        let res = Sanitizer::new(SanitizeProfile::Posix, "_", 5);
+        assert_eq!(res, Err(InvalidSanitizer::MaxNameLength { max_name_length: 5, min_name_length: 6 }));
+        let res = Sanitizer::new(SanitizeProfile::Posix, "_", 6);
+        assert!(res.is_ok());
+    }
+}
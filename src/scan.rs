@@ -0,0 +1,119 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use crate::plan::{SkipReason, SkippedFile};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedSong {
+    pub path: PathBuf,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    /// Where the song file would be placed, relative to the output directory
+    pub destination: PathBuf,
+    /// Fields read from the path because the tag lacked them
+    pub inferred: Vec<String>,
+}
+
+/// The song files found in an input directory, the tag info read from them and where each
+/// would be placed, without anything being moved.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Scan {
+    pub songs: Vec<ScannedSong>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl Scan {
+    pub fn new() -> Scan {
+        Scan::default()
+    }
+
+    pub fn add_song(
+        &mut self,
+        path: PathBuf,
+        artist: Option<&str>,
+        album: Option<&str>,
+        title: Option<&str>,
+        destination: PathBuf,
+        inferred: Vec<String>,
+    ) {
+        self.songs.push(ScannedSong {
+            path,
+            artist: artist.map(String::from),
+            album: album.map(String::from),
+            title: title.map(String::from),
+            destination,
+            inferred,
+        });
+    }
+
+    pub fn add_skipped(&mut self, path: PathBuf, reason: SkipReason) {
+        self.skipped.push(SkippedFile { path, reason });
+    }
+}
+
+impl Display for Scan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Song files ({}):", self.songs.len())?;
+        for song in self.songs.iter() {
+            write!(
+                f,
+                "  {}: {} / {} / {} -> {}",
+                song.path.display(),
+                song.artist.as_deref().unwrap_or("<no artist>"),
+                song.album.as_deref().unwrap_or("<no album>"),
+                song.title.as_deref().unwrap_or("<no title>"),
+                song.destination.display(),
+            )?;
+            if !song.inferred.is_empty() {
+                write!(f, " (inferred from path: {})", song.inferred.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "Unusable files ({}):", self.skipped.len())?;
+        for skipped in self.skipped.iter() {
+            writeln!(f, "  {} ({})", skipped.path.display(), skipped.reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn scan_display_lists_every_entry() {
+        let mut scan = Scan::new();
+        scan.add_song(
+            PathBuf::from("/in/F00/ABCD.mp3"),
+            Some("Artist"),
+            Some("Album"),
+            Some("Song"),
+            PathBuf::from("Artist/Album/Song.mp3"),
+            Vec::new(),
+        );
+        scan.add_song(
+            PathBuf::from("/in/F00/EFGH.mp3"),
+            None,
+            Some("Album"),
+            None,
+            PathBuf::from("Unknown/Album/EFGH.mp3"),
+            vec![String::from("album")],
+        );
+        scan.add_skipped(
+            PathBuf::from("/in/F00/IJKL.mp3"),
+            SkipReason::MissingField(String::from("album")),
+        );
+        let expected = "\
+Song files (2):
+  /in/F00/ABCD.mp3: Artist / Album / Song -> Artist/Album/Song.mp3
+  /in/F00/EFGH.mp3: <no artist> / Album / <no title> -> Unknown/Album/EFGH.mp3 (inferred from path: album)
+Unusable files (1):
+  /in/F00/IJKL.mp3 (missing field: album)
+";
+        assert_eq!(scan.to_string(), expected);
+    }
+}
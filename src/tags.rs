@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use audiotags::{AudioTag, FlacTag, Id3v2Tag, Mp4Tag};
+use id3::TagLike;
+use log::warn;
+
+use crate::metadata::{Codec, TrackMetadata};
+use crate::mpeg::read_mp3_properties;
+use crate::plan::SkipReason;
+
+/// A file found in the input directory, with its metadata or the reason it has none that can
+/// be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SongFile {
+    pub path: PathBuf,
+    pub metadata: Result<TrackMetadata, SkipReason>,
+}
+
+fn is_flag_set(value: &str) -> bool {
+    matches!(value.trim(), "1" | "true")
+}
+
+/// Parses a number that may be followed by a total, as in `3` or `3/12`, treating 0 as no
+/// number at all.
+fn parse_number_pair(text: &str) -> (Option<u16>, Option<u16>) {
+    let parse = |number: &str| number.trim().parse().ok().filter(|n| *n > 0);
+    match text.split_once('/') {
+        Some((number, total)) => (parse(number), parse(total)),
+        None => (parse(text), None),
+    }
+}
+
+/// The fields audiotags reads the same way whatever the tag format.
+fn tag_metadata(tag: &dyn AudioTag) -> TrackMetadata {
+    let year = tag.year();
+    TrackMetadata {
+        artist: tag.artist().map(String::from),
+        album_artist: tag.album_artist().map(String::from),
+        album: tag.album_title().map(String::from),
+        title: tag.title().map(String::from),
+        year,
+        date: tag.date().map(|date| date.to_string()).or(year.map(|year| year.to_string())),
+        genre: tag.genre().map(String::from),
+        composer: tag.composer().map(String::from),
+        ..TrackMetadata::default()
+    }
+}
+
+fn read_id3_tag(path: &Path) -> Result<TrackMetadata, String> {
+    let tag = id3::Tag::read_from_path(path).map_err(|e| e.to_string())?;
+    let mut metadata = id3_metadata(tag);
+    metadata.codec = Some(Codec::Mp3);
+    match read_mp3_properties(path) {
+        Ok(Some(properties)) => {
+            metadata.duration = properties.duration;
+            metadata.bitrate = Some(properties.bitrate);
+            metadata.sample_rate = Some(properties.sample_rate);
+            metadata.channels = Some(properties.channels);
+        },
+        Ok(None) => {},
+        Err(e) => warn!("Couldn't read audio properties of {:?}: {}", path, e),
+    }
+    Ok(metadata)
+}
+
+/// The metadata in an ID3v2 tag, including the values audiotags either doesn't expose or
+/// can't parse in every form taggers write them in.
+pub(crate) fn id3_metadata(tag: id3::Tag) -> TrackMetadata {
+    let text = |frame_id| tag.get(frame_id).and_then(|frame| frame.content().text());
+    let compilation = text("TCMP").is_some_and(is_flag_set);
+    let (track_number, total_tracks) = text("TRCK").map(parse_number_pair).unwrap_or_default();
+    let (disc_number, total_discs) = text("TPOS").map(parse_number_pair).unwrap_or_default();
+    TrackMetadata {
+        track_number,
+        total_tracks,
+        disc_number,
+        total_discs,
+        compilation,
+        ..tag_metadata(&Id3v2Tag::from(tag))
+    }
+}
+
+fn read_flac_tag(path: &Path) -> Result<TrackMetadata, String> {
+    let tag = metaflac::Tag::read_from_path(path).map_err(|e| e.to_string())?;
+    let first = |key: &str| tag.get_vorbis(key).and_then(|mut values| values.next());
+    let compilation = tag.get_vorbis("COMPILATION")
+        .is_some_and(|mut values| values.any(is_flag_set));
+    let total = |keys: [&'static str; 2]| {
+        keys.into_iter().find_map(|key| first(key).and_then(|text| parse_number_pair(text).0))
+    };
+    let (track_number, total_tracks) = first("TRACKNUMBER").map(parse_number_pair)
+        .unwrap_or_default();
+    let (disc_number, total_discs) = first("DISCNUMBER").map(parse_number_pair)
+        .unwrap_or_default();
+    let stream_info = tag.get_streaminfo()
+        .filter(|stream_info| stream_info.sample_rate > 0)
+        .map(|info| (info.sample_rate, info.num_channels, info.total_samples));
+    let mut metadata = TrackMetadata {
+        track_number,
+        total_tracks: total_tracks.or_else(|| total(["TRACKTOTAL", "TOTALTRACKS"])),
+        disc_number,
+        total_discs: total_discs.or_else(|| total(["DISCTOTAL", "TOTALDISCS"])),
+        compilation,
+        codec: Some(Codec::Flac),
+        ..tag_metadata(&FlacTag::from(tag))
+    };
+    if let Some((sample_rate, channels, total_samples)) = stream_info {
+        metadata.sample_rate = Some(sample_rate);
+        metadata.channels = Some(channels);
+        // The total is 0 when the encoder didn't know it
+        if total_samples > 0 {
+            let duration = Duration::from_secs_f64(total_samples as f64 / f64::from(sample_rate));
+            metadata.duration = Some(duration);
+        }
+    }
+    Ok(metadata)
+}
+
+fn read_mp4_tag(path: &Path) -> Result<TrackMetadata, String> {
+    let tag = mp4ameta::Tag::read_from_path(path).map_err(|e| e.to_string())?;
+    let audio_info = tag.audio_info().clone();
+    // mp4ameta only reads the audio info of AAC tracks
+    let codec = if audio_info.sample_rate.is_some() { Codec::Aac } else { Codec::Alac };
+    Ok(TrackMetadata {
+        track_number: tag.track_number().filter(|n| *n > 0),
+        total_tracks: tag.total_tracks().filter(|n| *n > 0),
+        disc_number: tag.disc_number().filter(|n| *n > 0),
+        total_discs: tag.total_discs().filter(|n| *n > 0),
+        compilation: tag.compilation(),
+        duration: audio_info.duration,
+        bitrate: audio_info.avg_bitrate.map(|bitrate| bitrate / 1000),
+        sample_rate: audio_info.sample_rate.map(|sample_rate| sample_rate.hz()),
+        channels: audio_info.channel_config.map(|channels| channels.channel_count()),
+        codec: Some(codec),
+        ..tag_metadata(&Mp4Tag::from(tag))
+    })
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<TrackMetadata, SkipReason> {
+    let Some(file_extension) = path.extension() else {
+        return Err(SkipReason::NoExtension)
+    };
+    let file_extension = file_extension.to_string_lossy().to_lowercase();
+    let res = match file_extension.as_str() {
+        "flac" => read_flac_tag(path),
+        "mp3" => read_id3_tag(path),
+        "m4a" | "m4b" | "mp4" | "alac" => read_mp4_tag(path),
+        _ => return Err(SkipReason::UnsupportedExtension(file_extension)),
+    };
+    let mut metadata = res.map_err(SkipReason::UnreadableTag)?;
+    metadata.file_size = path.metadata().ok().map(|file_metadata| file_metadata.len());
+    if metadata.bitrate.is_none() {
+        metadata.bitrate = metadata.file_size.zip(metadata.duration)
+            .and_then(|(file_size, duration)| TrackMetadata::average_bitrate(file_size, duration));
+    }
+    Ok(metadata)
+}
+
+fn read_files(paths: &[PathBuf]) -> Vec<SongFile> {
+    paths.iter().map(|path| SongFile { path: path.clone(), metadata: read_file(path) }).collect()
+}
+
+/// Number of files read as one piece of work, so that a single large directory is still spread
+/// across the workers.
+const FILES_PER_CHUNK: usize = 32;
+
+/// How many chunks workers may get ahead of the one being visited, per worker, which bounds how
+/// many tags are held in memory at once.
+const CHUNKS_AHEAD_PER_JOB: usize = 2;
+
+/// Number of chunks that have been visited, which workers wait on so they don't get too far
+/// ahead.
+struct Progress {
+    visited: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn set(&self, visited: usize) {
+        *self.visited.lock().unwrap_or_else(|e| e.into_inner()) = visited;
+        self.changed.notify_all();
+    }
+
+    fn wait_until_within(&self, i: usize, window: usize) {
+        let mut visited = self.visited.lock().unwrap_or_else(|e| e.into_inner());
+        while i >= *visited + window {
+            visited = self.changed.wait(visited).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Releases any waiting workers once the chunks stop being visited, including when the visitor
+/// panics, so that they can finish rather than blocking forever.
+struct ReleaseWorkers<'p>(&'p Progress);
+
+impl Drop for ReleaseWorkers<'_> {
+    fn drop(&mut self) {
+        self.0.set(usize::MAX / 2);
+    }
+}
+
+/// Reads the tags of the files in each of `dirs` on up to `jobs` threads, handing each
+/// directory's reads to `visit` on the calling thread in the order the directories were given
+/// in, so that nothing downstream depends on which thread finishes first. Directories are read
+/// in chunks of files, so even a single flat directory gets read concurrently.
+pub(crate) fn read_dirs(dirs: &[Vec<PathBuf>], jobs: usize, visit: &mut dyn FnMut(Vec<SongFile>)) {
+    // Each chunk is the index of its directory and some of the directory's files. An empty
+    // directory still gets a chunk so that it's visited like any other
+    let chunks: Vec<(usize, &[PathBuf])> = dirs.iter()
+        .enumerate()
+        .flat_map(|(i, dir_paths)| {
+            let dir_chunks: Vec<&[PathBuf]> = match dir_paths.is_empty() {
+                true => vec![dir_paths],
+                false => dir_paths.chunks(FILES_PER_CHUNK).collect(),
+            };
+            dir_chunks.into_iter().map(move |chunk| (i, chunk))
+        })
+        .collect();
+    let jobs = jobs.min(chunks.len());
+    if jobs <= 1 {
+        for dir_paths in dirs.iter() {
+            visit(read_files(dir_paths));
+        }
+        return
+    }
+    let window = jobs * CHUNKS_AHEAD_PER_JOB;
+    let next_chunk = AtomicUsize::new(0);
+    let progress = Progress { visited: Mutex::new(0), changed: Condvar::new() };
+    let (sender, receiver) = channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let (chunks, next_chunk, progress) = (&chunks, &next_chunk, &progress);
+            scope.spawn(move || loop {
+                let i = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if i >= chunks.len() {
+                    break
+                }
+                progress.wait_until_within(i, window);
+                if sender.send((i, read_files(chunks[i].1))).is_err() {
+                    break
+                }
+            });
+        }
+        drop(sender);
+        let _release_workers = ReleaseWorkers(&progress);
+        let mut finished = HashMap::new();
+        let mut visited = 0;
+        let mut dir_reads = Vec::new();
+        for (i, reads) in receiver {
+            finished.insert(i, reads);
+            while let Some(reads) = finished.remove(&visited) {
+                dir_reads.extend(reads);
+                let dir = chunks[visited].0;
+                visited += 1;
+                let is_last_chunk = chunks.get(visited)
+                    .is_none_or(|(next_dir, _)| *next_dir != dir);
+                if is_last_chunk {
+                    visit(std::mem::take(&mut dir_reads));
+                }
+                progress.set(visited);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, create_dir, write};
+
+    use id3::Version;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn number_pairs_parsed() {
+        assert_eq!(parse_number_pair("3"), (Some(3), None));
+        assert_eq!(parse_number_pair("03/12"), (Some(3), Some(12)));
+        assert_eq!(parse_number_pair(" 3 / 12 "), (Some(3), Some(12)));
+        assert_eq!(parse_number_pair("0/12"), (None, Some(12)));
+        assert_eq!(parse_number_pair("A1"), (None, None));
+    }
+
+    #[test]
+    fn mp3_metadata_read_from_tag_and_stream() {
+        let indir = tempdir().unwrap();
+        let path = indir.as_ref().join("A.mp3");
+        // A second of 128 kbit/s, 44.1 kHz joint stereo audio
+        let mut audio = vec![0xff, 0xfb, 0x90, 0x44];
+        audio.resize(16_000, 0);
+        write(&path, &audio).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_artist("Artist");
+        tag.set_title("Title");
+        tag.set_text("TCOM", "Composer");
+        tag.set_text("TRCK", "3/12");
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let metadata = read_file(&path).unwrap();
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.composer.as_deref(), Some("Composer"));
+        assert_eq!((metadata.track_number, metadata.total_tracks), (Some(3), Some(12)));
+        assert_eq!(metadata.codec, Some(Codec::Mp3));
+        assert_eq!(metadata.duration, Some(Duration::from_secs(1)));
+        assert_eq!(metadata.bitrate, Some(128));
+        assert_eq!(metadata.sample_rate, Some(44100));
+        assert_eq!(metadata.channels, Some(2));
+        assert_eq!(metadata.file_size, Some(path.metadata().unwrap().len()));
+    }
+
+    #[test]
+    fn dirs_visited_in_order_whatever_the_jobs() {
+        let indir = tempdir().unwrap();
+        let mut dirs = Vec::new();
+        for i in 0..20 {
+            let dir = indir.as_ref().join(format!("F{:02}", i));
+            create_dir(&dir).unwrap();
+            let path = dir.join("A.txt");
+            File::create(&path).unwrap();
+            dirs.push(vec![path]);
+        }
+        // A flat directory big enough to be split into several chunks
+        let flat_dir = indir.as_ref().join("Flat");
+        create_dir(&flat_dir).unwrap();
+        let flat_paths: Vec<PathBuf> = (0..FILES_PER_CHUNK * 3 + 1)
+            .map(|i| flat_dir.join(format!("{:03}.txt", i)))
+            .collect();
+        for path in flat_paths.iter() {
+            File::create(path).unwrap();
+        }
+        dirs.insert(10, flat_paths);
+        dirs.push(Vec::new());
+        for jobs in [1, 3, 8] {
+            let mut visited = Vec::new();
+            let mut dir_sizes = Vec::new();
+            read_dirs(&dirs, jobs, &mut |song_files| {
+                dir_sizes.push(song_files.len());
+                visited.extend(song_files.into_iter().map(|song_file| {
+                    assert_eq!(
+                        song_file.metadata.err(),
+                        Some(SkipReason::UnsupportedExtension(String::from("txt"))),
+                    );
+                    song_file.path
+                }));
+            });
+            assert_eq!(visited, dirs.concat(), "Jobs: {}", jobs);
+            // Each directory is still visited once, with all of its files
+            let expected_sizes: Vec<usize> = dirs.iter().map(Vec::len).collect();
+            assert_eq!(dir_sizes, expected_sizes, "Jobs: {}", jobs);
+        }
+    }
+}
@@ -0,0 +1,663 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::MissingSongInfo;
+use crate::metadata::TrackMetadata;
+use crate::sanitize::Sanitizer;
+
+pub const DEFAULT_TEMPLATE: &str =
+    "{group}/{album}/[Disc {multidisc}]/[{track:02} ]{title|filename}.{ext}";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    Empty,
+    EmptyComponent,
+    TraversalComponent,
+    UnmatchedBrace,
+    UnterminatedPlaceholder,
+    UnterminatedString,
+    EmptyPlaceholder,
+    UnknownField(String),
+    InvalidFormatSpec(String),
+    MisplacedFallback(String),
+    UnmatchedBracket,
+    UnterminatedSection,
+    NestedSection,
+    OptionalFileName,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Empty => write!(f, "Template is empty"),
+            TemplateError::EmptyComponent => {
+                write!(f, "Template contains an empty path component")
+            },
+            TemplateError::TraversalComponent => {
+                write!(f, "Template contains a '.' or '..' path component")
+            },
+            TemplateError::UnmatchedBrace => {
+                write!(f, "Template contains an unmatched '}}' (use '}}}}' for a literal brace)")
+            },
+            TemplateError::UnterminatedPlaceholder => {
+                write!(f, "Template contains a placeholder with no closing '}}'")
+            },
+            TemplateError::UnterminatedString => {
+                write!(f, "Template contains a fallback string with no closing '\"'")
+            },
+            TemplateError::EmptyPlaceholder => write!(f, "Template contains an empty placeholder"),
+            TemplateError::UnknownField(field) => {
+                write!(f, "Template contains unknown field: {}", field)
+            },
+            TemplateError::InvalidFormatSpec(spec) => {
+                write!(f, "Template contains invalid format spec: {}", spec)
+            },
+            TemplateError::MisplacedFallback(fallback) => {
+                write!(f, "Template fallback string {} must be the last alternative", fallback)
+            },
+            TemplateError::UnmatchedBracket => {
+                write!(f, "Template contains an unmatched ']' (use ']]' for a literal bracket)")
+            },
+            TemplateError::UnterminatedSection => write!(
+                f,
+                "Template contains an optional section with no closing ']' before the end of its \
+                    path component",
+            ),
+            TemplateError::NestedSection => {
+                write!(f, "Template contains an optional section inside another")
+            },
+            TemplateError::OptionalFileName => {
+                write!(f, "Template file name can't be made up only of optional sections")
+            },
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Artist,
+    AlbumArtist,
+    /// The album artist, the various artists name for compilations and albums with many
+    /// artists, or else the artist
+    Group,
+    Album,
+    Title,
+    Year,
+    /// The release date as tagged, which may be a full date or only a year
+    Date,
+    Genre,
+    Composer,
+    Track,
+    TotalTracks,
+    Disc,
+    TotalDiscs,
+    /// The disc number, but only for songs from an album that spans more than one disc
+    MultiDisc,
+    /// How the audio is encoded, e.g. MP3 or FLAC
+    Codec,
+    Filename,
+    Ext,
+}
+
+impl Field {
+    pub(crate) fn from_name(name: &str) -> Option<Field> {
+        let field = match name {
+            "artist" => Field::Artist,
+            "albumartist" => Field::AlbumArtist,
+            "group" => Field::Group,
+            "album" => Field::Album,
+            "title" => Field::Title,
+            "year" => Field::Year,
+            "date" => Field::Date,
+            "genre" => Field::Genre,
+            "composer" => Field::Composer,
+            "track" => Field::Track,
+            "totaltracks" => Field::TotalTracks,
+            "disc" => Field::Disc,
+            "totaldiscs" => Field::TotalDiscs,
+            "multidisc" => Field::MultiDisc,
+            "codec" => Field::Codec,
+            "filename" => Field::Filename,
+            "ext" => Field::Ext,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Artist => "artist",
+            Field::AlbumArtist => "albumartist",
+            Field::Group => "group",
+            Field::Album => "album",
+            Field::Title => "title",
+            Field::Year => "year",
+            Field::Date => "date",
+            Field::Genre => "genre",
+            Field::Composer => "composer",
+            Field::Track => "track",
+            Field::TotalTracks => "totaltracks",
+            Field::Disc => "disc",
+            Field::TotalDiscs => "totaldiscs",
+            Field::MultiDisc => "multidisc",
+            Field::Codec => "codec",
+            Field::Filename => "filename",
+            Field::Ext => "ext",
+        }
+    }
+
+    fn value(&self, metadata: &TrackMetadata, source: &Path) -> Option<String> {
+        let value = match self {
+            Field::Artist => metadata.artist.clone(),
+            Field::AlbumArtist => metadata.album_artist.clone(),
+            Field::Group => metadata.album_artist.as_ref()
+                .filter(|album_artist| !album_artist.is_empty())
+                .or(metadata.album_group.as_ref())
+                .or(metadata.artist.as_ref())
+                .cloned(),
+            Field::Album => metadata.album.clone(),
+            Field::Title => metadata.title.clone(),
+            Field::Year => metadata.year.map(|year| year.to_string()),
+            Field::Date => metadata.date.clone(),
+            Field::Genre => metadata.genre.clone(),
+            Field::Composer => metadata.composer.clone(),
+            Field::Track => metadata.track_number.map(|track| track.to_string()),
+            Field::TotalTracks => metadata.total_tracks.map(|total| total.to_string()),
+            Field::Disc => metadata.disc_number.map(|disc| disc.to_string()),
+            Field::TotalDiscs => metadata.total_discs.map(|total| total.to_string()),
+            Field::MultiDisc => {
+                let discs = metadata.total_discs.max(metadata.album_discs);
+                metadata.disc_number
+                    .filter(|disc| *disc > 1 || discs.is_some_and(|total| total > 1))
+                    .map(|disc| disc.to_string())
+            },
+            Field::Codec => metadata.codec.map(|codec| codec.to_string()),
+            Field::Filename => source.file_stem().map(|stem| stem.to_string_lossy().to_string()),
+            Field::Ext => source.extension().map(|ext| ext.to_string_lossy().to_string()),
+        };
+        // A tag can hold an empty string, which is no more use for naming than a missing value
+        value.filter(|val| !val.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Alternative {
+    Field(Field),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder {
+        alternatives: Vec<Alternative>,
+        width: Option<usize>,
+    },
+    /// Left out entirely when any of its placeholders has no value
+    Optional(Vec<Part>),
+}
+
+/// A destination path layout such as `{albumartist|artist}/{album}/{track:02} {title}.{ext}`,
+/// rendered relative to the output directory for each song file.
+///
+/// Placeholders name a field, optionally followed by `|`-separated fallbacks which are tried in
+/// order, the last of which may be a quoted string used when no field has a value. A `:0N`
+/// suffix zero-pads the value to `N` characters. Text in `[...]` is optional: it's left out
+/// rather than failing when a placeholder in it has no value, and a directory made up only of
+/// optional text is left out when none of it remains. `/` separates path components, and
+/// `{{`/`}}` and `[[`/`]]` produce literal braces and brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    components: Vec<Vec<Part>>,
+}
+
+impl Default for PathTemplate {
+    fn default() -> PathTemplate {
+        DEFAULT_TEMPLATE.parse().expect("Default template should be valid")
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<PathTemplate, TemplateError> {
+        if template.is_empty() {
+            return Err(TemplateError::Empty)
+        }
+        let mut components = Vec::new();
+        let mut parts = Vec::new();
+        // Parts of the optional section being parsed, if any
+        let mut section: Option<Vec<Part>> = None;
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '[' if chars.peek() == Some(&'[') => {
+                    chars.next();
+                    literal.push('[');
+                },
+                ']' if chars.peek() == Some(&']') => {
+                    chars.next();
+                    literal.push(']');
+                },
+                '}' => return Err(TemplateError::UnmatchedBrace),
+                '{' => {
+                    let target = section.as_mut().unwrap_or(&mut parts);
+                    if !literal.is_empty() {
+                        target.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    let mut body = String::new();
+                    let mut in_string = false;
+                    loop {
+                        match chars.next() {
+                            None => return Err(TemplateError::UnterminatedPlaceholder),
+                            Some('}') if !in_string => break,
+                            Some(c) => {
+                                if c == '"' {
+                                    in_string = !in_string;
+                                }
+                                body.push(c);
+                            },
+                        }
+                    }
+                    target.push(parse_placeholder(&body)?);
+                },
+                '[' => {
+                    if section.is_some() {
+                        return Err(TemplateError::NestedSection)
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    section = Some(Vec::new());
+                },
+                ']' => {
+                    let Some(mut section_parts) = section.take() else {
+                        return Err(TemplateError::UnmatchedBracket)
+                    };
+                    if !literal.is_empty() {
+                        section_parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Optional(section_parts));
+                },
+                '/' => {
+                    if section.is_some() {
+                        return Err(TemplateError::UnterminatedSection)
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(validate_component(std::mem::take(&mut parts))?);
+                },
+                _ => literal.push(c),
+            }
+        }
+        if section.is_some() {
+            return Err(TemplateError::UnterminatedSection)
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        let file_name = validate_component(parts)?;
+        if file_name.iter().all(|part| matches!(part, Part::Optional(_))) {
+            return Err(TemplateError::OptionalFileName)
+        }
+        components.push(file_name);
+        Ok(PathTemplate { components })
+    }
+}
+
+fn validate_component(parts: Vec<Part>) -> Result<Vec<Part>, TemplateError> {
+    match parts.as_slice() {
+        [] => Err(TemplateError::EmptyComponent),
+        [Part::Literal(text)] if text == "." || text == ".." => {
+            Err(TemplateError::TraversalComponent)
+        },
+        _ => Ok(parts),
+    }
+}
+
+fn split_outside_quotes(text: &str, separator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            in_string = !in_string;
+        } else if c == separator && !in_string {
+            pieces.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+fn parse_placeholder(body: &str) -> Result<Part, TemplateError> {
+    let mut pieces = split_outside_quotes(body, ':');
+    let width = if pieces.len() > 1 {
+        let spec = pieces.pop().expect("Split always returns at least one piece");
+        let is_valid_spec = spec.len() > 1
+            && spec.starts_with('0')
+            && spec.chars().all(|c| c.is_ascii_digit());
+        if pieces.len() > 1 || !is_valid_spec {
+            return Err(TemplateError::InvalidFormatSpec(spec.to_string()))
+        }
+        Some(spec[1..].parse().map_err(|_| TemplateError::InvalidFormatSpec(spec.to_string()))?)
+    } else {
+        None
+    };
+
+    let raw_alternatives = split_outside_quotes(pieces[0], '|');
+    let mut alternatives = Vec::new();
+    for (i, raw_alternative) in raw_alternatives.iter().enumerate() {
+        let raw_alternative = raw_alternative.trim();
+        if raw_alternative.is_empty() {
+            return Err(TemplateError::EmptyPlaceholder)
+        }
+        if let Some(quoted) = raw_alternative.strip_prefix('"') {
+            let literal = quoted.strip_suffix('"').ok_or(TemplateError::UnterminatedString)?;
+            if i != raw_alternatives.len() - 1 {
+                return Err(TemplateError::MisplacedFallback(raw_alternative.to_string()))
+            }
+            alternatives.push(Alternative::Literal(literal.to_string()));
+        } else {
+            let field = Field::from_name(raw_alternative)
+                .ok_or(TemplateError::UnknownField(raw_alternative.to_string()))?;
+            alternatives.push(Alternative::Field(field));
+        }
+    }
+    Ok(Part::Placeholder { alternatives, width })
+}
+
+impl PathTemplate {
+    /// Renders the destination path of the song file at `source`, relative to the output
+    /// directory, with every component made safe by `sanitizer`. Fails with the first field the
+    /// template needs that has no value and no fallback.
+    pub fn render(
+        &self,
+        metadata: &TrackMetadata,
+        source: &Path,
+        sanitizer: &Sanitizer,
+    ) -> Result<PathBuf, MissingSongInfo> {
+        let mut path = PathBuf::new();
+        for (i, component) in self.components.iter().enumerate() {
+            let rendered = render_parts(component, metadata, source, sanitizer)?;
+            let is_only_optional = component.iter().all(|part| matches!(part, Part::Optional(_)));
+            if rendered.is_empty() && is_only_optional {
+                continue;
+            }
+            let is_file_name = i == self.components.len() - 1;
+            path.push(sanitizer.sanitize_component(&rendered, is_file_name));
+        }
+        Ok(path)
+    }
+}
+
+fn render_parts(
+    parts: &[Part],
+    metadata: &TrackMetadata,
+    source: &Path,
+    sanitizer: &Sanitizer,
+) -> Result<String, MissingSongInfo> {
+    let mut rendered = String::new();
+    for part in parts.iter() {
+        match part {
+            Part::Literal(text) => rendered.push_str(text),
+            Part::Placeholder { alternatives, width } => {
+                let value = alternatives.iter().find_map(|alternative| {
+                    match alternative {
+                        Alternative::Field(field) => field.value(metadata, source),
+                        Alternative::Literal(text) => Some(text.clone()),
+                    }
+                });
+                let value = match value {
+                    Some(val) => sanitizer.sanitize_value(&val),
+                    None => {
+                        let missing_field = match alternatives[0] {
+                            // Only the artist can be relied on to give a group
+                            Alternative::Field(Field::Group) => Field::Artist.name(),
+                            Alternative::Field(field) => field.name(),
+                            Alternative::Literal(_) => unreachable!(
+                                "Placeholders starting with a literal always have a value"
+                            ),
+                        };
+                        return Err(MissingSongInfo { missing_field: String::from(missing_field) })
+                    }
+                };
+                match width {
+                    Some(width) => rendered.push_str(&format!("{:0>width$}", value)),
+                    None => rendered.push_str(&value),
+                }
+            },
+            Part::Optional(section_parts) => {
+                if let Ok(section) = render_parts(section_parts, metadata, source, sanitizer) {
+                    rendered.push_str(&section);
+                }
+            },
+        }
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::Codec;
+    use crate::sanitize::SanitizeProfile;
+
+    use super::*;
+
+    fn render(
+        template: &PathTemplate,
+        metadata: &TrackMetadata,
+        source: &str,
+    ) -> Result<PathBuf, MissingSongInfo> {
+        template.render(metadata, Path::new(source), &Sanitizer::default())
+    }
+
+    fn full_metadata() -> TrackMetadata {
+        TrackMetadata {
+            artist: Some(String::from("Track Artist")),
+            album_artist: Some(String::from("Album Artist")),
+            album: Some(String::from("Album")),
+            title: Some(String::from("Title")),
+            year: Some(1999),
+            genre: Some(String::from("Rock")),
+            track_number: Some(3),
+            total_tracks: Some(12),
+            disc_number: Some(1),
+            total_discs: Some(2),
+            ..TrackMetadata::default()
+        }
+    }
+
+    #[test]
+    fn default_template_matches_album_artist_album_disc_track_title_layout() {
+        let template = PathTemplate::default();
+        let path = render(&template, &full_metadata(), "/in/F00/ABCD.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Album Artist/Album/Disc 1/03 Title.mp3"));
+    }
+
+    #[test]
+    fn composer_date_and_codec_fields() {
+        let template: PathTemplate = "{composer}/{date} {album} [{codec}]/{title}.{ext}".parse().unwrap();
+        let metadata = TrackMetadata {
+            composer: Some(String::from("Composer")),
+            date: Some(String::from("1999-05-14")),
+            codec: Some(Codec::Flac),
+            ..full_metadata()
+        };
+        let path = render(&template, &metadata, "/in/A.flac").unwrap();
+        assert_eq!(path, PathBuf::from("Composer/1999-05-14 Album FLAC/Title.flac"));
+        let err = render(&template, &full_metadata(), "/in/A.flac").unwrap_err();
+        assert_eq!(err.missing_field, "composer");
+    }
+
+    #[test]
+    fn default_template_leaves_out_missing_numbers_and_single_disc() {
+        let template = PathTemplate::default();
+        let metadata = TrackMetadata { total_discs: Some(1), ..full_metadata() };
+        let path = render(&template, &metadata, "/in/F00/ABCD.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Album Artist/Album/03 Title.mp3"));
+        let metadata = TrackMetadata { disc_number: None, track_number: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/F00/ABCD.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Album Artist/Album/Title.mp3"));
+    }
+
+    #[test]
+    fn multidisc_given_by_disc_number_without_total() {
+        let template: PathTemplate = "[{multidisc}-]{track:02} {title}.{ext}".parse().unwrap();
+        let metadata = TrackMetadata { disc_number: Some(2), total_discs: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("2-03 Title.mp3"));
+        let metadata = TrackMetadata { disc_number: Some(1), total_discs: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("03 Title.mp3"));
+        // Unless another disc of the album was found alongside it
+        let metadata = TrackMetadata { album_discs: Some(2), ..metadata };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("1-03 Title.mp3"));
+    }
+
+    #[test]
+    fn optional_section_left_out_only_when_missing() {
+        let template: PathTemplate =
+            "{artist}/[{year} - ]{album}[ ({genre})]/{title}.{ext}".parse().unwrap();
+        let path = render(&template, &full_metadata(), "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Track Artist/1999 - Album (Rock)/Title.mp3"));
+        let metadata = TrackMetadata { year: None, genre: Some(String::from("")), ..full_metadata() };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Track Artist/Album/Title.mp3"));
+        // Fields outside sections are still required
+        let metadata = TrackMetadata { album: None, ..full_metadata() };
+        let err = render(&template, &metadata, "/in/A.mp3").unwrap_err();
+        assert_eq!(err.to_string(), "Missing field: album");
+    }
+
+    #[test]
+    fn group_falls_back_to_album_group_then_artist() {
+        let template: PathTemplate = "{group}/{title}.{ext}".parse().unwrap();
+        let metadata = TrackMetadata {
+            album_artist: Some(String::from("")),
+            album_group: Some(String::from("Various Artists")),
+            ..full_metadata()
+        };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Various Artists/Title.mp3"));
+        let metadata = TrackMetadata { album_group: None, ..metadata };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Track Artist/Title.mp3"));
+        let metadata = TrackMetadata { artist: None, ..metadata };
+        let err = render(&template, &metadata, "/in/A.mp3").unwrap_err();
+        assert_eq!(err.to_string(), "Missing field: artist");
+    }
+
+    #[test]
+    fn default_template_falls_back_to_filename() {
+        let template = PathTemplate::default();
+        let metadata = TrackMetadata { title: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/F00/ABCD.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Album Artist/Album/Disc 1/03 ABCD.mp3"));
+    }
+
+    #[test]
+    fn zero_padded_fields() {
+        let template: PathTemplate =
+            "{albumartist}/{year} - {album}/{disc:02}-{track:02} {title}.{ext}".parse().unwrap();
+        let path = render(&template, &full_metadata(), "/in/F00/ABCD.flac").unwrap();
+        assert_eq!(path, PathBuf::from("Album Artist/1999 - Album/01-03 Title.flac"));
+    }
+
+    #[test]
+    fn fallbacks_tried_in_order() {
+        let template: PathTemplate =
+            "{albumartist|artist|\"Unknown Artist\"}/{title}.{ext}".parse().unwrap();
+        let metadata = TrackMetadata { album_artist: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Track Artist/Title.mp3"));
+        let metadata = TrackMetadata { album_artist: None, artist: None, ..full_metadata() };
+        let path = render(&template, &metadata, "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("Unknown Artist/Title.mp3"));
+    }
+
+    #[test]
+    fn missing_field_without_fallback() {
+        let template: PathTemplate = "{genre|artist}/{album}/{title}.{ext}".parse().unwrap();
+        let metadata = TrackMetadata { genre: None, artist: None, ..full_metadata() };
+        let err = render(&template, &metadata, "/in/A.mp3").unwrap_err();
+        assert_eq!(err.to_string(), "Missing field: genre");
+    }
+
+    #[test]
+    fn empty_tag_value_treated_as_missing() {
+        let template = PathTemplate::default();
+        let metadata = TrackMetadata { album: Some(String::from("")), ..full_metadata() };
+        let err = render(&template, &metadata, "/in/A.mp3").unwrap_err();
+        assert_eq!(err.to_string(), "Missing field: album");
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let template: PathTemplate = "{{{artist}}}/[[{title}]].{ext}".parse().unwrap();
+        let path = render(&template, &full_metadata(), "/in/A.mp3").unwrap();
+        assert_eq!(path, PathBuf::from("{Track Artist}/[Title].mp3"));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let cases = [
+            ("", TemplateError::Empty),
+            ("/{artist}", TemplateError::EmptyComponent),
+            ("{artist}//{title}", TemplateError::EmptyComponent),
+            ("{artist}/", TemplateError::EmptyComponent),
+            ("../{artist}", TemplateError::TraversalComponent),
+            ("{artist}/./{title}", TemplateError::TraversalComponent),
+            ("{artist", TemplateError::UnterminatedPlaceholder),
+            ("artist}", TemplateError::UnmatchedBrace),
+            ("{}", TemplateError::EmptyPlaceholder),
+            ("{artist|}", TemplateError::EmptyPlaceholder),
+            ("{mood}", TemplateError::UnknownField(String::from("mood"))),
+            ("{track:2}", TemplateError::InvalidFormatSpec(String::from("2"))),
+            ("{track:0x}", TemplateError::InvalidFormatSpec(String::from("0x"))),
+            ("{\"Unknown|artist}", TemplateError::UnterminatedPlaceholder),
+            ("{\"Unknown\"|artist}", TemplateError::MisplacedFallback(String::from("\"Unknown\""))),
+            ("{artist}]/{title}", TemplateError::UnmatchedBracket),
+            ("[{artist}/{title}]", TemplateError::UnterminatedSection),
+            ("{artist}/[{title}", TemplateError::UnterminatedSection),
+            ("[{disc}[{track}]]/{title}", TemplateError::NestedSection),
+            ("{artist}/[{title}.{ext}]", TemplateError::OptionalFileName),
+        ];
+        for (template, expected_err) in cases {
+            assert_eq!(template.parse::<PathTemplate>(), Err(expected_err), "Template: {}", template);
+        }
+    }
+
+    #[test]
+    fn values_sanitized_into_single_component() {
+        let template = PathTemplate::default();
+        let metadata = TrackMetadata {
+            album_artist: Some(String::from("AC/DC")),
+            album: Some(String::from("..")),
+            title: Some(String::from("Live: 1/2")),
+            ..full_metadata()
+        };
+        let sanitizer = Sanitizer::default();
+        let path = template.render(&metadata, Path::new("/in/A.mp3"), &sanitizer).unwrap();
+        assert_eq!(path, PathBuf::from("AC_DC/_/Disc 1/03 Live_ 1_2.mp3"));
+        let sanitizer = Sanitizer::new(SanitizeProfile::Posix, "-", 255).unwrap();
+        let path = template.render(&metadata, Path::new("/in/A.mp3"), &sanitizer).unwrap();
+        assert_eq!(path, PathBuf::from("AC-DC/-/Disc 1/03 Live: 1-2.mp3"));
+    }
+}
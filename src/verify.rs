@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::{read_dir, remove_dir, symlink_metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::collision::{CollisionPolicy, numbered_destination};
+use crate::dedup::DUPLICATES_DIR;
+use crate::discover::{WalkOptions, is_hidden};
+use crate::error::MoverError;
+use crate::journal::Journal;
+use crate::pipeline::{Naming, NamedSong, discover, read_song_files};
+use crate::plan::{Plan, SkipReason, SkippedFile};
+use crate::playlist::{Playlist, PlaylistPaths, final_paths, is_playlist, rewritten_playlist};
+use crate::report::{PlaylistOutcome, RunReport};
+use crate::{execute_plan, write_playlists};
+
+/// A song file in the library that its tags say belongs somewhere else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Misplaced {
+    pub path: PathBuf,
+    pub expected: PathBuf,
+}
+
+/// How well a library organised by an earlier run still matches its song files' tags.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub in_place: usize,
+    pub misplaced: Vec<Misplaced>,
+    /// Song files whose tags can't be read or lack a field the template needs, so that where
+    /// they belong isn't known
+    pub unverifiable: Vec<SkippedFile>,
+    /// Directories with nothing in them but other empty directories, deepest first
+    pub empty_dirs: Vec<PathBuf>,
+    /// Files that are neither song files nor playlists
+    pub orphans: Vec<PathBuf>,
+    /// Playlists, whose entries are kept pointing at their song files when those are moved
+    pub playlists: Vec<PathBuf>,
+}
+
+/// Whether `path` is where a song file expected at `expected` was put when that was taken,
+/// e.g. `Intro (2).mp3` for `Intro.mp3`.
+fn is_numbered_copy(path: &Path, expected: &Path) -> bool {
+    let n = path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_suffix(')'))
+        .and_then(|stem| stem.rsplit_once(" ("))
+        .and_then(|(_, n)| n.parse().ok());
+    n.is_some_and(|n| numbered_destination(expected, n) == path)
+}
+
+impl Verification {
+    fn add(&mut self, library: &Path, named_song: NamedSong) {
+        match named_song.destination {
+            Ok(relative_path) => {
+                let expected = library.join(relative_path);
+                if named_song.path == expected || is_numbered_copy(&named_song.path, &expected) {
+                    self.in_place += 1;
+                } else {
+                    self.misplaced.push(Misplaced { path: named_song.path, expected });
+                }
+            },
+            Err(SkipReason::NoExtension | SkipReason::UnsupportedExtension(_)) => {
+                match is_playlist(&named_song.path) {
+                    true => self.playlists.push(named_song.path),
+                    false => self.orphans.push(named_song.path),
+                }
+            },
+            Err(reason) => self.unverifiable.push(SkippedFile { path: named_song.path, reason }),
+        }
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Song files in place: {}", self.in_place)?;
+        writeln!(f, "Misplaced song files ({}):", self.misplaced.len())?;
+        for misplaced in self.misplaced.iter() {
+            writeln!(f, "  {} -> {}", misplaced.path.display(), misplaced.expected.display())?;
+        }
+        writeln!(f, "Unverifiable song files ({}):", self.unverifiable.len())?;
+        for skipped in self.unverifiable.iter() {
+            writeln!(f, "  {} ({})", skipped.path.display(), skipped.reason)?;
+        }
+        writeln!(f, "Empty directories ({}):", self.empty_dirs.len())?;
+        for dir in self.empty_dirs.iter() {
+            writeln!(f, "  {}", dir.display())?;
+        }
+        writeln!(f, "Orphan files ({}):", self.orphans.len())?;
+        for orphan in self.orphans.iter() {
+            writeln!(f, "  {}", orphan.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the empty directories under `dir` to `empty_dirs`, deepest first, along with `dir`
+/// itself if it's empty, which is returned.
+fn collect_empty_dirs(
+    dir: &Path,
+    include_hidden: bool,
+    empty_dirs: &mut Vec<PathBuf>,
+) -> io::Result<bool> {
+    let mut paths = read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    let mut is_empty = true;
+    for path in paths {
+        // Hidden files are still something a directory can't be removed with
+        let is_searched_dir = symlink_metadata(&path)?.is_dir()
+            && (include_hidden || !is_hidden(&path));
+        if !is_searched_dir || !collect_empty_dirs(&path, include_hidden, empty_dirs)? {
+            is_empty = false;
+        }
+    }
+    if is_empty {
+        empty_dirs.push(dir.to_path_buf());
+    }
+    Ok(is_empty)
+}
+
+/// The empty directories in `library`, deepest first, not counting `library` itself.
+pub fn find_empty_dirs(library: &Path, include_hidden: bool) -> io::Result<Vec<PathBuf>> {
+    let mut empty_dirs = Vec::new();
+    if collect_empty_dirs(library, include_hidden, &mut empty_dirs)? {
+        empty_dirs.pop();
+    }
+    Ok(empty_dirs)
+}
+
+/// Checks that the song files in `library` are where `naming` says they belong, and looks for
+/// empty directories and files that aren't song files. The duplicates dir is left out, since
+/// what's in it was set aside on purpose.
+pub fn verify(
+    library: &Path,
+    walk_options: &WalkOptions,
+    naming: Naming,
+) -> Result<Verification, MoverError> {
+    let duplicates_dir = library.join(DUPLICATES_DIR);
+    let mut file_paths = discover(library, walk_options)?;
+    file_paths.retain(|path| !path.starts_with(&duplicates_dir));
+    let mut verification = Verification::default();
+    let song_files = read_song_files(file_paths, walk_options.jobs);
+    for named_song in naming.name_song_files(song_files) {
+        verification.add(library, named_song);
+    }
+    verification.empty_dirs = find_empty_dirs(library, walk_options.include_hidden)
+        .map_err(|source| MoverError::Discovery { path: library.to_path_buf(), source })?;
+    verification.misplaced.sort_by(|a, b| a.path.cmp(&b.path));
+    verification.unverifiable.sort_by(|a, b| a.path.cmp(&b.path));
+    verification.orphans.sort();
+    verification.playlists.sort();
+    Ok(verification)
+}
+
+/// The moves made to fix a library, and the empty directories removed afterwards.
+#[derive(Debug)]
+pub struct LibraryFix {
+    pub report: RunReport,
+    pub removed_dirs: Vec<PathBuf>,
+}
+
+impl Display for LibraryFix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.report)?;
+        writeln!(f, "Removed empty directories ({}):", self.removed_dirs.len())?;
+        for dir in self.removed_dirs.iter() {
+            writeln!(f, "  {}", dir.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// The library's playlists that have entries for moved song files, rewritten in place to point
+/// at where those ended up. Errors reading the playlists are added to `errors`.
+fn playlists_to_rewrite(
+    library: &Path,
+    plan: &Plan,
+    failed: &[&Path],
+    playlist_paths: &[PathBuf],
+    errors: &mut Vec<MoverError>,
+) -> Vec<Playlist> {
+    let final_paths = final_paths(plan, failed);
+    let moved: HashSet<&PathBuf> = final_paths.iter()
+        .filter(|(source, path)| source != path)
+        .map(|(_, path)| path)
+        .collect();
+    let mut playlists = Vec::new();
+    for path in playlist_paths {
+        match rewritten_playlist(path, library, library, &final_paths) {
+            Ok(playlist) if playlist.entries.iter().any(|entry| moved.contains(&entry.path)) => {
+                // Rewritten where it is, even if it's an .m3u that would otherwise become .m3u8
+                playlists.push(Playlist { path: path.clone(), ..playlist });
+            },
+            Ok(_) => {},
+            Err(source) => {
+                warn!("Couldn't read playlist {:?}: {}", path, source);
+                errors.push(MoverError::Filesystem { path: path.clone(), source });
+            },
+        }
+    }
+    playlists
+}
+
+/// Moves the misplaced song files to where they belong, renaming them if that's taken, and
+/// rewrites the library's playlists to follow them. Both are recorded in a journal in
+/// `library` so that the moves can be undone. Empty directories,
+/// including any the moves leave behind, are then removed, which isn't recorded. Orphan files
+/// are left for the user to deal with.
+pub fn fix_library(
+    library: &Path,
+    walk_options: &WalkOptions,
+    verification: &Verification,
+) -> Result<LibraryFix, MoverError> {
+    let mut plan = Plan::new();
+    for misplaced in verification.misplaced.iter() {
+        if let Some(dir) = misplaced.expected.parent() {
+            plan.add_dir(dir.to_path_buf());
+        }
+        let source = misplaced.path.clone();
+        plan.add_move(source, misplaced.expected.clone(), CollisionPolicy::Rename)?;
+    }
+    let mut errors = Vec::new();
+    let mut journal_path = None;
+    let mut playlists: Vec<PlaylistOutcome> = Vec::new();
+    if !plan.moves.is_empty() {
+        let mut journal = Journal::create(library).map_err(|source| MoverError::Filesystem {
+            path: library.to_path_buf(),
+            source,
+        })?;
+        let res = execute_plan(&plan, &mut journal).and_then(|mut run_errors| {
+            let failed: Vec<PathBuf> = run_errors.iter()
+                .filter_map(|e| match e {
+                    MoverError::Filesystem { path, .. } => Some(path.clone()),
+                    _ => None,
+                })
+                .collect();
+            let failed: Vec<&Path> = failed.iter().map(PathBuf::as_path).collect();
+            let playlists = playlists_to_rewrite(
+                library,
+                &plan,
+                &failed,
+                &verification.playlists,
+                &mut run_errors,
+            );
+            let paths = PlaylistPaths::Relative;
+            let policy = CollisionPolicy::Overwrite;
+            let outcomes =
+                write_playlists(&playlists, paths, policy, &mut journal, &mut run_errors)?;
+            Ok((run_errors, outcomes))
+        });
+        journal.sync().map_err(|source| MoverError::Filesystem {
+            path: journal.path().to_path_buf(),
+            source,
+        })?;
+        (errors, playlists) = res?;
+        journal_path = Some(journal.path().to_path_buf());
+    }
+    let empty_dirs = find_empty_dirs(library, walk_options.include_hidden)
+        .map_err(|source| MoverError::Discovery { path: library.to_path_buf(), source })?;
+    let mut removed_dirs = Vec::new();
+    for dir in empty_dirs {
+        info!("Removing empty directory {:?}", dir);
+        match remove_dir(&dir) {
+            Ok(()) => removed_dirs.push(dir),
+            Err(source) => {
+                warn!("Couldn't remove directory {:?}: {}", dir, source);
+                errors.push(MoverError::Filesystem { path: dir, source });
+            },
+        }
+    }
+    let mut report = RunReport::new(plan, errors, false, journal_path);
+    report.playlists = playlists;
+    Ok(LibraryFix { report, removed_dirs })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, create_dir_all, write};
+
+    use id3::{Tag, TagLike};
+    use tempfile::tempdir;
+
+    use crate::album::DEFAULT_VARIOUS_ARTISTS;
+    use crate::sanitize::Sanitizer;
+    use crate::template::PathTemplate;
+
+    use super::*;
+
+    fn write_song(path: &Path, artist: &str, album: &str, title: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let mut tag = Tag::new();
+        tag.set_artist(artist);
+        tag.set_album(album);
+        tag.set_title(title);
+        File::create(path).unwrap();
+        tag.write_to_path(path, id3::Version::Id3v24).unwrap();
+    }
+
+    #[test]
+    fn numbered_copies_count_as_in_place() {
+        let expected = Path::new("/lib/Artist/Album/Intro.mp3");
+        assert!(is_numbered_copy(Path::new("/lib/Artist/Album/Intro (2).mp3"), expected));
+        assert!(!is_numbered_copy(Path::new("/lib/Artist/Album/Intro.mp3"), expected));
+        assert!(!is_numbered_copy(Path::new("/lib/Artist/Other/Intro (2).mp3"), expected));
+        assert!(!is_numbered_copy(Path::new("/lib/Artist/Album/Outro (2).mp3"), expected));
+    }
+
+    #[test]
+    fn empty_dirs_found_deepest_first() {
+        let library = tempdir().unwrap();
+        create_dir_all(library.as_ref().join("A/B/C")).unwrap();
+        create_dir_all(library.as_ref().join("D")).unwrap();
+        write(library.as_ref().join("D/song.mp3"), b"").unwrap();
+        create_dir_all(library.as_ref().join("E")).unwrap();
+        write(library.as_ref().join("E/.hidden"), b"").unwrap();
+        let empty_dirs = find_empty_dirs(library.as_ref(), false).unwrap();
+        let expected: Vec<PathBuf> = ["A/B/C", "A/B", "A"].iter()
+            .map(|dir| library.as_ref().join(dir))
+            .collect();
+        assert_eq!(empty_dirs, expected);
+    }
+
+    #[test]
+    fn library_verified_and_fixed() {
+        let library = tempdir().unwrap();
+        let library = library.as_ref();
+        write_song(&library.join("Artist/Album/Song A.mp3"), "Artist", "Album", "Song A");
+        // Retagged since it was organised
+        write_song(&library.join("Artist/Album/Song B.mp3"), "Artist", "Other Album", "Song B");
+        write(library.join("Artist/Album/cover.jpg"), b"").unwrap();
+        let playlist = "#EXTM3U\nArtist/Album/Song A.mp3\nArtist/Album/Song B.mp3\n";
+        write(library.join("Road Trip.m3u8"), playlist).unwrap();
+        create_dir_all(library.join("Leftover")).unwrap();
+        let (template, sanitizer) = (PathTemplate::default(), Sanitizer::default());
+        let naming = Naming {
+            template: &template,
+            sanitizer: &sanitizer,
+            path_pattern: None,
+            various_artists: DEFAULT_VARIOUS_ARTISTS,
+            itunes_db: None,
+        };
+        let walk_options = WalkOptions::default();
+        let verification = verify(library, &walk_options, naming).unwrap();
+        assert_eq!(verification.in_place, 1);
+        assert_eq!(verification.misplaced, vec![Misplaced {
+            path: library.join("Artist/Album/Song B.mp3"),
+            expected: library.join("Artist/Other Album/Song B.mp3"),
+        }]);
+        assert_eq!(verification.orphans, vec![library.join("Artist/Album/cover.jpg")]);
+        assert_eq!(verification.empty_dirs, vec![library.join("Leftover")]);
+        assert_eq!(verification.playlists, vec![library.join("Road Trip.m3u8")]);
+
+        let fix = fix_library(library, &walk_options, &verification).unwrap();
+        assert_eq!(fix.report.counts.placed, 1);
+        assert_eq!(fix.removed_dirs, vec![library.join("Leftover")]);
+        assert!(library.join("Artist/Other Album/Song B.mp3").try_exists().unwrap());
+        assert_eq!(fix.report.playlists.len(), 1);
+        let playlist = std::fs::read_to_string(library.join("Road Trip.m3u8")).unwrap();
+        assert!(playlist.contains("Artist/Album/Song A.mp3"));
+        assert!(playlist.contains("Artist/Other Album/Song B.mp3"));
+        assert!(!playlist.contains("Artist/Album/Song B.mp3"));
+        let verification = verify(library, &walk_options, naming).unwrap();
+        assert_eq!((verification.in_place, verification.misplaced.len()), (2, 0));
+    }
+}
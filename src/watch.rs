@@ -0,0 +1,446 @@
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::MoverError;
+use crate::report::RunReport;
+
+/// How long a file has to go unchanged before it's taken to have finished being written.
+pub const DEFAULT_SETTLE_SECS: u64 = 2;
+
+/// Called with the outcome of organising each batch of files that have settled.
+pub type BatchHandler<'h> = dyn FnMut(Result<RunReport, MoverError>) + 'h;
+
+/// Watches the input directory and organises files as they land in it, along with any that
+/// were already there, until the process is sent SIGINT or SIGTERM. A file is organised once
+/// it's gone `settle` without changing.
+#[cfg(target_os = "linux")]
+pub fn watch(
+    config: &Config,
+    settle: Duration,
+    on_batch: &mut BatchHandler,
+) -> Result<(), MoverError> {
+    linux::install_signal_handlers()
+        .map_err(|e| MoverError::Config(format!("Couldn't handle signals: {}", e)))?;
+    linux::watch_until(config, settle, &linux::STOP, on_batch)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch(
+    _config: &Config,
+    _settle: Duration,
+    _on_batch: &mut BatchHandler,
+) -> Result<(), MoverError> {
+    Err(MoverError::Config(String::from("Watch mode is only supported on Linux")))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsStr};
+    use std::fs::{metadata, read_dir, symlink_metadata};
+    use std::io::{Error, ErrorKind};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    use log::{info, warn};
+
+    use crate::config::Config;
+    use crate::discover::{SymlinkPolicy, is_hidden};
+    use crate::error::MoverError;
+    use crate::run_files;
+
+    use super::BatchHandler;
+
+    pub(super) static STOP: AtomicBool = AtomicBool::new(false);
+
+    /// Longest time to go without checking whether to stop.
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    extern "C" fn request_stop(_signal: libc::c_int) {
+        STOP.store(true, Ordering::Relaxed);
+    }
+
+    pub(super) fn install_signal_handlers() -> std::io::Result<()> {
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+            let res = unsafe {
+                libc::signal(signal, request_stop as *const () as libc::sighandler_t)
+            };
+            if res == libc::SIG_ERR {
+                return Err(Error::last_os_error())
+            }
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct PendingFile {
+        last_change: Instant,
+        size: Option<u64>,
+    }
+
+    /// Files seen changing in the watched directory, waiting for their writes to finish.
+    #[derive(Debug, Default)]
+    pub(super) struct PendingFiles {
+        files: HashMap<PathBuf, PendingFile>,
+    }
+
+    impl PendingFiles {
+        pub(super) fn touch(&mut self, path: PathBuf, now: Instant) {
+            let size = file_size(&path);
+            self.files.insert(path, PendingFile { last_change: now, size });
+        }
+
+        pub(super) fn remove(&mut self, path: &Path) {
+            self.files.remove(path);
+        }
+
+        fn next_deadline(&self, settle: Duration) -> Option<Instant> {
+            self.files.values().map(|pending| pending.last_change + settle).min()
+        }
+
+        /// Takes the files that have gone `settle` without an event, as long as their size
+        /// hasn't changed either, since not every writer closes the file when it's done. Files
+        /// that have gone are dropped.
+        pub(super) fn take_settled(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+            let mut settled = Vec::new();
+            self.files.retain(|path, pending| {
+                if now < pending.last_change + settle {
+                    return true
+                }
+                match file_size(path) {
+                    None => false,
+                    Some(size) if pending.size == Some(size) => {
+                        settled.push(path.clone());
+                        false
+                    },
+                    size => {
+                        *pending = PendingFile { last_change: now, size };
+                        true
+                    },
+                }
+            });
+            settled.sort();
+            settled
+        }
+    }
+
+    fn file_size(path: &Path) -> Option<u64> {
+        metadata(path).ok().filter(|metadata| metadata.is_file()).map(|metadata| metadata.len())
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        FileChanged(PathBuf),
+        FileRemoved(PathBuf),
+        DirAdded { path: PathBuf, depth: usize },
+        /// Events were dropped because too many arrived at once
+        Overflow,
+    }
+
+    struct Inotify {
+        fd: OwnedFd,
+        /// Watched dir and its depth below the input directory, by watch descriptor
+        watches: HashMap<libc::c_int, (PathBuf, usize)>,
+    }
+
+    impl Inotify {
+        fn new() -> std::io::Result<Inotify> {
+            // SAFETY: inotify_init1 takes no pointers, and the descriptor it returns is owned
+            // by nothing else
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(Error::last_os_error())
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Inotify { fd, watches: HashMap::new() })
+        }
+
+        /// Watches `dir`, returning whether it wasn't already being watched, e.g. through a
+        /// symlink.
+        fn add_watch(&mut self, dir: &Path, depth: usize) -> std::io::Result<bool> {
+            let c_path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let mask = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_CREATE
+                | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE | libc::IN_ONLYDIR;
+            // SAFETY: the path is a valid NUL-terminated string for the duration of the call
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), mask) };
+            if wd < 0 {
+                return Err(Error::last_os_error())
+            }
+            Ok(self.watches.insert(wd, (dir.to_path_buf(), depth)).is_none())
+        }
+
+        /// Waits up to `timeout` for events, returning early with none if a signal arrives.
+        fn wait(&mut self, timeout: Duration) -> std::io::Result<Vec<Event>> {
+            let mut poll_fd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            // SAFETY: poll_fd is a single valid pollfd for the duration of the call
+            let res = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+            if res < 0 {
+                let e = Error::last_os_error();
+                return if e.kind() == ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(e) }
+            }
+            let mut events = Vec::new();
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                // SAFETY: buf is valid for writes of its whole length
+                let read = unsafe {
+                    libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len())
+                };
+                if read < 0 {
+                    let e = Error::last_os_error();
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted {
+                        return Ok(events)
+                    }
+                    return Err(e)
+                }
+                self.parse_events(&buf[..read as usize], &mut events);
+            }
+        }
+
+        fn parse_events(&mut self, mut buf: &[u8], events: &mut Vec<Event>) {
+            let header_len = std::mem::size_of::<libc::inotify_event>();
+            while buf.len() >= header_len {
+                // SAFETY: the kernel only writes whole events, and there's a header's worth of
+                // bytes left, which read_unaligned doesn't need aligned
+                let header: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buf.as_ptr().cast())
+                };
+                let name_end = (header_len + header.len as usize).min(buf.len());
+                let name = &buf[header_len..name_end];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                buf = &buf[name_end..];
+                if header.mask & libc::IN_Q_OVERFLOW != 0 {
+                    events.push(Event::Overflow);
+                    continue;
+                }
+                if header.mask & libc::IN_IGNORED != 0 {
+                    self.watches.remove(&header.wd);
+                    continue;
+                }
+                let Some((dir, depth)) = self.watches.get(&header.wd) else {
+                    continue
+                };
+                if name.is_empty() {
+                    continue;
+                }
+                let path = dir.join(OsStr::from_bytes(name));
+                let is_dir = header.mask & libc::IN_ISDIR != 0;
+                let event = if header.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    if is_dir { continue } else { Event::FileRemoved(path) }
+                } else if is_dir {
+                    if header.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) == 0 {
+                        continue;
+                    }
+                    Event::DirAdded { path, depth: depth + 1 }
+                } else {
+                    Event::FileChanged(path)
+                };
+                events.push(event);
+            }
+        }
+    }
+
+    struct Watcher<'c> {
+        config: &'c Config,
+        inotify: Inotify,
+        pending: PendingFiles,
+        /// The output and unsorted directories, which files are placed in rather than taken
+        /// from, as absolute paths
+        excluded_dirs: Vec<PathBuf>,
+    }
+
+    impl Watcher<'_> {
+        fn is_excluded(&self, path: &Path) -> bool {
+            if is_hidden(path) && !self.config.walk_options.include_hidden {
+                return true
+            }
+            let Ok(path) = std::path::absolute(path) else {
+                return true
+            };
+            self.excluded_dirs.iter().any(|dir| path.starts_with(dir))
+        }
+
+        /// Watches `dir` and the directories below it that the walk options allow, noting the
+        /// files already in them as pending.
+        fn add_dir(&mut self, dir: &Path, depth: usize) {
+            match self.inotify.add_watch(dir, depth) {
+                Ok(true) => self.add_entries(dir, depth),
+                Ok(false) => {},
+                Err(e) => warn!("Couldn't watch dir {:?}: {}; moving on", dir, e),
+            }
+        }
+
+        fn add_entries(&mut self, dir: &Path, depth: usize) {
+            let entries = match read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("During reading of dir {:?} encountered error {:?}; moving on", dir, e);
+                    return
+                },
+            };
+            let now = Instant::now();
+            let walk_options = &self.config.walk_options;
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if self.is_excluded(&path) {
+                    continue;
+                }
+                let is_symlink = symlink_metadata(&path)
+                    .is_ok_and(|metadata| metadata.file_type().is_symlink());
+                if is_symlink && walk_options.symlinks == SymlinkPolicy::Skip {
+                    continue;
+                }
+                if !path.is_dir() {
+                    self.pending.touch(path, now);
+                    continue;
+                }
+                let is_too_deep = walk_options.max_depth.is_some_and(|max_depth| depth >= max_depth);
+                if is_too_deep || (is_symlink && walk_options.symlinks == SymlinkPolicy::FilesOnly) {
+                    continue;
+                }
+                self.add_dir(&path, depth + 1);
+            }
+        }
+
+        fn handle(&mut self, event: Event) {
+            match event {
+                Event::FileChanged(path) if !self.is_excluded(&path) => {
+                    self.pending.touch(path, Instant::now());
+                },
+                Event::FileRemoved(path) => self.pending.remove(&path),
+                Event::DirAdded { path, depth } if !self.is_excluded(&path) => {
+                    let max_depth = self.config.walk_options.max_depth;
+                    if max_depth.is_none_or(|max_depth| depth <= max_depth) {
+                        self.add_dir(&path, depth);
+                    }
+                },
+                Event::Overflow => {
+                    warn!("Too many changes to keep up with, searching the input dir again");
+                    let input_path = PathBuf::from(&self.config.input_path);
+                    self.inotify.watches.retain(|_, (_, depth)| *depth == 0);
+                    self.add_entries(&input_path, 0);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    pub(super) fn watch_until(
+        config: &Config,
+        settle: Duration,
+        stop: &AtomicBool,
+        on_batch: &mut BatchHandler,
+    ) -> Result<(), MoverError> {
+        let input_path = PathBuf::from(&config.input_path);
+        let to_error = |source| MoverError::Filesystem { path: input_path.clone(), source };
+        let mut excluded_dirs = vec![std::path::absolute(&config.output_path).map_err(to_error)?];
+        if let Some(unsorted_dir) = &config.unsorted_dir {
+            excluded_dirs.push(std::path::absolute(unsorted_dir).map_err(to_error)?);
+        }
+        let inotify = Inotify::new().map_err(to_error)?;
+        let mut watcher = Watcher { config, inotify, pending: PendingFiles::default(), excluded_dirs };
+        watcher.inotify.add_watch(&input_path, 0).map_err(to_error)?;
+        watcher.add_entries(&input_path, 0);
+        info!("Watching {:?} for song files", input_path);
+        while !stop.load(Ordering::Relaxed) {
+            let timeout = watcher.pending.next_deadline(settle)
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .map_or(POLL_INTERVAL, |until_deadline| until_deadline.min(POLL_INTERVAL));
+            for event in watcher.inotify.wait(timeout).map_err(to_error)? {
+                watcher.handle(event);
+            }
+            let settled = watcher.pending.take_settled(Instant::now(), settle);
+            if !settled.is_empty() {
+                info!("Organising {} files that have finished being written", settled.len());
+                on_batch(run_files(config, settled));
+            }
+        }
+        info!("Stopped watching {:?}", input_path);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::fs::{File, create_dir, write};
+        use std::thread;
+
+        use id3::{Tag, TagLike};
+        use tempfile::tempdir;
+
+        use crate::cli::OrganizeArgs;
+
+        use super::*;
+
+        #[test]
+        fn files_settle_once_unchanged() {
+            let dir = tempdir().unwrap();
+            let path = dir.as_ref().join("A.mp3");
+            write(&path, b"song").unwrap();
+            let settle = Duration::from_secs(2);
+            let start = Instant::now();
+            let mut pending = PendingFiles::default();
+            pending.touch(path.clone(), start);
+            assert_eq!(pending.take_settled(start + Duration::from_secs(1), settle), Vec::<PathBuf>::new());
+            // Still being written to without any events
+            write(&path, b"song data").unwrap();
+            assert_eq!(pending.take_settled(start + settle, settle), Vec::<PathBuf>::new());
+            assert_eq!(pending.take_settled(start + settle * 2, settle), vec![path.clone()]);
+            assert!(pending.files.is_empty());
+        }
+
+        #[test]
+        fn removed_files_dropped() {
+            let dir = tempdir().unwrap();
+            let start = Instant::now();
+            let mut pending = PendingFiles::default();
+            pending.touch(dir.as_ref().join("A.mp3"), start);
+            let settle = Duration::from_secs(2);
+            assert_eq!(pending.take_settled(start + settle, settle), Vec::<PathBuf>::new());
+            assert!(pending.files.is_empty());
+        }
+
+        #[test]
+        fn song_files_organised_as_they_land() {
+            let indir = tempdir().unwrap();
+            let outdir = tempdir().unwrap();
+            let args = OrganizeArgs::new(
+                indir.as_ref().to_str().unwrap().to_string(),
+                outdir.as_ref().to_str().unwrap().to_string(),
+            );
+            let config = Config::for_watch(&args).unwrap();
+            let stop = AtomicBool::new(false);
+            let settle = Duration::from_millis(100);
+            let song_path = outdir.as_ref().join("Artist").join("Album").join("Song.mp3");
+            let mut placed = 0;
+            thread::scope(|scope| {
+                let watcher = scope.spawn(|| {
+                    watch_until(&config, settle, &stop, &mut |res| {
+                        placed += res.unwrap().counts.placed;
+                    })
+                });
+                thread::sleep(Duration::from_millis(200));
+                let subdir = indir.as_ref().join("F00");
+                create_dir(&subdir).unwrap();
+                let mut tag = Tag::new();
+                tag.set_title("Song");
+                tag.set_artist("Artist");
+                tag.set_album("Album");
+                File::create(subdir.join("ABCD.mp3")).unwrap();
+                tag.write_to_path(subdir.join("ABCD.mp3"), id3::Version::Id3v24).unwrap();
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while !song_path.try_exists().unwrap() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                stop.store(true, Ordering::Relaxed);
+                watcher.join().unwrap().unwrap();
+            });
+            assert!(song_path.try_exists().unwrap());
+            assert_eq!(placed, 1);
+        }
+    }
+}
@@ -1,10 +1,20 @@
 mod helpers{
     use std::path::{Path, PathBuf};
-    use std::fs::{File, create_dir};
+    use std::fs::{File, create_dir, write};
     use std::iter::zip;
 
+    use audiotags::{AudioTagEdit, AudioTagWrite, FlacTag};
     use id3::{Tag, TagLike};
 
+    use mp3_mover::cli::OrganizeArgs;
+
+    pub fn organize_args(input_dir: &Path, output_dir: &Path) -> OrganizeArgs {
+        OrganizeArgs::new(
+            input_dir.to_str().unwrap().to_string(),
+            output_dir.to_str().unwrap().to_string(),
+        )
+    }
+
     pub fn create_dir_with_song_files(
         dir_name: &str,
         parent_dir: &Path,
@@ -31,17 +41,44 @@ mod helpers{
         }
         Ok(dir_path)
     }
+
+    pub fn create_flac_song_file(
+        path: &Path,
+        title: &str,
+        artist: &str,
+        album: &str,
+    ) -> std::io::Result<()> {
+        // Smallest valid FLAC file: the marker followed by an empty STREAMINFO block flagged as
+        // the last metadata block
+        let mut contents = b"fLaC".to_vec();
+        contents.extend_from_slice(&[0x80, 0x00, 0x00, 0x22]);
+        contents.extend_from_slice(&[0; 34]);
+        write(path, contents)?;
+        let mut tag = FlacTag::read_from_path(path).unwrap();
+        tag.set_title(title);
+        tag.set_artist(artist);
+        tag.set_album_title(album);
+        tag.write_to_path(path.to_str().unwrap()).unwrap();
+        Ok(())
+    }
 }
 
 mod tests{
     use std::path::PathBuf;
     use std::iter::zip;
-    use std::fs::read_dir;
+    use std::fs::{File, copy, create_dir_all, read_dir, read_to_string, write};
+    use id3::{Frame, Tag, TagLike};
     use tempfile::tempdir;
 
-    use crate::helpers::create_dir_with_song_files;
+    use crate::helpers::{create_dir_with_song_files, create_flac_song_file, organize_args};
 
-    use mp3_mover::{run, config::Config};
+    use mp3_mover::{execute_plan, run, cli::OrganizeArgs, config::Config};
+    use mp3_mover::journal::{Journal, undo};
+    use mp3_mover::pipeline::{Naming, Planner, discover, read_song_files};
+    use mp3_mover::operation::Operation;
+    use mp3_mover::dedup::DuplicatePolicy;
+    use mp3_mover::playlist::PlaylistPaths;
+    use mp3_mover::report::OutcomeKind;
 
     #[test]
     fn find_song_files_in_two_dirs() {
@@ -56,8 +93,8 @@ mod tests{
         let dir_one = "F00";
         let dir_one_song_files = ["A.mp3", "B.mp3", "C.mp3"];
         let dir_one_song_titles = ["Song1", "Song2", "Song3"];
-        let dir_one_non_song_files = ["D.mp4", "E.jpg"];
-        let dir_one_path = create_dir_with_song_files(
+        let dir_one_non_song_files = ["D.txt", "E.jpg"];
+        create_dir_with_song_files(
             dir_one,
             indir.as_ref(),
             &dir_one_song_files,
@@ -71,8 +108,8 @@ mod tests{
         let dir_two = "F11";
         let dir_two_song_files = ["F.mp3", "G.mp3", "H.mp3"];
         let dir_two_song_titles = ["Song4", "Song5", "Song6"];
-        let dir_two_non_song_files = ["I.mp4", "J.jpg"];
-        let dir_two_path = create_dir_with_song_files(
+        let dir_two_non_song_files = ["I.txt", "J.jpg"];
+        create_dir_with_song_files(
             dir_two,
             indir.as_ref(),
             &dir_two_song_files,
@@ -86,24 +123,20 @@ mod tests{
         let outdir = tempdir().unwrap();
 
         // Create config instance
-        let args = [
-            "/path/to/program".to_string(),
-            indir.as_ref().to_str().unwrap().to_string(),
-            outdir.as_ref().to_str().unwrap().to_string(),
-        ];
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
         let config = Config::new(&args).unwrap();
 
         // Run function to search through all subdirs in input dir and rename+move song files into
         // the output dir
-        let res = run(config);
+        run(config).unwrap();
 
         // Check output dir has expected subdirs
         let read_iter = read_dir(outdir.path()).unwrap();
         let pathbuf_iter = read_iter.map(|entry| entry.unwrap().path());
         let subdirs: Vec<PathBuf> = pathbuf_iter.collect();
         for artist in artists {
-            assert_eq!(
-                subdirs.contains(&outdir.path().join(artist)), true
+            assert!(
+                subdirs.contains(&outdir.path().join(artist))
             );
         }
 
@@ -111,7 +144,7 @@ mod tests{
         let artist_album_iter = zip(artists, albums);
         for (artist, album) in artist_album_iter {
             let album_path = outdir.as_ref().to_path_buf().join(artist).join(album);
-            assert_eq!(album_path.try_exists().unwrap(), true);
+            assert!(album_path.try_exists().unwrap());
         }
 
         // Check all album subdirs contain expected renamed song files
@@ -124,7 +157,7 @@ mod tests{
                 .join(artist)
                 .join(album)
                 .join(song);
-            assert_eq!(song_path.try_exists().unwrap(), true);
+            assert!(song_path.try_exists().unwrap());
         }
         let dir_two_expected_song_files = dir_two_song_titles.map(
             |s| format!("{}.mp3", s)
@@ -135,7 +168,570 @@ mod tests{
                 .join(artist)
                 .join(album)
                 .join(song);
-            assert_eq!(song_path.try_exists().unwrap(), true);
+            assert!(song_path.try_exists().unwrap());
+        }
+    }
+
+    #[test]
+    fn find_song_files_at_root_and_nested_dirs() {
+        let indir = tempdir().unwrap();
+        let artists = ["Artist1"];
+        let albums = ["Album1"];
+
+        // Song file sitting directly in the input dir
+        let mut tag = Tag::new();
+        tag.set_title("Song1");
+        tag.set_artist(artists[0]);
+        tag.set_album(albums[0]);
+        File::create(indir.as_ref().join("A.mp3")).unwrap();
+        tag.write_to_path(indir.as_ref().join("A.mp3"), id3::Version::Id3v24).unwrap();
+
+        // Song file nested a few levels deep
+        let nested_parent = indir.as_ref().join("iPod_Control").join("Music");
+        create_dir_all(nested_parent.clone()).unwrap();
+        create_dir_with_song_files(
+            "F00",
+            &nested_parent,
+            &["B.mp3"],
+            &["Song2"],
+            &[],
+            &artists,
+            &albums,
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
+        let config = Config::new(&args).unwrap();
+        run(config).unwrap();
+
+        let album_path = outdir.as_ref().join(artists[0]).join(albums[0]);
+        assert!(album_path.join("Song1.mp3").try_exists().unwrap());
+        assert!(album_path.join("Song2.mp3").try_exists().unwrap());
+    }
+
+    #[test]
+    fn flac_song_file_keeps_extension() {
+        let indir = tempdir().unwrap();
+        let subdir = indir.as_ref().join("F00");
+        create_dir_all(subdir.clone()).unwrap();
+        create_flac_song_file(&subdir.join("A.flac"), "Song1", "Artist1", "Album1").unwrap();
+
+        let outdir = tempdir().unwrap();
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
+        let config = Config::new(&args).unwrap();
+        run(config).unwrap();
+
+        let song_path = outdir.as_ref().join("Artist1").join("Album1").join("Song1.flac");
+        assert!(song_path.try_exists().unwrap());
+    }
+
+    #[test]
+    fn songs_with_same_title_both_kept() {
+        let indir = tempdir().unwrap();
+        create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3"],
+            &["Intro", "Intro"],
+            &[],
+            &["Artist1", "Artist1"],
+            &["Album1", "Album1"],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
+        let config = Config::new(&args).unwrap();
+        let plan = run(config).unwrap().plan;
+
+        let album_path = outdir.as_ref().join("Artist1").join("Album1");
+        assert!(album_path.join("Intro.mp3").try_exists().unwrap());
+        assert!(album_path.join("Intro (2).mp3").try_exists().unwrap());
+        assert_eq!(plan.collisions.len(), 1);
+    }
+
+    #[test]
+    fn copy_mode_leaves_input_untouched() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3"],
+            &["Song1"],
+            &[],
+            &["Artist1"],
+            &["Album1"],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let args = OrganizeArgs {
+            mode: Operation::Copy,
+            ..organize_args(indir.as_ref(), outdir.as_ref())
+        };
+        let config = Config::new(&args).unwrap();
+        run(config).unwrap();
+
+        let song_path = outdir.as_ref().join("Artist1").join("Album1").join("Song1.mp3");
+        assert!(song_path.try_exists().unwrap());
+        assert!(dir_path.join("A.mp3").try_exists().unwrap());
+    }
+
+    #[test]
+    fn undo_restores_input_dir() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3"],
+            &["Song1", "Song2"],
+            &[],
+            &["Artist1", "Artist2"],
+            &["Album1", "Album2"],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
+        let config = Config::new(&args).unwrap();
+        let journal_path = run(config).unwrap().journal_path.unwrap();
+        assert!(!dir_path.join("A.mp3").try_exists().unwrap());
+
+        let report = undo(&journal_path).unwrap();
+        assert!(report.failures.is_empty());
+        assert!(dir_path.join("A.mp3").try_exists().unwrap());
+        assert!(dir_path.join("B.mp3").try_exists().unwrap());
+        // Only the journal itself is left in the output dir
+        let outdir_entries: Vec<PathBuf> = read_dir(outdir.as_ref()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(outdir_entries, vec![journal_path]);
+    }
+
+    #[test]
+    fn incomplete_songs_moved_to_unsorted_dir() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3", "C.mp3"],
+            &["Song1", "Song2", "Song3"],
+            &["D.mp3", "cover.jpg"],
+            &["Artist1", "", "Artist3"],
+            &["Album1", "Album2", ""],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let unsorted_dir = outdir.as_ref().join("unsorted");
+        let args = OrganizeArgs {
+            unsorted_dir: Some(unsorted_dir.to_str().unwrap().to_string()),
+            ..organize_args(indir.as_ref(), outdir.as_ref())
+        };
+        let config = Config::new(&args).unwrap();
+        let report = run(config).unwrap();
+
+        let song_path = outdir.as_ref().join("Artist1").join("Album1").join("Song1.mp3");
+        assert!(song_path.try_exists().unwrap());
+        let unsorted_paths = [
+            unsorted_dir.join("missing-artist").join("F00").join("B.mp3"),
+            unsorted_dir.join("missing-album").join("F00").join("C.mp3"),
+            unsorted_dir.join("unreadable-tag").join("F00").join("D.mp3"),
+        ];
+        for path in unsorted_paths.iter() {
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
         }
+        assert!(dir_path.join("cover.jpg").try_exists().unwrap());
+        assert_eq!(report.counts.placed, 1);
+        assert_eq!(report.counts.missing_field, 2);
+        assert_eq!(report.counts.unreadable_tag, 1);
+    }
+
+    #[test]
+    fn missing_tag_fields_inferred_from_path() {
+        let indir = tempdir().unwrap();
+        create_dir_with_song_files(
+            "Artist1 - Album1",
+            indir.as_ref(),
+            &["01 - A.mp3"],
+            &["Song1"],
+            &[],
+            &[""],
+            &[""],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.layout.path_pattern = Some(String::from("{artist} - {album}/{track} - {title}"));
+        let config = Config::new(&args).unwrap();
+        let report = run(config).unwrap();
+
+        let song_path = outdir.as_ref().join("Artist1").join("Album1").join("01 Song1.mp3");
+        assert!(song_path.try_exists().unwrap());
+        assert_eq!(report.counts.inferred_from_path, 1);
+        assert_eq!(report.files[0].inferred, vec!["artist", "album", "track"]);
+    }
+
+    #[test]
+    fn compilation_album_kept_together() {
+        let indir = tempdir().unwrap();
+        let compilation_dir = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3", "C.mp3"],
+            &["Song1", "Song2", "Song3"],
+            &[],
+            &["Artist1", "Artist2", "Artist3"],
+            &["Hits", "Hits", "Hits"],
+        ).unwrap();
+        // Only one of the compilation's tracks is flagged, as can happen after retagging
+        let mut tag = Tag::read_from_path(compilation_dir.join("A.mp3")).unwrap();
+        tag.add_frame(Frame::text("TCMP", "1"));
+        tag.write_to_path(compilation_dir.join("A.mp3"), id3::Version::Id3v24).unwrap();
+        create_dir_with_song_files(
+            "F11",
+            indir.as_ref(),
+            &["D.mp3", "E.mp3"],
+            &["Song4", "Song5"],
+            &[],
+            &["Artist4", "Artist4 feat. Artist5"],
+            &["Solo", "Solo"],
+        ).unwrap();
+
+        let outdir = tempdir().unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.naming.various_artists = String::from("Compilations");
+        let config = Config::new(&args).unwrap();
+        let report = run(config).unwrap();
+
+        let song_paths = [
+            outdir.as_ref().join("Compilations").join("Hits").join("Song1.mp3"),
+            outdir.as_ref().join("Compilations").join("Hits").join("Song2.mp3"),
+            outdir.as_ref().join("Compilations").join("Hits").join("Song3.mp3"),
+            outdir.as_ref().join("Artist4").join("Solo").join("Song4.mp3"),
+            outdir.as_ref().join("Artist4").join("Solo").join("Song5.mp3"),
+        ];
+        for path in song_paths.iter() {
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+        assert_eq!(report.counts.placed, 5);
+    }
+
+    #[test]
+    fn track_and_disc_numbers_in_file_names() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3"],
+            &["Song1"],
+            &[],
+            &["Artist1"],
+            &["Album1"],
+        ).unwrap();
+        let mut tag = Tag::read_from_path(dir_path.join("A.mp3")).unwrap();
+        tag.set_text("TRCK", "5/10");
+        tag.set_text("TPOS", "1/1");
+        tag.write_to_path(dir_path.join("A.mp3"), id3::Version::Id3v24).unwrap();
+        let flac_path = dir_path.join("B.flac");
+        create_flac_song_file(&flac_path, "Song2", "Artist2", "Album2").unwrap();
+        let mut flac_tag = metaflac::Tag::read_from_path(&flac_path).unwrap();
+        flac_tag.set_vorbis("TRACKNUMBER", vec!["3/12"]);
+        flac_tag.set_vorbis("DISCNUMBER", vec!["2"]);
+        flac_tag.set_vorbis("DISCTOTAL", vec!["2"]);
+        flac_tag.save().unwrap();
+
+        let outdir = tempdir().unwrap();
+        let config = Config::new(&organize_args(indir.as_ref(), outdir.as_ref())).unwrap();
+        run(config).unwrap();
+
+        let song_paths = [
+            outdir.as_ref().join("Artist1").join("Album1").join("05 Song1.mp3"),
+            outdir.as_ref().join("Artist2").join("Album2").join("Disc 2").join("03 Song2.flac"),
+        ];
+        for path in song_paths.iter() {
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+    }
+
+    #[test]
+    fn both_discs_of_set_without_total_get_disc_dirs() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3"],
+            &["Song1", "Song2"],
+            &[],
+            &["Artist1", "Artist1"],
+            &["Album1", "Album1"],
+        ).unwrap();
+        for (song, disc) in [("A.mp3", "1"), ("B.mp3", "2")] {
+            let mut tag = Tag::read_from_path(dir_path.join(song)).unwrap();
+            tag.set_text("TPOS", disc);
+            tag.write_to_path(dir_path.join(song), id3::Version::Id3v24).unwrap();
+        }
+
+        let outdir = tempdir().unwrap();
+        let config = Config::new(&organize_args(indir.as_ref(), outdir.as_ref())).unwrap();
+        run(config).unwrap();
+
+        let album_dir = outdir.as_ref().join("Artist1").join("Album1");
+        let song_paths = [
+            album_dir.join("Disc 1").join("Song1.mp3"),
+            album_dir.join("Disc 2").join("Song2.mp3"),
+        ];
+        for path in song_paths.iter() {
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+    }
+
+    #[test]
+    fn discs_spread_over_fxx_dirs_get_disc_dirs() {
+        let indir = tempdir().unwrap();
+        for (dir, song, title, disc) in [("F00", "A.mp3", "Song1", "1"), ("F01", "B.mp3", "Song2", "2")] {
+            let dir_path = create_dir_with_song_files(
+                dir,
+                indir.as_ref(),
+                &[song],
+                &[title],
+                &[],
+                &["Artist1"],
+                &["Album1"],
+            ).unwrap();
+            let mut tag = Tag::read_from_path(dir_path.join(song)).unwrap();
+            tag.set_text("TPOS", disc);
+            tag.write_to_path(dir_path.join(song), id3::Version::Id3v24).unwrap();
+        }
+
+        let outdir = tempdir().unwrap();
+        let config = Config::new(&organize_args(indir.as_ref(), outdir.as_ref())).unwrap();
+        run(config).unwrap();
+
+        let album_dir = outdir.as_ref().join("Artist1").join("Album1");
+        let song_paths = [
+            album_dir.join("Disc 1").join("Song1.mp3"),
+            album_dir.join("Disc 2").join("Song2.mp3"),
+        ];
+        for path in song_paths.iter() {
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+    }
+
+    #[test]
+    fn compilation_spread_over_fxx_dirs_kept_together() {
+        let indir = tempdir().unwrap();
+        let songs = [("F00", "Song1", "Artist1"), ("F01", "Song2", "Artist2"), ("F02", "Song3", "Artist3")];
+        for (dir, title, artist) in songs {
+            create_dir_with_song_files(dir, indir.as_ref(), &["A.mp3"], &[title], &[], &[artist], &["Hits"])
+                .unwrap();
+        }
+
+        let outdir = tempdir().unwrap();
+        let config = Config::new(&organize_args(indir.as_ref(), outdir.as_ref())).unwrap();
+        run(config).unwrap();
+
+        let album_dir = outdir.as_ref().join("Various Artists").join("Hits");
+        for title in ["Song1", "Song2", "Song3"] {
+            let path = album_dir.join(format!("{}.mp3", title));
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+    }
+
+    #[test]
+    fn stages_run_separately_with_own_metadata() {
+        let indir = tempdir().unwrap();
+        let dir_path = create_dir_with_song_files(
+            "F00",
+            indir.as_ref(),
+            &["A.mp3", "B.mp3"],
+            &["Song1", "Song2"],
+            &["notes.txt"],
+            &["Artist1", "Artist1"],
+            &["", ""],
+        ).unwrap();
+        let outdir = tempdir().unwrap();
+        let config = Config::new(&organize_args(indir.as_ref(), outdir.as_ref())).unwrap();
+
+        let file_paths = discover(indir.as_ref(), &config.walk_options).unwrap();
+        assert_eq!(file_paths.len(), 3);
+        let naming = Naming::from_config(&config);
+        let mut planner = Planner::new(&config);
+        // Fill in the album the tags lack from somewhere else
+        let songs = read_song_files(file_paths, config.walk_options.jobs).into_iter()
+            .filter_map(|song_file| {
+                let mut metadata = song_file.metadata.ok()?;
+                metadata.album = Some(String::from("Album1"));
+                Some((song_file.path, metadata))
+            })
+            .collect();
+        for named_song in naming.name_songs(songs) {
+            planner.add(named_song);
+        }
+        let plan = planner.finish().unwrap();
+        assert_eq!(plan.moves.len(), 2);
+        let mut journal = Journal::create(outdir.as_ref()).unwrap();
+        let errors = execute_plan(&plan, &mut journal).unwrap();
+        assert!(errors.is_empty());
+
+        let album_dir = outdir.as_ref().join("Artist1").join("Album1");
+        assert!(album_dir.join("Song1.mp3").try_exists().unwrap());
+        assert!(album_dir.join("Song2.mp3").try_exists().unwrap());
+        assert!(dir_path.join("notes.txt").try_exists().unwrap());
+    }
+
+    #[test]
+    fn retagged_copies_moved_to_duplicates_dir() {
+        let indir = tempdir().unwrap();
+        for (dir, title) in [("F00", "Song"), ("F01", "Song (Remastered)"), ("F02", "Other Song")] {
+            create_dir_all(indir.as_ref().join(dir)).unwrap();
+            let path = indir.as_ref().join(dir).join("ABCD.mp3");
+            let audio = if dir == "F02" { [2; 1000] } else { [1; 1000] };
+            write(&path, audio).unwrap();
+            let mut tag = Tag::new();
+            tag.set_artist("Artist1");
+            tag.set_album("Album1");
+            tag.set_title(title);
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+        }
+        let outdir = tempdir().unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.dedup = Some(DuplicatePolicy::MoveExtras);
+        let report = run(Config::new(&args).unwrap()).unwrap();
+
+        assert_eq!(report.counts.placed, 2);
+        assert_eq!(report.counts.duplicate, 1);
+        let duplicate = report.files.iter()
+            .find(|file| file.kind == OutcomeKind::Duplicate)
+            .unwrap();
+        assert_eq!(duplicate.path, indir.as_ref().join("F01").join("ABCD.mp3"));
+        let album_dir = outdir.as_ref().join("Artist1").join("Album1");
+        assert!(album_dir.join("Song.mp3").try_exists().unwrap());
+        assert!(album_dir.join("Other Song.mp3").try_exists().unwrap());
+        assert!(!album_dir.join("Song (Remastered).mp3").try_exists().unwrap());
+        let moved_aside = outdir.as_ref().join("duplicates").join("F01").join("ABCD.mp3");
+        assert!(moved_aside.try_exists().unwrap());
+    }
+
+    #[test]
+    fn untagged_ipod_files_named_from_itunes_db() {
+        let indir = tempdir().unwrap();
+        let ipod_dir = indir.as_ref().join("iPod_Control");
+        create_dir_all(ipod_dir.join("iTunes")).unwrap();
+        create_dir_all(ipod_dir.join("Music").join("F00")).unwrap();
+        create_dir_all(ipod_dir.join("Music").join("F11")).unwrap();
+        copy("tests/fixtures/iTunesDB", ipod_dir.join("iTunes").join("iTunesDB")).unwrap();
+        // No tags at all, so everything comes from the iTunesDB
+        write(ipod_dir.join("Music").join("F00").join("ABCD.mp3"), [0; 100]).unwrap();
+        write(ipod_dir.join("Music").join("F11").join("EFGH.m4a"), [0; 100]).unwrap();
+        // The tag's own title is kept
+        let mut tag = Tag::new();
+        tag.set_title("Cafe del Mar (Three 'N One Remix)");
+        let tagged_path = ipod_dir.join("Music").join("F11").join("IJKL.mp3");
+        File::create(&tagged_path).unwrap();
+        tag.write_to_path(&tagged_path, id3::Version::Id3v24).unwrap();
+        let outdir = tempdir().unwrap();
+        let args = organize_args(indir.as_ref(), outdir.as_ref());
+        let report = run(Config::new(&args).unwrap()).unwrap();
+
+        assert_eq!(report.counts.placed, 3);
+        let expected_paths = [
+            PathBuf::from("The Beatles/Abbey Road/07 Here Comes the Sun.mp3"),
+            PathBuf::from("Miles Davis/Kind of Blue/03 Blue in Green.m4a"),
+            PathBuf::from("Various Artists/Café del Mar Vol. 1/Cafe del Mar (Three 'N One Remix).mp3"),
+        ];
+        for path in expected_paths {
+            let path = outdir.as_ref().join(path);
+            assert!(path.try_exists().unwrap(), "Expected {:?} to exist", path);
+        }
+    }
+
+    #[test]
+    fn ipod_playlists_exported_as_m3u8() {
+        let indir = tempdir().unwrap();
+        let ipod_dir = indir.as_ref().join("iPod_Control");
+        create_dir_all(ipod_dir.join("iTunes")).unwrap();
+        create_dir_all(ipod_dir.join("Music").join("F00")).unwrap();
+        create_dir_all(ipod_dir.join("Music").join("F11")).unwrap();
+        copy("tests/fixtures/iTunesDB", ipod_dir.join("iTunes").join("iTunesDB")).unwrap();
+        write(ipod_dir.join("Music").join("F00").join("ABCD.mp3"), [0; 100]).unwrap();
+        write(ipod_dir.join("Music").join("F11").join("IJKL.mp3"), [0; 100]).unwrap();
+        let outdir = tempdir().unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.playlists = Some(PlaylistPaths::Relative);
+        let report = run(Config::new(&args).unwrap()).unwrap();
+
+        // The master playlist of every track isn't exported
+        assert_eq!(report.playlists.len(), 1);
+        let playlist_path = outdir.as_ref().join("Road Trip.m3u8");
+        assert_eq!(report.playlists[0].path, playlist_path);
+        assert_eq!(report.playlists[0].entries, 2);
+        let expected_playlist = "#EXTM3U\n\
+            #EXTINF:450,Energy 52 - Café del Mar\n\
+            Various Artists/Café del Mar Vol. 1/Café del Mar.mp3\n\
+            #EXTINF:185,The Beatles - Here Comes the Sun\n\
+            The Beatles/Abbey Road/07 Here Comes the Sun.mp3\n";
+        assert_eq!(read_to_string(&playlist_path).unwrap(), expected_playlist);
+
+        undo(report.journal_path.as_ref().unwrap()).unwrap();
+        assert!(!playlist_path.try_exists().unwrap());
+    }
+
+    #[test]
+    fn existing_playlist_renamed_around() {
+        let indir = tempdir().unwrap();
+        let ipod_dir = indir.as_ref().join("iPod_Control");
+        create_dir_all(ipod_dir.join("iTunes")).unwrap();
+        create_dir_all(ipod_dir.join("Music").join("F00")).unwrap();
+        copy("tests/fixtures/iTunesDB", ipod_dir.join("iTunes").join("iTunesDB")).unwrap();
+        write(ipod_dir.join("Music").join("F00").join("ABCD.mp3"), [0; 100]).unwrap();
+        let outdir = tempdir().unwrap();
+        // Exported by an earlier run from another iPod
+        let earlier_playlist = outdir.as_ref().join("Road Trip.m3u8");
+        write(&earlier_playlist, "#EXTM3U\nElsewhere.mp3\n").unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.playlists = Some(PlaylistPaths::Relative);
+        let report = run(Config::new(&args).unwrap()).unwrap();
+
+        let playlist_path = outdir.as_ref().join("Road Trip (2).m3u8");
+        assert_eq!(report.playlists[0].path, playlist_path);
+        assert!(read_to_string(&playlist_path).unwrap().contains("Here Comes the Sun"));
+        assert_eq!(read_to_string(&earlier_playlist).unwrap(), "#EXTM3U\nElsewhere.mp3\n");
+
+        undo(report.journal_path.as_ref().unwrap()).unwrap();
+        assert!(!playlist_path.try_exists().unwrap());
+        assert!(earlier_playlist.try_exists().unwrap());
+    }
+
+    #[test]
+    fn input_playlists_rewritten_to_follow_moved_files() {
+        let indir = tempdir().unwrap();
+        create_dir_with_song_files(
+            "F01",
+            indir.as_ref(),
+            &["ABCD.mp3", "EFGH.mp3"],
+            &["Song A", "Song B"],
+            &[],
+            &["Artist", "Artist"],
+            &["Album", "Album"],
+        ).unwrap();
+        let m3u = "#EXTM3U\n#EXTINF:200,Artist - Song B\nF01/EFGH.mp3\nF01/ABCD.mp3\nF02/Missing.mp3\n";
+        write(indir.as_ref().join("mix.m3u"), m3u).unwrap();
+        write(indir.as_ref().join("mix.pls"), "[playlist]\nFile1=F01\\ABCD.mp3\nNumberOfEntries=1\n").unwrap();
+        let outdir = tempdir().unwrap();
+        let mut args = organize_args(indir.as_ref(), outdir.as_ref());
+        args.playlists = Some(PlaylistPaths::Relative);
+        let report = run(Config::new(&args).unwrap()).unwrap();
+
+        // Playlists aren't reported as song files with an unsupported extension
+        assert_eq!(report.counts.unsupported_extension, 0);
+        assert_eq!(report.playlists.len(), 2);
+        let missing = indir.as_ref().join("F02").join("Missing.mp3");
+        assert_eq!(report.playlists[0].unresolved, vec![missing.display().to_string()]);
+        let expected_m3u = "#EXTM3U\n\
+            #EXTINF:200,Artist - Song B\n\
+            Artist/Album/Song B.mp3\n\
+            Artist/Album/Song A.mp3\n";
+        assert_eq!(read_to_string(outdir.as_ref().join("mix.m3u8")).unwrap(), expected_m3u);
+        let pls = read_to_string(outdir.as_ref().join("mix.pls")).unwrap();
+        assert!(pls.contains("File1=Artist/Album/Song A.mp3\n"));
+        // The originals are left alone
+        assert_eq!(read_to_string(indir.as_ref().join("mix.m3u")).unwrap(), m3u);
     }
 }